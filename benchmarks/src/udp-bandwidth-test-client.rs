@@ -7,9 +7,9 @@ use common::messages::Message;
 async fn main() {
     let mut peer_packet_counts = HashMap::new();
     let mut interval = tokio::time::interval(Duration::from_secs(1));
-    let mut last_heard_from: HashMap<String, Instant> = HashMap::new();
+    let mut last_heard_from: HashMap<common::Peer, Instant> = HashMap::new();
 
-    let mut listener = common::networking::make_listener(vec![SocketAddr::from(([0, 0, 0, 0], 1337))], "...");
+    let (mut listener, _) = common::networking::make_listener(vec![SocketAddr::from(([0, 0, 0, 0], 1337))], &common::Identity::new("...".to_string()), &Default::default());
     loop {
         tokio::select!{
             _ = interval.tick() => {
@@ -28,7 +28,7 @@ async fn main() {
                 println!("");
             }
             Some((_src, name, message)) = listener.recv() => {
-                if let Message::Ping{ nonce, recvs } = message {
+                if let Message::Ping{ nonce, recvs, .. } = message {
                     if nonce == recvs { // This should never happen naturally: these packets are only sent by the bandwidth test server
                         last_heard_from.insert(name.clone(), tokio::time::Instant::now());
                         if peer_packet_counts.contains_key(&name) {
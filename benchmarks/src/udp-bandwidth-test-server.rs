@@ -9,6 +9,7 @@ async fn main() {
     let rate = rate.parse::<u64>().expect("First argument must be target number of packets per second");
     let name = common::make_name();
     println!("Starting server with name {}", name);
+    let identity = common::Identity::new(name);
 
     let mut last_accounting_period = Instant::now();
     let mut packets_this_period = 0;
@@ -17,6 +18,7 @@ async fn main() {
 
     let mut total_sent_packets = 0;
     let addrs = &[SocketAddr::from(([127,255,255,255], 1337))];
+    let mut packet = Vec::new();
     loop {
         let now = Instant::now();
         if now - last_accounting_period > Duration::from_secs(1) {
@@ -26,8 +28,8 @@ async fn main() {
             println!("Packets per second: {}", packets_per_second);
         }
         total_sent_packets += 1;
-        let message = common::messages::Message::Ping{ nonce: total_sent_packets, recvs: total_sent_packets };
-        common::networking::broadcast_message(&socket, addrs, &name, &message).await.unwrap();
+        let message = common::messages::Message::Ping{ nonce: total_sent_packets, recvs: total_sent_packets, lost: None };
+        common::networking::broadcast_message(&socket, addrs, &identity, common::magic::VERSION, &message, &mut packet).await.unwrap();
 
         packets_this_period += 1;
         if packets_this_period > rate {
@@ -1,4 +1,5 @@
 use clap::Parser;
+use common::checksum::ChecksumAlgorithm;
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
@@ -6,10 +7,50 @@ pub(crate) struct Args {
     #[clap(short, long, default_value_t = 1337)]
     pub port: u16,
 
-    /// IP addresses to bind to
-    #[clap(short, long, default_value = "0.0.0.0")]
+    /// IP address to bind to.
+    /// The default, `::`, receives both IPv4 and IPv6.
+    #[clap(short, long, default_value = "::")]
     pub ip: String,
 
+    /// Network interfaces to listen on, comma-separated, like `eth1`.
+    /// If unset, listen on all of them through one socket.
+    #[clap(long, value_delimiter = ',')]
+    pub iface: Vec<String>,
+
+    /// Listen on every network interface except the loopback, with a socket for each.
+    /// Unlike the default, this joins `--multicast-group` on every interface.
+    #[clap(long)]
+    pub all_interfaces: bool,
+
+    /// Multicast group to join, to receive from a server that sends to it.
+    /// An IPv6 group can name the interface to join on, like `ff12::1337%eth0`.
+    #[clap(long)]
+    pub multicast_group: Option<String>,
+
+    /// With `--multicast-group`, only receive the group's packets from this server,
+    /// with source-specific multicast.
+    #[clap(long, requires = "multicast_group")]
+    pub multicast_source: Option<String>,
+
+    /// Size of the kernel's receive buffer for our sockets, in bytes.
+    /// Raise it if packets are dropped when they arrive in bursts.
+    /// The kernel caps it at net.core.rmem_max.
+    #[clap(long)]
+    pub recv_buffer: Option<usize>,
+
+    /// Size of the kernel's send buffer for our sockets, in bytes.
+    /// The kernel caps it at net.core.wmem_max.
+    #[clap(long)]
+    pub send_buffer: Option<usize>,
+
+    /// DSCP class to mark our packets with, from 0 to 63, for example 8 (CS1) for low priority.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..64))]
+    pub dscp: Option<u8>,
+
+    /// TTL (IPv4) or hop limit (IPv6) of the unicast packets we send.
+    #[clap(long)]
+    pub ttl: Option<u32>,
+
     /// Name of this client. Will show up on server. If unset, generated randomly.
     #[clap(short, long)]
     pub name: Option<String>,
@@ -23,4 +64,50 @@ pub(crate) struct Args {
     /// If set to 0, will not request any chunks, and will only rely on broadcasted chunks.
     #[clap(short, long, default_value_t = 10000000)]
     pub request_interval_us: u64,
+
+    /// Download a file at most this many times if it doesn't match the hash that the server sent for it.
+    /// Files that still don't match are reported, and the client exits with an error.
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_attempts: u32,
+
+    /// Directory to save the progress of downloads in, so that a stopped client continues where it stopped.
+    /// The progress of a file is only used again if the server still has the same version of it.
+    #[clap(long, default_value = ".udp-sender-state")]
+    pub state_dir: String,
+
+    /// Don't save the progress of downloads, and download every file from the start.
+    #[clap(long)]
+    pub no_resume: bool,
+
+    /// Download the whole of every file that changed, instead of taking the chunks that its old copy has from it.
+    #[clap(long)]
+    pub no_delta: bool,
+
+    /// Hashlist of the files that are already here, made by `hasher hash`.
+    /// Files whose size matches their entry in it are trusted to have its hash, instead of being hashed again.
+    #[clap(long)]
+    pub local_hashlist: Option<String>,
+
+    /// Pre-shared passphrase to authenticate packets with.
+    /// If set, packets that are not authenticated with the same key are dropped,
+    /// so every server and client on the network must use the same key.
+    #[clap(long, conflicts_with = "psk_file")]
+    pub psk: Option<String>,
+
+    /// File containing the pre-shared key to authenticate packets with.
+    /// This works like `--psk`, but keeps the key out of the process list.
+    #[clap(long)]
+    pub psk_file: Option<String>,
+
+    /// Encrypt packets with the pre-shared key, so that file names and contents can't be read without it.
+    /// Packets from peers are accepted whether they are encrypted or only authenticated.
+    #[clap(long)]
+    pub encrypt: bool,
+
+    /// Checksum algorithm for the packets we send: sha256, crc32c or xxh3.
+    /// Packets from peers are accepted with any of these.
+    /// Defaults to sha256 for peers older than protocol version 4, which is all that the oldest ones understand,
+    /// and to crc32c for the others.
+    #[clap(long)]
+    pub checksum: Option<ChecksumAlgorithm>,
 }
@@ -19,12 +19,19 @@ pub fn split_by_files(
 
     tokio::spawn(async move {
         while let Some((src, name, message)) = listener.recv().await {
-            match &message {
-                Message::FileChunk(chunk) => {
+            let file_idx = match &message {
+                Message::FileChunk(chunk) => Some(chunk.idx),
+                Message::FileChunkCompressed(chunk) => Some(chunk.idx),
+                Message::FileChunkRepair(repair) => Some(repair.idx),
+                Message::ChunkHashes(hashes) => Some(hashes.idx),
+                _ => None,
+            };
+            match file_idx {
+                Some(file_idx) => {
                     let send_to = {
                         let mut maybe_sender = None;
                         for (i, _file) in data.files.iter().enumerate() {
-                            if (i as u32) == chunk.idx {
+                            if (i as u32) == file_idx {
                                 maybe_sender = Some(&senders[i]);
                             }
                         }
@@ -36,7 +43,7 @@ pub fn split_by_files(
                     };
                     send_to.send((src, name, message)).await.unwrap();
                 }
-                _ => {
+                None => {
                     extra_sender.send((src, name, message)).await.unwrap();
                 }
             }
@@ -0,0 +1,119 @@
+use common::chunk_hashes::{hash_chunk, HASH_SIZE};
+
+/// The hashes of the chunks of a file that we got from the server so far.
+#[derive(Debug, Clone)]
+pub struct KnownHashes {
+    /// The hash of every chunk, if we have it.
+    hashes: Vec<Option<[u8; HASH_SIZE]>>,
+    /// The rolling checksum of every chunk, if we have it.
+    checksums: Vec<Option<u32>>,
+    /// The number of chunks whose hash we don't have yet.
+    missing: usize,
+}
+
+impl KnownHashes {
+    pub fn new(num_chunks: u64) -> Self {
+        Self {
+            hashes: vec![None; num_chunks as usize],
+            checksums: vec![None; num_chunks as usize],
+            missing: num_chunks as usize,
+        }
+    }
+
+    /// Add the hashes and checksums of the chunks from `first_chunk` on, from a `ChunkHashes` message.
+    /// Hashes past the end of the file are ignored.
+    pub fn add(&mut self, first_chunk: u64, hashes: &[u8], checksums: Option<&[u8]>) {
+        let Ok(first) = usize::try_from(first_chunk) else {
+            return;
+        };
+        let Some(known) = self.hashes.get_mut(first..) else {
+            return;
+        };
+        for (known, hash) in known.iter_mut().zip(common::chunk_hashes::split(hashes)) {
+            if known.is_none() {
+                self.missing -= 1;
+            }
+            *known = Some(hash.try_into().unwrap());
+        }
+        let known = &mut self.checksums[first..];
+        for (known, checksum) in known
+            .iter_mut()
+            .zip(common::rolling::split(checksums.unwrap_or_default()))
+        {
+            *known = Some(checksum);
+        }
+    }
+
+    /// Check if we have the hash of a chunk.
+    pub fn is_known(&self, chunk: u64) -> bool {
+        self.hashes.get(chunk as usize).is_some_and(Option::is_some)
+    }
+
+    /// Check if we have the hashes of all the chunks.
+    pub fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+
+    /// The first chunk from `from` on whose hash we don't have yet, if any.
+    pub fn next_unknown(&self, from: u64) -> Option<u64> {
+        let unknown = self
+            .hashes
+            .get(from as usize..)?
+            .iter()
+            .position(Option::is_none)?;
+        Some(from + unknown as u64)
+    }
+
+    /// The hash of a chunk, if we have it.
+    pub fn hash(&self, chunk: u64) -> Option<&[u8; HASH_SIZE]> {
+        self.hashes.get(chunk as usize)?.as_ref()
+    }
+
+    /// The rolling checksum of a chunk, if we have it.
+    pub fn checksum(&self, chunk: u64) -> Option<u32> {
+        *self.checksums.get(chunk as usize)?
+    }
+
+    /// Check a chunk against its hash.
+    /// Returns `None` if we don't have its hash yet.
+    pub fn check(&self, chunk: u64, data: &[u8]) -> Option<bool> {
+        let hash = self.hash(chunk)?;
+        Some(hash_chunk(data) == *hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::rolling::checksum;
+
+    #[test]
+    fn test_known_hashes() {
+        let mut known = KnownHashes::new(3);
+        let hashes: Vec<u8> = [&b"one"[..], b"two", b"three"]
+            .iter()
+            .flat_map(|chunk| hash_chunk(chunk))
+            .collect();
+        let checksums: Vec<u8> = [checksum(b"one"), checksum(b"two")]
+            .iter()
+            .flat_map(|checksum| checksum.to_le_bytes())
+            .collect();
+        known.add(1, &hashes, Some(&checksums));
+        assert!(!known.is_known(0));
+        assert_eq!(known.next_unknown(0), Some(0));
+        assert_eq!(known.next_unknown(1), None);
+        assert!(!known.is_complete());
+        assert_eq!(known.checksum(1), Some(checksum(b"one")));
+        assert_eq!(known.checksum(0), None);
+        assert_eq!(known.check(0, b"zero"), None);
+        assert_eq!(known.check(1, b"one"), Some(true));
+        assert_eq!(known.check(2, b"one"), Some(false));
+        // The third hash is past the end of the file
+        assert!(!known.is_known(3));
+        known.add(0, &hashes, None);
+        assert_eq!(known.next_unknown(0), None);
+        assert!(known.is_complete());
+        assert_eq!(known.checksum(0), None);
+        known.add(7, &hashes, None);
+    }
+}
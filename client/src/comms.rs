@@ -1,6 +1,7 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
-use common::{messages::Message, networking::send_message};
+use common::{capabilities::Capabilities, messages::Message, networking::send_message, Identity};
+use tokio::net::UdpSocket;
 
 /// Structure to hold info on how to send info to the server
 
@@ -8,19 +9,42 @@ use common::{messages::Message, networking::send_message};
 pub struct ServerCommunicator {
     /// The server's SocketAddr
     addr: SocketAddr,
-    /// My name
-    my_name: String,
+    /// Our listening socket, which the messages are sent from
+    socket: Arc<UdpSocket>,
+    /// Who I am
+    identity: Identity,
+    /// The protocol version that we agreed on with the server
+    version: u16,
+    /// The optional features that the server supports
+    capabilities: Capabilities,
 }
 
 impl ServerCommunicator {
     /// Create a new ServerCommunicator
-    pub fn new(addr: SocketAddr, my_name: String) -> Self {
-        Self { addr, my_name }
+    pub fn new(
+        addr: SocketAddr,
+        socket: Arc<UdpSocket>,
+        identity: Identity,
+        version: u16,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self {
+            addr,
+            socket,
+            identity,
+            version,
+            capabilities,
+        }
+    }
+
+    /// Check if the server supports an optional feature
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
     }
 
     /// Send a message to the server
     pub async fn send_message(&self, message: &Message) {
-        send_message(self.addr, &self.my_name, message)
+        send_message(&self.socket, self.addr, &self.identity, self.version, message)
             .await
             .expect("Error while sending message to server over UDP");
     }
@@ -0,0 +1,425 @@
+/// Delta transfer: taking the chunks of a new version of a file from an old copy of it,
+/// so that only the chunks that changed are downloaded.
+///
+/// This is rsync turned around for a server that broadcasts, as in zsync:
+/// the server publishes the hashes and rolling checksums of the chunks of the new version,
+/// and every client searches its own old copy for them.
+/// A chunk is found anywhere in the old copy, so data that moved, as after an insertion, is found too.
+/// From a server whose hashlist has no rolling checksums, only the chunks that stayed in place are found.
+///
+/// The chunks that are found are checked against their hashes,
+/// and the file is checked against its hash once it is complete, like any other.
+///
+/// While the rest of the file downloads, we tell the server which chunks we took from the old copy,
+/// in `CopiedChunks` messages, if it has the `DELTA` capability.
+/// It leaves the chunks that every joined client took out of its broadcasts,
+/// so a file that changed a little takes little of the broadcast too.
+///
+/// The old copy is moved aside, and kept until the new version matches its hash,
+/// or put back if the new version can't be downloaded, so that a failed download doesn't lose it.
+/// A stopped run leaves it aside: the next run takes chunks from it again,
+/// or only removes it once the file is done if it resumes the download.
+use std::{
+    collections::HashMap,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{fs, time::Instant};
+
+use common::{
+    capabilities::Capabilities,
+    chunk_hashes::hash_chunk,
+    messages::{ChunkRange, FileListingFragment, Message},
+    rolling::Rolling,
+    MessageReceiver,
+};
+
+use crate::{chunk_hashes::KnownHashes, comms::ServerCommunicator, server_state::ChunkState};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The number of requests for hashes to have on their way at once:
+/// every `ChunkHashes` message that arrives lets us send another one, so that we go at the server's pace.
+const HASHES_REQUESTS_IN_FLIGHT: usize = 4;
+
+/// How often to request hashes again if none arrive, as requests and replies get lost.
+const HASHES_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait for more hashes before giving up on them, and downloading the whole file.
+const HASHES_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to tell the server which chunks we took from the old copy, as the messages can get lost.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most runs of chunks to tell the server about in one message, so that it fits in a datagram.
+/// If there are more, only the longest ones are told: the server broadcasts the others.
+const MAX_REPORTED_RANGES: usize = 32;
+
+/// The path that the old copy of a file is moved to, while the new version is put in its place.
+fn old_copy_path(path: &Path) -> PathBuf {
+    let mut old = path.as_os_str().to_owned();
+    old.push(".udp-sender-old");
+    PathBuf::from(old)
+}
+
+/// The old copy of a file that a stopped run moved aside, if there is one.
+pub async fn left_over(path: &Path) -> Option<PathBuf> {
+    let old = old_copy_path(path);
+    fs::metadata(&old)
+        .await
+        .is_ok_and(|metadata| metadata.is_file())
+        .then_some(old)
+}
+
+/// Move the old copy of a file aside, so that the new version can be put in its place.
+///
+/// Returns the path of the old copy, if there is one.
+pub async fn set_aside(path: &Path) -> Option<PathBuf> {
+    let old = old_copy_path(path);
+    let metadata = fs::metadata(path).await.ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+    match fs::rename(path, &old).await {
+        Ok(()) => Some(old),
+        Err(e) => {
+            warn!("Failed to move {path:?} aside: {e}");
+            None
+        }
+    }
+}
+
+/// Remove the old copy of a file, once the new version matches its hash.
+pub async fn forget(old: &Path) {
+    if let Err(e) = fs::remove_file(old).await {
+        warn!("Failed to remove {old:?}: {e}");
+    }
+}
+
+/// Put the old copy of a file back in its place, when the new version can't be downloaded.
+pub async fn put_back(old: &Path, path: &Path) {
+    match fs::rename(old, path).await {
+        Ok(()) => info!("Put the old copy of {path:?} back"),
+        Err(e) => warn!("Failed to put {old:?} back in place of {path:?}: {e}"),
+    }
+}
+
+/// Request the hashes of all the chunks of a file, until we have them.
+///
+/// The chunks that arrive meanwhile are dropped: they come round again.
+/// Returns whether we have all the hashes: we give up if the server stops sending them.
+pub async fn fetch_hashes(
+    listener: &mut MessageReceiver,
+    comm: &ServerCommunicator,
+    file: &FileListingFragment,
+    hashes: &mut KnownHashes,
+) -> bool {
+    // The next chunk to request the hash of, going through the file in order and then again for the lost ones
+    let mut next_request = 0;
+    for _ in 0..HASHES_REQUESTS_IN_FLIGHT {
+        request_hashes(comm, file, hashes, &mut next_request).await;
+    }
+    let mut interval = tokio::time::interval(HASHES_REQUEST_INTERVAL);
+    interval.tick().await;
+    let mut last_received = Instant::now();
+    let mut received = false;
+    while !hashes.is_complete() {
+        tokio::select! {
+            _ = interval.tick() => {
+                if last_received.elapsed() > HASHES_TIMEOUT {
+                    return false;
+                }
+                if !received {
+                    request_hashes(comm, file, hashes, &mut next_request).await;
+                }
+                received = false;
+            }
+            Some((_, _, message)) = listener.recv() => {
+                if let Message::ChunkHashes(chunk_hashes) = message {
+                    hashes.add(chunk_hashes.first_chunk, &chunk_hashes.hashes, chunk_hashes.checksums.as_deref());
+                    last_received = Instant::now();
+                    received = true;
+                    request_hashes(comm, file, hashes, &mut next_request).await;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Request the hashes of the next chunks that we don't have the hashes of, from `next_request` on,
+/// or from the start once we get to the end.
+async fn request_hashes(
+    comm: &ServerCommunicator,
+    file: &FileListingFragment,
+    hashes: &KnownHashes,
+    next_request: &mut u64,
+) {
+    let Some(first_chunk) = hashes
+        .next_unknown(*next_request)
+        .or_else(|| hashes.next_unknown(0))
+    else {
+        return;
+    };
+    debug!("Requesting chunk hashes {}-{}", file.idx, first_chunk);
+    comm.send_message(&Message::ChunkHashesRequest {
+        idx: file.idx,
+        first_chunk,
+    })
+    .await;
+    *next_request = first_chunk + common::chunk_hashes::hashes_per_message(file.chunk_size) as u64;
+}
+
+/// Copy the chunks of a file that its old copy has into the file.
+///
+/// Returns the chunks that were copied.
+pub fn copy_from_old(
+    old: &Path,
+    path: &Path,
+    file: &FileListingFragment,
+    hashes: &KnownHashes,
+) -> Result<Vec<u64>, std::io::Error> {
+    let old = std::fs::File::open(old)?;
+    if old.metadata()?.len() == 0 {
+        return Ok(vec![]);
+    }
+    let old = unsafe { memmap::MmapOptions::new().map(&old)? };
+    let chunk_size = file.chunk_size as usize;
+    let found = find_chunks(&old, chunk_size, file.size, hashes);
+
+    let mut new = std::fs::OpenOptions::new().write(true).open(path)?;
+    for (chunk, offset) in found.iter() {
+        let len = chunk_len(*chunk, chunk_size, file.size);
+        new.seek(SeekFrom::Start(chunk * chunk_size as u64))?;
+        new.write_all(&old[*offset..*offset + len])?;
+    }
+    new.flush()?;
+    Ok(found.into_iter().map(|(chunk, _)| chunk).collect())
+}
+
+/// Tell the server which chunks of a file we took from its old copy and still have,
+/// so that it can leave them out of its broadcasts.
+///
+/// Once we lose the chunks, as when the file is downloaded again, this tells the server that we need them again.
+pub async fn report_copied(
+    comm: &ServerCommunicator,
+    file: &FileListingFragment,
+    copied: &[u64],
+    chunks: &ChunkState,
+) {
+    if !comm.supports(Capabilities::DELTA) {
+        return;
+    }
+    let ranges = copied_ranges(copied, chunks);
+    trace!("Telling the server that we copied {ranges:?} of file {}", file.idx);
+    comm.send_message(&Message::CopiedChunks {
+        idx: file.idx,
+        ranges,
+    })
+    .await;
+}
+
+/// The runs of consecutive chunks that were copied, out of the ones we have, in order.
+/// `copied` must be in order.
+fn copied_ranges(copied: &[u64], chunks: &ChunkState) -> Vec<ChunkRange> {
+    let mut ranges: Vec<ChunkRange> = vec![];
+    for &chunk in copied.iter().filter(|&&chunk| chunks.get(chunk)) {
+        match ranges.last_mut() {
+            Some(range) if range.end() == chunk => range.count += 1,
+            _ => ranges.push(ChunkRange {
+                first: chunk,
+                count: 1,
+            }),
+        }
+    }
+    if ranges.len() > MAX_REPORTED_RANGES {
+        ranges.sort_by_key(|range| std::cmp::Reverse(range.count));
+        ranges.truncate(MAX_REPORTED_RANGES);
+        ranges.sort_by_key(|range| range.first);
+    }
+    ranges
+}
+
+/// The size of a chunk of a file: the last chunk may be shorter than the others.
+fn chunk_len(chunk: u64, chunk_size: usize, size: u64) -> usize {
+    (size - chunk * chunk_size as u64).min(chunk_size as u64) as usize
+}
+
+/// Find the chunks of a file in an old copy of it.
+///
+/// Returns every chunk that the old copy has, with where it is in the old copy.
+fn find_chunks(
+    old: &[u8],
+    chunk_size: usize,
+    size: u64,
+    hashes: &KnownHashes,
+) -> Vec<(u64, usize)> {
+    let num_chunks = size.div_ceil(chunk_size as u64);
+    let mut found = vec![None; num_chunks as usize];
+
+    // Look for the chunks with a window of a whole chunk that rolls along the old copy,
+    // so the last chunk, which may be shorter, is only looked for in place below
+    let mut by_checksum: HashMap<u32, Vec<u64>> = HashMap::new();
+    for chunk in 0..num_chunks {
+        if chunk_len(chunk, chunk_size, size) == chunk_size {
+            if let Some(checksum) = hashes.checksum(chunk) {
+                by_checksum.entry(checksum).or_default().push(chunk);
+            }
+        }
+    }
+    if !by_checksum.is_empty() && old.len() >= chunk_size {
+        let mut offset = 0;
+        let mut rolling = Rolling::new(&old[..chunk_size]);
+        loop {
+            let mut matched = false;
+            if let Some(candidates) = by_checksum.get(&rolling.value()) {
+                // The checksum is weak, so the hash must match too
+                if candidates
+                    .iter()
+                    .any(|chunk| found[*chunk as usize].is_none())
+                {
+                    let hash = hash_chunk(&old[offset..offset + chunk_size]);
+                    for chunk in candidates {
+                        // Chunks with the same data, like chunks of zeroes, are all found here
+                        if found[*chunk as usize].is_none() && hashes.hash(*chunk) == Some(&hash) {
+                            found[*chunk as usize] = Some(offset);
+                            matched = true;
+                        }
+                    }
+                }
+            }
+
+            // After a chunk that was found, the next one most likely follows it
+            let next = if matched {
+                offset + chunk_size
+            } else {
+                offset + 1
+            };
+            if next + chunk_size > old.len() {
+                break;
+            }
+            if matched {
+                rolling = Rolling::new(&old[next..next + chunk_size]);
+            } else {
+                rolling.roll(old[offset], old[offset + chunk_size]);
+            }
+            offset = next;
+        }
+    }
+
+    // Look for the other chunks where they were, which is all of them if there are no checksums
+    for chunk in 0..num_chunks {
+        if found[chunk as usize].is_some() {
+            continue;
+        }
+        let start = chunk as usize * chunk_size;
+        let Some(data) = old.get(start..start + chunk_len(chunk, chunk_size, size)) else {
+            continue;
+        };
+        // The checksum is quicker to check than the hash, if we have it
+        let checksum = common::rolling::checksum(data);
+        if hashes.checksum(chunk).unwrap_or(checksum) == checksum
+            && hashes.check(chunk, data) == Some(true)
+        {
+            found[chunk as usize] = Some(start);
+        }
+    }
+
+    found
+        .into_iter()
+        .enumerate()
+        .filter_map(|(chunk, offset)| Some((chunk as u64, offset?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The hashes and checksums of the chunks of a file, as the server sends them.
+    fn known_hashes(data: &[u8], chunk_size: usize, with_checksums: bool) -> KnownHashes {
+        let chunks: Vec<&[u8]> = data.chunks(chunk_size).collect();
+        let hashes: Vec<u8> = chunks.iter().flat_map(|chunk| hash_chunk(chunk)).collect();
+        let checksums: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| common::rolling::checksum(chunk).to_le_bytes())
+            .collect();
+        let mut known = KnownHashes::new(chunks.len() as u64);
+        known.add(0, &hashes, with_checksums.then_some(&checksums[..]));
+        known
+    }
+
+    #[test]
+    fn test_find_chunks() {
+        let mut seed = 1u32;
+        let old: Vec<u8> = (0..1000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        // The new version has 5 bytes inserted in the third chunk, and a different end
+        let mut new = old[..250].to_vec();
+        new.extend(b"12345");
+        new.extend(&old[250..900]);
+        new.extend(b"the end");
+        let chunk_size = 100;
+
+        // The chunks before the insertion are in place, and the ones after it moved by 5 bytes
+        let hashes = known_hashes(&new, chunk_size, true);
+        let found = find_chunks(&old, chunk_size, new.len() as u64, &hashes);
+        assert_eq!(
+            found,
+            [
+                (0, 0),
+                (1, 100),
+                (3, 295),
+                (4, 395),
+                (5, 495),
+                (6, 595),
+                (7, 695),
+                (8, 795)
+            ]
+        );
+
+        // Without checksums, only the chunks in place are found
+        let hashes = known_hashes(&new, chunk_size, false);
+        let found = find_chunks(&old, chunk_size, new.len() as u64, &hashes);
+        assert_eq!(found, [(0, 0), (1, 100)]);
+    }
+
+    #[test]
+    fn test_copied_ranges() {
+        let range = |first, count| ChunkRange { first, count };
+        let mut chunks = ChunkState::from_file_size(200 * 10, 10);
+        let copied: Vec<u64> = (0..5).chain(7..10).chain(20..21).collect();
+        for chunk in copied.iter() {
+            chunks.set(*chunk, true);
+        }
+        assert_eq!(
+            copied_ranges(&copied, &chunks),
+            [range(0, 5), range(7, 3), range(20, 1)]
+        );
+
+        // Chunks that we lost again are not told about
+        chunks.set(8, false);
+        assert_eq!(
+            copied_ranges(&copied, &chunks),
+            [range(0, 5), range(7, 1), range(9, 1), range(20, 1)]
+        );
+        chunks.clear();
+        assert_eq!(copied_ranges(&copied, &chunks), []);
+
+        // Only the longest runs are told about
+        let copied: Vec<u64> = (0..100).filter(|chunk| chunk % 3 != 2).chain(150..160).collect();
+        for chunk in copied.iter() {
+            chunks.set(*chunk, true);
+        }
+        let ranges = copied_ranges(&copied, &chunks);
+        assert_eq!(ranges.len(), MAX_REPORTED_RANGES);
+        assert_eq!(ranges.last(), Some(&range(150, 10)));
+        assert!(ranges.windows(2).all(|pair| pair[0].end() < pair[1].first));
+    }
+}
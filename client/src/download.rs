@@ -1,32 +1,198 @@
-use std::path::PathBuf;
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::{Path, PathBuf},
+};
 use tokio::sync::mpsc::Sender;
 
 use common::{
-    messages::{FileListingFragment, Message},
+    messages::{CompressedFileChunkData, FileChunkData, FileListingFragment, Message},
     MessageReceiver,
 };
 
 use crate::{
-    comms::ServerCommunicator, progress_indicator::ProgressEvent, server_state::ChunkState,
+    chunk_hashes::KnownHashes, comms::ServerCommunicator, progress_indicator::ProgressEvent,
+    server_state::ChunkState,
 };
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+/// How to download files.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Request a missing chunk every this many microseconds, or never if 0.
+    pub request_interval_us: u64,
+    /// The number of times to download a file that doesn't match its hash.
+    pub max_attempts: u32,
+    /// The directory to save the progress of downloads in, if they can be resumed.
+    pub state_dir: Option<PathBuf>,
+    /// Whether to take the chunks that an old copy of a file has from it: see the `delta` module.
+    pub delta: bool,
+}
+
+/// How often to save the progress of a download.
+const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The repair shards received so far for a block of chunks.
+struct PendingBlock {
+    /// The number of chunks in the block.
+    data_shards: u16,
+    /// The repair shards, indexed by shard number.
+    repair: Vec<Option<Bytes>>,
+}
+
+/// Download a file, until all its chunks are received and it matches its hash.
+///
+/// If the server has the hashes of the file's chunks, every chunk is checked against its hash as it arrives,
+/// and chunks that don't match are dropped, to be received again.
+///
+/// If the file doesn't match its hash, it is downloaded again, up to `max_attempts` times in all:
+/// only the chunks that don't match their hashes, if we have them, or else the whole file.
+/// Returns whether the file was downloaded correctly.
+///
+/// If there is a state directory, the chunks that we have are saved there as they arrive,
+/// and forgotten once the file is done.
+///
+/// If we are not resuming the download, and there is an old copy of the file with the hashes of its chunks,
+/// the chunks that the old copy has are taken from it, and only the others are downloaded.
+/// The old copy is removed once the file matches its hash, and put back if it doesn't: see the `delta` module.
 pub async fn download_file(
-    mut listener: MessageReceiver,
+    listener: MessageReceiver,
     comm: ServerCommunicator,
     file: FileListingFragment,
     mut chunks: ChunkState,
     progress_sender: Sender<ProgressEvent>,
-    request_interval_us: u64,
-) {
+    options: DownloadOptions,
+) -> bool {
+    let max_attempts = options.max_attempts;
+    let path = file.local_path().expect("The path was checked before downloading");
+    let mut listener = listener;
+    let mut hashes = file.chunk_hashes.then(|| KnownHashes::new(chunks.num_chunks));
+
+    // Move an old copy of the file aside, and put the new version in its place.
+    // A stopped run may have moved it aside already.
+    let resuming = chunks.count() > 0;
+    let mut old_copy = crate::delta::left_over(&path).await;
+    if old_copy.is_none() && options.delta && hashes.is_some() && !resuming {
+        old_copy = crate::delta::set_aside(&path).await;
+    }
+    common::filesystem::allocate(&path, file.size)
+        .await
+        .expect("Failed to allocate file");
+    // The chunks that we took from the old copy, which the server need not send us
+    let mut copied = vec![];
+    if let (Some(old_copy), Some(hashes)) = (&old_copy, &mut hashes) {
+        if options.delta && !resuming {
+            copied = take_from_old_copy(
+                &mut listener,
+                &comm,
+                &file,
+                &path,
+                old_copy,
+                &mut chunks,
+                hashes,
+                &progress_sender,
+            )
+            .await;
+        }
+    }
+    for attempt in 1..=max_attempts {
+        if attempt > 1 {
+            let bad_chunks = match &hashes {
+                Some(hashes) => drain_while(&mut listener, find_bad_chunks(&path, &file, &chunks, hashes)).await,
+                None => vec![],
+            };
+            if bad_chunks.is_empty() {
+                chunks.clear();
+                progress_sender.send(ProgressEvent::FileReset(file.idx.into())).await.expect("Failed to send progress event");
+            } else {
+                debug!("Downloading {} chunks of file {} again", bad_chunks.len(), file.idx);
+                for chunk in bad_chunks {
+                    chunks.set(chunk, false);
+                    progress_sender.send(ProgressEvent::ChunkReset(file.idx.into(), chunk)).await.expect("Failed to send progress event");
+                }
+            }
+        }
+        let complete;
+        (listener, complete) = download_chunks(listener, &comm, &file, &mut chunks, &mut hashes, &copied, &progress_sender, &options).await;
+        if !complete {
+            warn!(
+                "The server keeps sending chunks of {:?} that don't match their hashes: giving up on it",
+                common::paths::display(&file.path)
+            );
+            break;
+        }
+
+        let hash = drain_while(&mut listener, common::filesystem::hash_file(&path))
+            .await
+            .expect("Failed to read back file");
+        if hash == file.hash {
+            debug!("File {:?} matches its hash", file);
+            progress_sender.send(ProgressEvent::FileDone(file.idx.into())).await.expect("Failed to send progress event");
+            // We need to drain the channel, otherwise it will be dropped and this will stop the download
+            common::channels::drain(listener);
+            if let Some(state_dir) = &options.state_dir {
+                common::resume::remove(state_dir, &file).await;
+            }
+            if let Some(old_copy) = &old_copy {
+                crate::delta::forget(old_copy).await;
+            }
+            return true;
+        }
+        warn!(
+            "File {:?} does not match its hash after attempt {attempt} of {max_attempts}",
+            common::paths::display(&file.path)
+        );
+    }
+
+    progress_sender.send(ProgressEvent::FileFailed(file.idx.into())).await.expect("Failed to send progress event");
+    common::channels::drain(listener);
+    // The chunks that we have are bad, so the next run should start over
+    if let Some(state_dir) = &options.state_dir {
+        common::resume::remove(state_dir, &file).await;
+    }
+    if let Some(old_copy) = &old_copy {
+        crate::delta::put_back(old_copy, &path).await;
+    }
+    false
+}
+
+/// Receive and request the chunks of a file, until we have all of them.
+/// Meanwhile, the server is told which of the chunks that we have were `copied` from an old copy of the file.
+///
+/// Returns the listener, to keep receiving chunks of the file if it has to be downloaded again,
+/// and whether we have all the chunks: we give up if a chunk doesn't match its hash `max_attempts` times.
+#[allow(clippy::too_many_arguments)]
+async fn download_chunks(
+    mut listener: MessageReceiver,
+    comm: &ServerCommunicator,
+    file: &FileListingFragment,
+    chunks: &mut ChunkState,
+    hashes: &mut Option<KnownHashes>,
+    copied: &[u64],
+    progress_sender: &Sender<ProgressEvent>,
+    options: &DownloadOptions,
+) -> (MessageReceiver, bool) {
+    let request_interval_us = options.request_interval_us;
     let mut timeout = if request_interval_us == 0 {
         tokio::time::interval(std::time::Duration::from_secs(10)) // This interval will not be used
     } else {
         tokio::time::interval(std::time::Duration::from_micros(request_interval_us))
     };
-    let path = PathBuf::from(&file.path);
+    let path = file.local_path().expect("The path was checked before downloading");
+
+    // Repair shards for blocks that are still missing chunks, keyed by the first chunk of the block
+    let mut pending_blocks: BTreeMap<u64, PendingBlock> = BTreeMap::new();
+    // Used to read back the chunks we already have when rebuilding a block
+    let mut mmaps = HashMap::new();
+    // The number of times that each chunk didn't match its hash
+    let mut rejected: HashMap<u64, u32> = HashMap::new();
+    // Whether the chunks changed since they were last saved
+    let mut changed = true;
+    let mut save_interval = tokio::time::interval(SAVE_INTERVAL);
+    let mut report_interval = tokio::time::interval(crate::delta::REPORT_INTERVAL);
 
     // Listen for messages containing chunks, and if the interval ticks, request the next chunk
     loop {
@@ -38,34 +204,303 @@ pub async fn download_file(
                 }
 
                 if let Some(next_chunk) = chunks.get_zero() {
+                    // Ask for the hash of the chunk too, so that we can check it
+                    if hashes.as_ref().is_some_and(|hashes| !hashes.is_known(next_chunk)) {
+                        debug!("Requesting chunk hashes {}-{}", file.idx, next_chunk);
+                        comm.send_message(&Message::ChunkHashesRequest{idx: file.idx, first_chunk: next_chunk}).await;
+                    }
                     debug!("Requesting chunk {} for file {:?}", next_chunk, file);
                     comm.send_message(&Message::FileChunkRequest{idx: file.idx, chunk: next_chunk}).await;
                     progress_sender.send(ProgressEvent::ChunkRequested(file.idx.into(), next_chunk)).await.expect("Failed to send progress event");
                 } else {
                     debug!("All chunks received for file {:?}!", file);
-                    progress_sender.send(ProgressEvent::FileDone(file.idx.into())).await.expect("Failed to send progress event");
-                    // We need to drain the channel, otherwise it will be dropped and this will stop the download
-                    common::channels::drain(listener);
-                    break;
+                    return (listener, true);
                 }
             }
+            _ = save_interval.tick() => {
+                if let Some(state_dir) = options.state_dir.as_ref().filter(|_| changed) {
+                    if let Err(e) = common::resume::save(state_dir, file, chunks.bitmap()).await {
+                        warn!("Failed to save the progress of file {}: {e}", file.idx);
+                    }
+                    changed = false;
+                }
+            }
+            _ = report_interval.tick(), if !copied.is_empty() => {
+                crate::delta::report_copied(comm, file, copied, chunks).await;
+            }
             Some((_, _, message)) = listener.recv() => {
-                if let Message::FileChunk(chunk) = message {
-                    debug!("Got chunk {}-{}", chunk.idx, chunk.chunk);
-                    common::filesystem::write_chunk(&path, file.chunk_size as u64, chunk.chunk, &chunk.data).await.expect("Failed to write chunk");
-                    chunks.set(chunk.chunk, true);
-                    progress_sender.send(ProgressEvent::ChunkDownloaded(file.idx.into(), chunk.chunk, chunk.data.len())).await.expect("Failed to send progress event");
-
-                    // If we have all the chunks, we can stop listening
-                    if chunks.is_complete() {
-                        debug!("All chunks received for file {:?}!", file);
-                        progress_sender.send(ProgressEvent::FileDone(file.idx.into())).await.expect("Failed to send progress event");
-                        // We need to drain the channel, otherwise it will be dropped and this will stop the download
-                        common::channels::drain(listener);
-                        break;
+                // Compressed chunks are handled like any other chunk, once decompressed
+                let message = match message {
+                    Message::FileChunkCompressed(chunk) => match decompress_chunk(file, chunk) {
+                        Some(chunk) => Message::FileChunk(chunk),
+                        None => continue,
+                    },
+                    message => message,
+                };
+                match message {
+                    Message::FileChunk(chunk) => {
+                        debug!("Got chunk {}-{}", chunk.idx, chunk.chunk);
+                        if hashes.as_ref().and_then(|hashes| hashes.check(chunk.chunk, &chunk.data)) == Some(false) {
+                            warn!("Chunk {}-{} does not match its hash, dropping it", chunk.idx, chunk.chunk);
+                            let rejections = rejected.entry(chunk.chunk).or_default();
+                            *rejections += 1;
+                            if *rejections >= options.max_attempts {
+                                return (listener, false);
+                            }
+                            continue;
+                        }
+                        common::filesystem::write_chunk(&path, file.chunk_size as u64, chunk.chunk, &chunk.data).await.expect("Failed to write chunk");
+                        chunks.set(chunk.chunk, true);
+                        changed = true;
+                        progress_sender.send(ProgressEvent::ChunkDownloaded(file.idx.into(), chunk.chunk, chunk.data.len())).await.expect("Failed to send progress event");
+
+                        // This chunk may be the last one we needed to rebuild the rest of its block
+                        let pending_block = pending_blocks
+                            .range(..=chunk.chunk)
+                            .next_back()
+                            .filter(|(first_chunk, block)| chunk.chunk < **first_chunk + block.data_shards as u64)
+                            .map(|(first_chunk, _)| *first_chunk);
+                        if let Some(first_chunk) = pending_block {
+                            let block = &pending_blocks[&first_chunk];
+                            if try_repair(first_chunk, block, chunks, file, hashes.as_ref(), &path, &mut mmaps, progress_sender).await {
+                                pending_blocks.remove(&first_chunk);
+                            }
+                        }
+                    }
+                    Message::FileChunkRepair(repair) => {
+                        debug!("Got repair shard {}-{}+{}", repair.idx, repair.first_chunk, repair.shard);
+                        if repair.first_chunk + repair.data_shards as u64 > chunks.num_chunks
+                            || repair.shard >= repair.parity_shards
+                            || repair.data.len() != file.chunk_size as usize
+                        {
+                            warn!("Ignoring malformed repair shard {}-{}+{}", repair.idx, repair.first_chunk, repair.shard);
+                            continue;
+                        }
+                        let block = pending_blocks.entry(repair.first_chunk).or_insert_with(|| PendingBlock {
+                            data_shards: repair.data_shards,
+                            repair: vec![None; repair.parity_shards as usize],
+                        });
+                        if block.data_shards != repair.data_shards || block.repair.len() != repair.parity_shards as usize {
+                            // The server changed its FEC parameters: forget the old shards
+                            block.data_shards = repair.data_shards;
+                            block.repair = vec![None; repair.parity_shards as usize];
+                        }
+                        block.repair[repair.shard as usize] = Some(repair.data);
+                        changed = true;
+                        let block = &pending_blocks[&repair.first_chunk];
+                        if try_repair(repair.first_chunk, block, chunks, file, hashes.as_ref(), &path, &mut mmaps, progress_sender).await {
+                            pending_blocks.remove(&repair.first_chunk);
+                        }
                     }
+                    Message::ChunkHashes(chunk_hashes) => {
+                        debug!("Got chunk hashes {}-{}", chunk_hashes.idx, chunk_hashes.first_chunk);
+                        if let Some(hashes) = hashes {
+                            hashes.add(chunk_hashes.first_chunk, &chunk_hashes.hashes, chunk_hashes.checksums.as_deref());
+                        }
+                    }
+                    _ => {}
+                }
+
+                // If we have all the chunks, we can stop listening
+                if chunks.is_complete() {
+                    debug!("All chunks received for file {:?}!", file);
+                    return (listener, true);
                 }
             }
         }
     }
 }
+
+/// Take the chunks of a file that its old copy has from it.
+///
+/// Returns the chunks that were taken, in order.
+#[allow(clippy::too_many_arguments)]
+async fn take_from_old_copy(
+    listener: &mut MessageReceiver,
+    comm: &ServerCommunicator,
+    file: &FileListingFragment,
+    path: &Path,
+    old_copy: &Path,
+    chunks: &mut ChunkState,
+    hashes: &mut KnownHashes,
+    progress_sender: &Sender<ProgressEvent>,
+) -> Vec<u64> {
+    if crate::delta::fetch_hashes(listener, comm, file, hashes).await {
+        let task = {
+            let (old_copy, path) = (old_copy.to_path_buf(), path.to_path_buf());
+            let (file, hashes) = (file.clone(), hashes.clone());
+            tokio::task::spawn_blocking(move || {
+                crate::delta::copy_from_old(&old_copy, &path, &file, &hashes)
+            })
+        };
+        match drain_while(listener, task).await.unwrap() {
+            Ok(copied) => {
+                info!(
+                    "Took {} of {} chunks of {:?} from its old copy",
+                    copied.len(),
+                    chunks.num_chunks,
+                    common::paths::display(&file.path)
+                );
+                for chunk in copied.iter() {
+                    chunks.set(*chunk, true);
+                }
+                progress_sender
+                    .send(ProgressEvent::ChunksCopied(file.idx.into(), copied.clone()))
+                    .await
+                    .expect("Failed to send progress event");
+                copied
+            }
+            Err(e) => {
+                warn!("Failed to take chunks from {old_copy:?}: {e}");
+                vec![]
+            }
+        }
+    } else {
+        warn!(
+            "Did not get the hashes of all the chunks of {:?}: downloading all of it",
+            common::paths::display(&file.path)
+        );
+        vec![]
+    }
+}
+
+/// Run a task on the downloaded file, like hashing it.
+///
+/// The chunks that arrive meanwhile are dropped, as we already have them,
+/// so that the other files' chunks don't wait behind them.
+pub async fn drain_while<T>(listener: &mut MessageReceiver, task: impl Future<Output = T>) -> T {
+    tokio::pin!(task);
+    loop {
+        tokio::select! {
+            result = &mut task => return result,
+            Some(_) = listener.recv() => {}
+        }
+    }
+}
+
+/// Find the chunks of a downloaded file that don't match their hashes, or whose hashes we don't have.
+async fn find_bad_chunks(
+    path: &PathBuf,
+    file: &FileListingFragment,
+    chunks: &ChunkState,
+    hashes: &KnownHashes,
+) -> Vec<u64> {
+    let mut mmaps = HashMap::new();
+    let mut bad_chunks = vec![];
+    for chunk in 0..chunks.num_chunks {
+        let data = common::filesystem::read_chunk(path, file.chunk_size.into(), chunk, &mut mmaps)
+            .await
+            .expect("Failed to read back chunk");
+        if hashes.check(chunk, &data) != Some(true) {
+            bad_chunks.push(chunk);
+        }
+    }
+    bad_chunks
+}
+
+/// Decompress a compressed chunk of the file.
+///
+/// Returns `None`, after logging a warning, if the chunk is not part of the file
+/// or does not decompress to the size that it should have.
+fn decompress_chunk(
+    file: &FileListingFragment,
+    chunk: CompressedFileChunkData,
+) -> Option<FileChunkData> {
+    let chunk_size = file.chunk_size as u64;
+    let offset = chunk.chunk.checked_mul(chunk_size).filter(|offset| *offset < file.size);
+    let Some(offset) = offset else {
+        warn!("Ignoring compressed chunk {}-{} past the end of the file", chunk.idx, chunk.chunk);
+        return None;
+    };
+    let size = (file.size - offset).min(chunk_size) as usize;
+    match chunk.compression.decompress(&chunk.data, size) {
+        Ok(data) => Some(FileChunkData {
+            idx: chunk.idx,
+            chunk: chunk.chunk,
+            data: data.into(),
+        }),
+        Err(e) => {
+            warn!("Failed to decompress chunk {}-{}: {e:?}", chunk.idx, chunk.chunk);
+            None
+        }
+    }
+}
+
+/// Try to rebuild the missing chunks of a block
+/// from the chunks and repair shards that were received so far.
+///
+/// Returns true if the block is now complete, and its repair shards can be forgotten.
+///
+/// Rebuilt chunks are checked against their hashes, if we have them, like chunks that arrive.
+/// Those that don't match are dropped, to be received again, and so are the repair shards:
+/// one of them must be bad, and would only rebuild the chunks wrong again.
+#[allow(clippy::too_many_arguments)]
+async fn try_repair(
+    first_chunk: u64,
+    block: &PendingBlock,
+    chunks: &mut ChunkState,
+    file: &FileListingFragment,
+    hashes: Option<&KnownHashes>,
+    path: &PathBuf,
+    mmaps: &mut HashMap<PathBuf, memmap::Mmap>,
+    progress_sender: &Sender<ProgressEvent>,
+) -> bool {
+    let data_shards = block.data_shards as u64;
+    let block_chunks = first_chunk..first_chunk + data_shards;
+    let missing: Vec<u64> = block_chunks.clone().filter(|&c| !chunks.get(c)).collect();
+    if missing.is_empty() {
+        return true;
+    }
+    let received_repair = block.repair.iter().filter(|shard| shard.is_some()).count();
+    if data_shards as usize - missing.len() + received_repair < data_shards as usize {
+        // Not enough shards yet
+        return false;
+    }
+
+    debug!(
+        "Rebuilding {} chunks of file {} from block at {}",
+        missing.len(),
+        file.idx,
+        first_chunk
+    );
+    let chunk_size = file.chunk_size as u64;
+    let mut shards = Vec::with_capacity(block.data_shards as usize + block.repair.len());
+    for chunk in block_chunks {
+        if chunks.get(chunk) {
+            let mut data = common::filesystem::read_chunk(path, chunk_size, chunk, mmaps)
+                .await
+                .expect("Failed to read back chunk");
+            data.resize(chunk_size as usize, 0);
+            shards.push(Some(data));
+        } else {
+            shards.push(None);
+        }
+    }
+    shards.extend(block.repair.iter().map(|shard| shard.as_ref().map(|shard| shard.to_vec())));
+    if let Err(e) = common::fec::reconstruct(&mut shards, data_shards as usize) {
+        warn!("Failed to rebuild block at {first_chunk} of file {}: {e:?}", file.idx);
+        return false;
+    }
+
+    for chunk in missing {
+        let mut data = shards[(chunk - first_chunk) as usize]
+            .take()
+            .expect("Rebuilt block is missing a chunk");
+        // The last chunk of the file was padded, so cut it back to size
+        let chunk_len = (file.size - chunk * chunk_size).min(chunk_size);
+        data.truncate(chunk_len as usize);
+        if hashes.and_then(|hashes| hashes.check(chunk, &data)) == Some(false) {
+            warn!("Rebuilt chunk {}-{} does not match its hash, dropping it", file.idx, chunk);
+            continue;
+        }
+        common::filesystem::write_chunk(path, chunk_size, chunk, &data)
+            .await
+            .expect("Failed to write chunk");
+        chunks.set(chunk, true);
+        progress_sender
+            .send(ProgressEvent::ChunkDownloaded(file.idx.into(), chunk, data.len()))
+            .await
+            .expect("Failed to send progress event");
+    }
+    true
+}
@@ -0,0 +1,63 @@
+/// Finding the files that are already here, so that they don't need to be downloaded again.
+///
+/// A file is already here if it has the size and hash that the server lists for it.
+/// Hashing every file can take a long time, so a hashlist of the local files, made by `hasher hash`,
+/// can be trusted instead: a file whose size matches its entry in it is taken to have the entry's hash.
+use std::collections::HashMap;
+
+use hasher::hashlist::{FileHashItem, HashList};
+
+use crate::server_state::ServerData;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// Mark every chunk of the files that are already here as downloaded.
+///
+/// Files that were partly downloaded by a previous run are left alone: they are checked when they are complete.
+/// Returns whether each file is already here.
+pub async fn find_existing_files(
+    state: &mut ServerData,
+    local_hashlist: Option<&HashList>,
+) -> Vec<bool> {
+    let listed: HashMap<&[u8], &FileHashItem> = local_hashlist
+        .iter()
+        .flat_map(|hashlist| hashlist.files.iter())
+        .map(|entry| (&entry.path[..], entry))
+        .collect();
+    let mut existing = vec![false; state.files.len()];
+    for ((file, chunks), existing) in state.files.iter_mut().zip(existing.iter_mut()) {
+        if chunks.count() > 0 {
+            continue;
+        }
+        let Ok(path) = file.local_path() else {
+            continue;
+        };
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        if !metadata.is_file() || metadata.len() != file.size {
+            continue;
+        }
+
+        let listed = listed
+            .get(&file.path[..])
+            .filter(|entry| entry.size == file.size);
+        let matches = match listed {
+            Some(entry) => entry.hash == file.hash,
+            None => match common::filesystem::hash_file(&path).await {
+                Ok(hash) => hash == file.hash,
+                Err(e) => {
+                    warn!("Failed to hash {path:?}: {e}");
+                    false
+                }
+            },
+        };
+        if matches {
+            debug!("{:?} is already here", common::paths::display(&file.path));
+            chunks.fill();
+            *existing = true;
+        }
+    }
+    existing
+}
@@ -2,8 +2,11 @@
 
 mod args;
 mod channels;
+mod chunk_hashes;
 mod comms;
+mod delta;
 mod download;
+mod existing;
 mod packet_counter;
 mod pong_listener;
 mod progress_indicator;
@@ -11,17 +14,21 @@ mod server_discover;
 mod server_state;
 mod server_state_initialization;
 
-use std::{net::SocketAddr, path::PathBuf};
-
 use args::Args;
 use clap::Parser;
+use std::path::PathBuf;
 
-use common::messages::{DisconnectReason, Message};
+use common::{
+    messages::{DisconnectReason, Message},
+    multicast::MulticastGroup,
+    networking::{ListenOptions, SocketOptions},
+    sequence::SequenceTrackers,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use tokio::sync::mpsc;
 
-use crate::progress_indicator::ProgressIndicator;
+use crate::progress_indicator::{ProgressEvent, ProgressIndicator};
 
 #[tokio::main]
 async fn main() {
@@ -36,9 +43,51 @@ async fn main() {
 
     eprintln!("Starting client as {my_name}");
 
+    let psk = common::auth::Psk::from_args(args.psk.as_deref(), args.psk_file.as_deref())
+        .expect("Failed to read pre-shared key file");
+    assert!(
+        psk.is_some() || !args.encrypt,
+        "Encryption requires a pre-shared key"
+    );
+    let identity = common::Identity {
+        name: my_name.clone(),
+        instance: common::make_instance_id(),
+        psk,
+        encrypt: args.encrypt,
+        checksum: args.checksum,
+    };
+
     // Create a listener
-    let addresses = vec![SocketAddr::new(args.ip.parse().unwrap(), args.port)];
-    let og_listener = common::networking::make_listener(addresses, &my_name);
+    let addresses = vec![common::networking::parse_addr(&args.ip, args.port).unwrap()];
+    let groups: Vec<MulticastGroup> = args
+        .multicast_group
+        .iter()
+        .map(|group| MulticastGroup::parse(group, args.multicast_source.as_deref()).unwrap())
+        .collect();
+    let mut interfaces = args.iface;
+    if args.all_interfaces {
+        for interface in common::interfaces::list().expect("Failed to list network interfaces") {
+            if !interface.is_loopback() && !interfaces.contains(&interface.name) {
+                interfaces.push(interface.name);
+            }
+        }
+        info!("Listening on interfaces {interfaces:?}");
+    }
+    let sequences = SequenceTrackers::new();
+    let listen_options = ListenOptions {
+        groups,
+        interfaces,
+        sequences: Some(sequences.clone()),
+        socket: SocketOptions {
+            recv_buffer: args.recv_buffer,
+            send_buffer: args.send_buffer,
+            dscp: args.dscp,
+            ttl: args.ttl,
+            ..Default::default()
+        },
+    };
+    let (og_listener, socket) =
+        common::networking::make_listener(addresses, &identity, &listen_options);
 
     // Count the packets
     let (sender, mut listener) = mpsc::channel(100);
@@ -52,13 +101,30 @@ async fn main() {
     ));
 
     // Discover the server
-    let server_addr =
-        server_discover::discover_server(&mut listener, &my_name, args.server_name.as_deref())
-            .await
-            .unwrap();
+    let (server_addr, server_name, version, capabilities) = match server_discover::discover_server(
+        &mut listener,
+        &socket,
+        &identity,
+        args.server_name.as_deref(),
+    )
+    .await
+    {
+        Some(server) => server,
+        None => {
+            eprintln!("Could not join a server");
+            std::process::exit(1);
+        }
+    };
     let server_port = server_addr.port();
+    info!("Talking to server at {server_addr} in protocol version {version}");
 
-    let server_comm = comms::ServerCommunicator::new(server_addr, my_name.clone());
+    let server_comm = comms::ServerCommunicator::new(
+        server_addr,
+        socket.clone(),
+        identity.clone(),
+        version,
+        capabilities,
+    );
 
     // Respond to pings
     let (ping_listener, listener) = common::channels::filter_branch_pred(
@@ -73,9 +139,17 @@ async fn main() {
     let (sender, receiver) = mpsc::channel(100);
     common::channels::drain(receiver);
 
-    let my_name_out = my_name.clone();
+    let identity_out = identity.clone();
     tokio::spawn(async move {
-        common::ping_reply::reply_to_pings(ping_listener, my_name_out, server_port, sender).await;
+        common::ping_reply::reply_to_pings(
+            ping_listener,
+            socket,
+            identity_out,
+            server_port,
+            version,
+            sender,
+        )
+        .await;
     });
 
     // Periodically send a ping, listening for pongs
@@ -99,39 +173,102 @@ async fn main() {
             comm,
             count_receiver,
             reset_sender,
+            sequences,
+            server_name,
         )
         .await;
     });
 
     // We now need to get the initial server state.
-    let state =
+    let mut state =
         server_state_initialization::initialize_state(&mut listener, server_comm.clone()).await;
 
+    // Refuse paths that would leave the current directory, or that can't be stored here.
+    // The other files are still downloaded, and these are listed with the ones that failed.
+    for (file, _) in state.files.iter() {
+        if let Err(e) = file.local_path() {
+            warn!("Not downloading {:?}: {e}", common::paths::display(&file.path));
+        }
+    }
+
+    // Continue the downloads that a previous run did not finish
+    let state_dir = (!args.no_resume).then(|| PathBuf::from(&args.state_dir));
+    if let Some(state_dir) = &state_dir {
+        for (file, chunks) in state.files.iter_mut() {
+            let Some(bitmap) = common::resume::load(state_dir, file).await else {
+                continue;
+            };
+            // The chunks are only there if the file is too
+            let Ok(path) = file.local_path() else {
+                continue;
+            };
+            let exists = std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() == file.size);
+            if exists && chunks.restore(bitmap) {
+                info!(
+                    "Resuming {:?} with {} of {} chunks",
+                    common::paths::display(&file.path),
+                    chunks.count(),
+                    chunks.num_chunks
+                );
+            }
+        }
+    }
+
+    // Don't download the files that are already here
+    let local_hashlist = args.local_hashlist.map(|path| {
+        info!("Loading local hashlist from {path}");
+        hasher::hashlist::HashList::load(path).expect("Failed to read local hashlist")
+    });
+    // Nothing reads the messages that arrive meanwhile, so drop them: otherwise they would hold up the pongs
+    let existing = download::drain_while(
+        &mut listener,
+        existing::find_existing_files(&mut state, local_hashlist.as_ref()),
+    )
+    .await;
+    let existing_count = existing.iter().filter(|existing| **existing).count();
+    if existing_count > 0 {
+        info!("{existing_count} files are already here");
+    }
+
     // Initialize the progress indicator
     let mut indicator = ProgressIndicator::new(&state);
 
     // When we know what we need to download: for each file, start a download thread
     let (download_listeners, listener) = crate::channels::split_by_files(listener, state.clone());
     let mut join_handles = vec![];
-    let request_interval_us = args.request_interval_us;
-    for (file, listener) in state.files.iter().zip(download_listeners) {
+    let options = download::DownloadOptions {
+        request_interval_us: args.request_interval_us,
+        max_attempts: args.max_attempts,
+        state_dir: state_dir.clone(),
+        delta: !args.no_delta,
+    };
+    for ((file, listener), existing) in state.files.iter().zip(download_listeners).zip(existing) {
         let comm = server_comm.clone();
         let (file, chunks) = file.clone();
         let progress_sender = indicator.event_tx();
+        let options = options.clone();
         let handle = tokio::spawn(async move {
-            // Allocate the file
-            common::filesystem::allocate(&PathBuf::from(&file.path), file.size)
-                .await
-                .expect("Failed to allocate file");
-            download::download_file(
-                listener,
-                comm,
-                file,
-                chunks,
-                progress_sender,
-                request_interval_us,
-            )
-            .await;
+            let Ok(path) = file.local_path() else {
+                progress_sender
+                    .send(ProgressEvent::FileFailed(file.idx.into()))
+                    .await
+                    .expect("Failed to send progress event");
+                common::channels::drain(listener);
+                return false;
+            };
+            if existing {
+                // A stopped run may have moved an old copy of it aside, which is not needed any more
+                if let Some(old_copy) = delta::left_over(&path).await {
+                    delta::forget(&old_copy).await;
+                }
+                progress_sender
+                    .send(ProgressEvent::FileDone(file.idx.into()))
+                    .await
+                    .expect("Failed to send progress event");
+                common::channels::drain(listener);
+                return true;
+            }
+            download::download_file(listener, comm, file, chunks, progress_sender, options).await
         });
         join_handles.push(handle);
     }
@@ -144,13 +281,28 @@ async fn main() {
         .await
         .expect("Failed to download all files");
 
-    // Wait for all downloads to finish
-    for handle in join_handles {
-        handle.await.unwrap();
+    // Wait for all downloads to finish, and find the files that didn't match their hash
+    let mut failed = vec![];
+    for ((file, _), handle) in state.files.iter().zip(join_handles) {
+        if !handle.await.unwrap() {
+            failed.push(common::paths::display(&file.path).into_owned());
+        }
     }
 
     println!("All downloads finished!");
+    // Every file is done with its state, so this only removes the directory if we made it
+    if let Some(state_dir) = &state_dir {
+        std::fs::remove_dir(state_dir).ok();
+    }
     server_comm
         .send_message(&Message::Disconnect(DisconnectReason::Done))
         .await;
+
+    if !failed.is_empty() {
+        eprintln!("{} files could not be downloaded correctly:", failed.len());
+        for path in failed {
+            eprintln!("  {path}");
+        }
+        std::process::exit(1);
+    }
 }
@@ -1,4 +1,8 @@
-use common::{messages::Message, MessageReceiver};
+use common::{
+    messages::{unknown_fields, unknown_messages, Message},
+    sequence::SequenceTrackers,
+    MessageReceiver, Peer,
+};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -7,6 +11,11 @@ use crate::comms::ServerCommunicator;
 
 /// Periodically send out pings to the server, and listen for pongs.
 /// If the number of missed pongs exceeds the threshold, the program will exit.
+///
+/// If the server's packets have sequence numbers, every ping reports the ones that we lost since the last,
+/// and we log how many of its packets were lost, duplicated and reordered.
+/// Otherwise, it has the count of all the packets that we received.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn pong_listener(
     mut pong_listener: MessageReceiver,
     mut ping_interval: tokio::time::Interval,
@@ -14,8 +23,11 @@ pub(crate) async fn pong_listener(
     comm: ServerCommunicator,
     recv_packets_counter: tokio::sync::watch::Receiver<u64>,
     recv_packets_count_reset: tokio::sync::watch::Sender<()>,
+    sequences: SequenceTrackers,
+    server_name: Peer,
 ) {
     let mut missed_pings = 0;
+    let mut skipped = (0, 0);
     loop {
         tokio::select! {
             _ = ping_interval.tick() => {
@@ -31,10 +43,21 @@ pub(crate) async fn pong_listener(
                 }
 
                 let nonce = rand::random();
-                let recv_packets = *recv_packets_counter.borrow();
-                let message = Message::Ping{nonce, recvs: recv_packets};
+                let message = match sequences.report(&server_name) {
+                    Some((report, stats)) => {
+                        debug!("Packets from {server_name}: {stats:?}");
+                        Message::Ping{nonce, recvs: report.received, lost: Some(report.gaps)}
+                    }
+                    None => Message::Ping{nonce, recvs: *recv_packets_counter.borrow(), lost: None},
+                };
                 comm.send_message(&message).await;
-                debug!("Sent ping with nonce {nonce} and recvs {recv_packets}");
+                debug!("Sent ping {message:?}");
+
+                // A server newer than us may send messages and fields that we don't know
+                if (unknown_messages(), unknown_fields()) != skipped {
+                    skipped = (unknown_messages(), unknown_fields());
+                    debug!("Skipped {} unknown messages and {} unknown fields so far", skipped.0, skipped.1);
+                }
 
                 // When we send a ping, reset the packet counter.
                 recv_packets_count_reset.send(()).unwrap();
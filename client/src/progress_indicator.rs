@@ -27,6 +27,16 @@ pub enum ProgressEvent {
     ChunkRequested(u64, u64),
     /// Mark this file as done
     FileDone(u64),
+    /// Mark every chunk of this file as not downloaded, as it is downloaded again
+    FileReset(u64),
+    /// Mark this chunk as not downloaded, as it is downloaded again
+    /// (file_idx, chunk_idx)
+    ChunkReset(u64, u64),
+    /// Mark this file as failed: it didn't match its hash, or can't be stored here, and won't be downloaded again
+    FileFailed(u64),
+    /// Mark these chunks as there, without counting them as downloaded: they were copied from an old copy of the file
+    /// (file_idx, chunk_idxs)
+    ChunksCopied(u64, Vec<u64>),
 }
 
 const MAX_FILE_NAME_LEN: usize = 20;
@@ -53,12 +63,16 @@ impl ProgressIndicator {
     /// Create a new progress indicator
     pub fn new(server_data: &ServerData) -> Self {
         let file_lengths = server_data.files.iter().map(|f| f.1.num_chunks).collect();
-        let file_names = server_data.files.iter().map(|f| f.0.path.clone()).collect();
+        let file_names = server_data
+            .files
+            .iter()
+            .map(|f| common::paths::display(&f.0.path).into_owned())
+            .collect();
         let file_states = server_data
             .files
             .iter()
             .map(|f| {
-                vec![
+                let mut states = vec![
                     0;
                     f.1.num_chunks as usize / SUPERBLOCK_STEPS.len()
                         + (if f.1.num_chunks as usize % SUPERBLOCK_STEPS.len() == 0 {
@@ -66,7 +80,12 @@ impl ProgressIndicator {
                         } else {
                             1
                         })
-                ]
+                ];
+                // Chunks of a resumed download are already there
+                for chunk_idx in (0..f.1.num_chunks).filter(|&chunk_idx| f.1.get(chunk_idx)) {
+                    states[chunk_idx as usize / SUPERBLOCK_STEPS.len()] += 1;
+                }
+                states
             })
             .collect();
 
@@ -125,12 +144,16 @@ impl ProgressIndicator {
                         self.stream.flush().unwrap();
                     }
                 }
-                ProgressEvent::FileDone(file_idx) => {
+                ProgressEvent::FileDone(file_idx) | ProgressEvent::FileFailed(file_idx) => {
                     if do_print {
+                        let status = match event {
+                            ProgressEvent::FileDone(_) => " Done".green(),
+                            _ => " Failed".red(),
+                        };
                         self.stream.queue(MoveToNextLine(file_idx as u16 + 1))?;
                         self.stream
                             .queue(Print(self.file_names[file_idx as usize].clone()))?;
-                        self.stream.queue(PrintStyledContent(" Done".green()))?;
+                        self.stream.queue(PrintStyledContent(status))?;
                         self.stream.queue(Clear(ClearType::UntilNewLine))?;
                         self.stream.queue(MoveToPreviousLine(file_idx as u16 + 1))?;
                         self.print_first_row()?;
@@ -142,6 +165,38 @@ impl ProgressIndicator {
                         break Ok(());
                     }
                 }
+                ProgressEvent::FileReset(file_idx) => {
+                    self.file_states[file_idx as usize].fill(0);
+                    if do_print {
+                        self.on_file_line(file_idx as usize, |this| {
+                            this.print_file_progress(file_idx as usize)?;
+                            Ok(())
+                        })?;
+                    }
+                }
+                ProgressEvent::ChunksCopied(file_idx, chunk_idxs) => {
+                    for chunk_idx in chunk_idxs {
+                        let superblock_idx = chunk_idx as usize / (SUPERBLOCK_STEPS.len());
+                        self.file_states[file_idx as usize][superblock_idx] += 1;
+                    }
+                    if do_print {
+                        self.on_file_line(file_idx as usize, |this| {
+                            this.print_file_progress(file_idx as usize)?;
+                            Ok(())
+                        })?;
+                    }
+                }
+                ProgressEvent::ChunkReset(file_idx, chunk_idx) => {
+                    let superblock_idx = chunk_idx as usize / (SUPERBLOCK_STEPS.len());
+                    let superblock = &mut self.file_states[file_idx as usize][superblock_idx];
+                    *superblock = superblock.saturating_sub(1);
+                    if do_print {
+                        self.on_file_line(file_idx as usize, |this| {
+                            this.print_file_progress(file_idx as usize)?;
+                            Ok(())
+                        })?;
+                    }
+                }
             }
         }
     }
@@ -1,53 +1,129 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use common::{messages::JoinReason, networking::send_message};
+use common::{
+    capabilities::Capabilities,
+    magic::{VersionRange, MIN_VERSION},
+    messages::JoinReason,
+    networking::{auth_failures, send_message, with_port},
+    Identity, Peer,
+};
+use tokio::net::UdpSocket;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+/// How long to wait for a server before giving up,
+/// if in the meantime we have only received packets that failed authentication.
+const AUTH_FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Discover a server on the local network.
 /// When found, ask the server to join.
-/// If the server accepts, return the address of the server.
+/// If the server accepts, return the address and the name of the server,
+/// the protocol version to talk to it in, which is the highest version that both of us support,
+/// and the capabilities of the server.
+///
+/// Gives up and returns `None` if no server answers, but packets keep failing authentication:
+/// this means the servers are using a different pre-shared key than we are.
 pub async fn discover_server(
     channel: &mut common::MessageReceiver,
-    my_name: &str,
+    socket: &UdpSocket,
+    identity: &Identity,
     server_name: Option<&str>,
-) -> Option<SocketAddr> {
+) -> Option<(SocketAddr, Peer, u16, Capabilities)> {
     info!("Discovering server {:?}", server_name);
     // Initially, we're not expecting a join ack message
-    let mut expecting_join_ok_from = None;
+    let mut expecting_join_ok_from: Option<SocketAddr> = None;
+    let mut version = MIN_VERSION;
+
+    let started = Instant::now();
+    let initial_auth_failures = auth_failures();
 
     loop {
-        let (their_addr, their_name, message) = channel.recv().await?;
-        let their_addr = their_addr.ip();
-        if server_name.is_none() || their_name == server_name.unwrap() {
+        let (their_addr, their_name, message) =
+            match tokio::time::timeout(Duration::from_secs(1), channel.recv()).await {
+                Ok(received) => received?,
+                Err(_) => {
+                    if started.elapsed() > AUTH_FAILURE_TIMEOUT
+                        && auth_failures() > initial_auth_failures
+                    {
+                        error!(
+                            "Could not join a server: {} packets failed authentication. Are you using the same pre-shared key as the server?",
+                            auth_failures() - initial_auth_failures
+                        );
+                        return None;
+                    }
+                    continue;
+                }
+            };
+        if server_name.is_none() || &*their_name.name == server_name.unwrap() {
             debug!(
                 "Received packet from {} ({}): {:?}",
                 their_addr, their_name, message
             );
             match message {
-                common::messages::Message::Announce { port } => {
+                common::messages::Message::Announce { port, versions } => {
                     debug!("It is a server announcement");
+                    let common_version = match VersionRange::SUPPORTED.highest_common(&versions) {
+                        Some(common_version) => common_version,
+                        None => {
+                            warn!(
+                                "Server {} supports protocol versions {:?}, but we only support {:?}",
+                                their_name,
+                                versions,
+                                VersionRange::SUPPORTED
+                            );
+                            continue;
+                        }
+                    };
                     // If we're not waiting on a join ack, send a join request
                     if expecting_join_ok_from.is_none() {
-                        let message = common::messages::Message::JoinQuery {};
-                        let their_addr = SocketAddr::new(their_addr, port);
+                        let message = common::messages::Message::JoinQuery {
+                            versions: VersionRange::SUPPORTED,
+                            capabilities: Some(Capabilities::SUPPORTED),
+                        };
+                        // Keep the zone of a link-local address, so that we can reach it
+                        let their_addr = with_port(their_addr, port);
 
-                        send_message(their_addr, my_name, &message).await.ok()?;
-                        debug!("Sent join request to {}", their_addr);
+                        send_message(socket, their_addr, identity, common_version, &message).await.ok()?;
+                        debug!("Sent join request to {} in version {}", their_addr, common_version);
                         expecting_join_ok_from = Some(their_addr);
+                        version = common_version;
                     }
                 }
-                common::messages::Message::JoinResponse(reason) => {
+                common::messages::Message::JoinResponse {
+                    reason,
+                    versions,
+                    capabilities,
+                } => {
                     if expecting_join_ok_from.is_none() {
                         continue;
                     }
                     let expecting_ip_addr = expecting_join_ok_from.unwrap().ip();
-                    if their_addr == expecting_ip_addr {
+                    if their_addr.ip() == expecting_ip_addr {
                         debug!("It is a join response");
                         if reason == JoinReason::Accepted {
-                            debug!("Server accepted our join request!");
-                            return Some(expecting_join_ok_from.unwrap());
+                            let capabilities =
+                                capabilities.unwrap_or_else(|| Capabilities::implied_by(version));
+                            debug!(
+                                "Server accepted our join request! It has capabilities {}",
+                                capabilities
+                            );
+                            // The server picks the version the same way we do, so this should agree
+                            if VersionRange::SUPPORTED.highest_common(&versions) != Some(version) {
+                                warn!(
+                                    "Server supports protocol versions {:?}, but we are using version {}",
+                                    versions, version
+                                );
+                            }
+                            return Some((
+                                expecting_join_ok_from.unwrap(),
+                                their_name,
+                                version,
+                                capabilities,
+                            ));
                         } else {
                             error!(
                                 "Server rejected our join request with this reason: {:?}",
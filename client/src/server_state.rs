@@ -41,7 +41,6 @@ impl ChunkState {
         }
     }
 
-    #[allow(dead_code)]
     pub fn get(&self, idx: u64) -> bool {
         let u64_idx = idx / 64;
         let bit_idx = idx % 64;
@@ -85,6 +84,36 @@ impl ChunkState {
     pub fn is_complete(&self) -> bool {
         self.get_zero().is_none()
     }
+
+    /// Mark every chunk as not downloaded, to download the whole file again.
+    pub fn clear(&mut self) {
+        self.bitmap.fill(0);
+    }
+
+    /// Mark every chunk as downloaded, for a file that we already have.
+    pub fn fill(&mut self) {
+        self.bitmap.fill(!0);
+    }
+
+    /// Get the bitmap, to save it.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    /// Restore a saved bitmap.
+    /// Returns false, leaving the state unchanged, if the bitmap is for another number of chunks.
+    pub fn restore(&mut self, bitmap: Vec<u64>) -> bool {
+        if bitmap.len() != self.bitmap.len() {
+            return false;
+        }
+        self.bitmap = bitmap;
+        true
+    }
+
+    /// Count the chunks that are downloaded.
+    pub fn count(&self) -> u64 {
+        (0..self.num_chunks).filter(|&idx| self.get(idx)).count() as u64
+    }
 }
 
 #[cfg(test)]
@@ -98,6 +127,16 @@ mod tests {
         assert!(!state.get(1));
         state.set(1, true);
         assert!(state.get(1));
+        assert_eq!(state.count(), 2);
+
+        let mut restored = ChunkState::from_file_size(100, 10);
+        assert!(restored.restore(state.bitmap().to_vec()));
+        assert!(restored.get(1));
+        assert!(!restored.restore(vec![0; 2]));
+
+        state.clear();
+        assert!(!state.get(0));
+        assert_eq!(state.get_zero(), Some(0));
     }
 
     #[test]
@@ -1,16 +1,30 @@
-use common::{messages, MessageReceiver};
+use common::{listing::ListingAssembler, messages, MessageReceiver};
 
 use crate::comms::ServerCommunicator;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+/// The file listing entry in a message, if it has one.
+/// Entries that are too large for one datagram come in parts, and the last of them has the entry.
+fn listing_entry(
+    message: messages::Message,
+    parts: &mut ListingAssembler,
+) -> Option<messages::FileListingFragment> {
+    match message {
+        messages::Message::FileListing(listing_item) => Some(listing_item),
+        messages::Message::FileListingPart(part) => parts.add(part),
+        _ => None,
+    }
+}
+
 /// Function to talk to the server to initialize the state.
 pub async fn initialize_state(
     listener: &mut MessageReceiver,
     comm: ServerCommunicator,
 ) -> crate::server_state::ServerData {
     let mut timeout = tokio::time::interval(std::time::Duration::from_millis(500));
+    let mut parts = ListingAssembler::new();
     // First, we need to figure out how many files there are
     // If we see a FileListing, we can figure it out, otherwise we need to request it
     let num_files;
@@ -27,7 +41,7 @@ pub async fn initialize_state(
                 comm.send_message(&messages::Message::FileListingRequest{idx: 0}).await;
             }
             Some((_, _, message)) = listener.recv() => {
-                if let messages::Message::FileListing(listing_item) = message {
+                if let Some(listing_item) = listing_entry(message, &mut parts) {
                     num_files = listing_item.total;
                     debug!("Got file listing, there are {} files", num_files);
                     break;
@@ -56,7 +70,7 @@ pub async fn initialize_state(
                 }
             }
             Some((_, _, message)) = listener.recv() => {
-                if let messages::Message::FileListing(listing_item) = message {
+                if let Some(listing_item) = listing_entry(message, &mut parts) {
                     let idx = listing_item.idx as usize;
                     file_listings[idx] = Some(listing_item);
                     debug!("Got file listing for file {}", idx);
@@ -0,0 +1,422 @@
+/// Authentication and encryption of packets with a pre-shared key.
+///
+/// When a pre-shared key is configured, every packet carries a timestamp,
+/// and an HMAC-SHA256 of the whole packet in place of the plain SHA-256 hash
+/// (truncated to 16 bytes in the compact header of version 4).
+/// Receivers drop packets whose MAC does not match,
+/// and packets whose timestamp is too old or has been seen before.
+///
+/// Packets can also be encrypted with ChaCha20-Poly1305, using a key derived from the pre-shared key.
+/// Then the message is unreadable without the key, and the AEAD tag takes the place of the MAC.
+///
+/// The timestamps are wall-clock microseconds, so the clocks of the peers must roughly agree.
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadInPlace, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Digest;
+
+use crate::HashType;
+
+/// How far a packet's timestamp may be from our clock, in microseconds.
+const MAX_CLOCK_SKEW_US: u64 = 30_000_000;
+
+/// How far back from the newest timestamp of a peer we remember which timestamps we have seen,
+/// in microseconds.
+/// Packets older than this are dropped, even if we have not seen them.
+const REPLAY_WINDOW_US: u64 = 1 << 16;
+
+/// Why a packet failed authentication.
+#[derive(Debug)]
+pub enum AuthError {
+    /// The packet has no MAC, but we require one.
+    Missing,
+    /// The packet has a MAC, but we don't have a key to check it.
+    NoKey,
+    /// The MAC or AEAD tag does not match: the packet was forged, corrupted, or made with a different key.
+    BadMac,
+    /// The timestamp is too far from our clock.
+    /// The value is the timestamp of the packet.
+    Stale(u64),
+    /// We have already seen a packet from this peer with this timestamp.
+    Replayed(u64),
+}
+
+/// The length of the AEAD tag that is appended to encrypted messages.
+pub const TAG_LENGTH: usize = 16;
+
+/// The length of the truncated MAC in compact packets.
+/// Half of an HMAC-SHA256 is still far more than anyone can forge by guessing.
+pub const SHORT_MAC_LENGTH: usize = 16;
+
+/// The length of the salt in encrypted packets.
+pub const SALT_LENGTH: usize = 4;
+
+/// A pre-shared key.
+#[derive(Clone)]
+pub struct Psk {
+    key: HashType,
+    /// The key used for encryption, derived from `key`.
+    cipher: ChaCha20Poly1305,
+    /// Random bytes that go into the nonce of every packet we encrypt.
+    /// Together with the timestamp, this keeps nonces unique between peers with the same key.
+    salt: [u8; SALT_LENGTH],
+}
+
+impl std::fmt::Debug for Psk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the key
+        f.write_str("Psk(..)")
+    }
+}
+
+impl Psk {
+    /// Derive a key from a secret, such as a passphrase or the contents of a key file.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(secret);
+        let key: HashType = hasher.finalize().into();
+
+        // Use a different key for encryption than for MACs
+        let mut encryption_key = <Hmac<sha2::Sha256> as Mac>::new_from_slice(&key)
+            .expect("HMAC accepts keys of any length");
+        encryption_key.update(b"rust-udp-sender encryption");
+        let encryption_key = encryption_key.finalize().into_bytes();
+
+        let mut salt = [0; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            key,
+            cipher: ChaCha20Poly1305::new(&encryption_key),
+            salt,
+        }
+    }
+
+    /// Derive a key from the contents of a key file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        Ok(Self::from_secret(&std::fs::read(path)?))
+    }
+
+    /// Make a key from the command-line options: either a passphrase, or a key file, or neither.
+    pub fn from_args(
+        passphrase: Option<&str>,
+        file: Option<&str>,
+    ) -> Result<Option<Self>, std::io::Error> {
+        match (passphrase, file) {
+            (Some(passphrase), _) => Ok(Some(Self::from_secret(passphrase.as_bytes()))),
+            (None, Some(file)) => Ok(Some(Self::from_file(file)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Compute the MAC of a packet, given as a list of its parts.
+    pub fn mac(&self, parts: &[&[u8]]) -> HashType {
+        let mut mac = self.hmac();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Check the MAC of a packet, given as a list of its parts.
+    pub fn verify(&self, parts: &[&[u8]], expected: &[u8]) -> Result<(), AuthError> {
+        let mut mac = self.hmac();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.verify_slice(expected).map_err(|_| AuthError::BadMac)
+    }
+
+    /// Check a MAC that was truncated to its first `SHORT_MAC_LENGTH` bytes, like `verify`.
+    pub fn verify_short(&self, parts: &[&[u8]], expected: &[u8]) -> Result<(), AuthError> {
+        if expected.len() != SHORT_MAC_LENGTH {
+            return Err(AuthError::BadMac);
+        }
+        let mut mac = self.hmac();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.verify_truncated_left(expected)
+            .map_err(|_| AuthError::BadMac)
+    }
+
+    /// The salt to put into the packets we encrypt.
+    pub fn salt(&self) -> [u8; SALT_LENGTH] {
+        self.salt
+    }
+
+    /// Encrypt a message.
+    /// The nonce is made from the salt and the timestamp of the packet,
+    /// and `header` is authenticated but not encrypted.
+    /// Returns the encrypted message, followed by the AEAD tag.
+    pub fn encrypt(
+        &self,
+        salt: [u8; SALT_LENGTH],
+        timestamp: u64,
+        header: &[u8],
+        message: &[u8],
+    ) -> Vec<u8> {
+        self.cipher
+            .encrypt(
+                &Self::nonce(salt, timestamp),
+                Payload {
+                    msg: message,
+                    aad: header,
+                },
+            )
+            .expect("Failed to encrypt message")
+    }
+
+    /// Encrypt a message in place, like `encrypt`.
+    /// Returns the AEAD tag, which must be sent after the encrypted message.
+    pub fn encrypt_in_place(
+        &self,
+        salt: [u8; SALT_LENGTH],
+        timestamp: u64,
+        header: &[u8],
+        message: &mut [u8],
+    ) -> [u8; TAG_LENGTH] {
+        self.cipher
+            .encrypt_in_place_detached(&Self::nonce(salt, timestamp), header, message)
+            .expect("Failed to encrypt message")
+            .into()
+    }
+
+    /// Decrypt a message that was encrypted with `encrypt`, checking the AEAD tag.
+    pub fn decrypt(
+        &self,
+        salt: [u8; SALT_LENGTH],
+        timestamp: u64,
+        header: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, AuthError> {
+        self.cipher
+            .decrypt(
+                &Self::nonce(salt, timestamp),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| AuthError::BadMac)
+    }
+
+    fn nonce(salt: [u8; SALT_LENGTH], timestamp: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[..SALT_LENGTH].copy_from_slice(&salt);
+        nonce[SALT_LENGTH..].copy_from_slice(&timestamp.to_be_bytes());
+        nonce
+    }
+
+    fn hmac(&self) -> Hmac<sha2::Sha256> {
+        <Hmac<sha2::Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before 1970")
+        .as_micros() as u64
+}
+
+/// Get a timestamp for a packet that we are about to send.
+///
+/// The timestamps are strictly increasing within this process,
+/// so no two packets that we send have the same one.
+pub fn next_timestamp() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = now_us();
+    let mut last = LAST.load(Ordering::Relaxed);
+    loop {
+        let next = now.max(last + 1);
+        match LAST.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(actual) => last = actual,
+        }
+    }
+}
+
+/// Remembers the timestamps of recent packets from every peer, to drop replayed packets.
+#[derive(Debug, Default)]
+pub struct ReplayFilter {
+    peers: HashMap<u32, ReplayWindow>,
+}
+
+impl ReplayFilter {
+    /// Forget the peers that can't send any fresh packets now once there are this many,
+    /// and all of them if that is not enough, so that peers that come and go don't use up all memory.
+    /// Every restarted peer has a new id, and a window takes 8 KiB.
+    const MAX_PEERS: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check that a packet from the given peer is fresh and was not seen before,
+    /// and remember it.
+    /// The peer is identified by the sender id in the header of its packets.
+    pub fn check(&mut self, peer: u32, timestamp: u64) -> Result<(), AuthError> {
+        if now_us().abs_diff(timestamp) > MAX_CLOCK_SKEW_US {
+            return Err(AuthError::Stale(timestamp));
+        }
+        match self.peers.get_mut(&peer) {
+            Some(window) => window.check(timestamp),
+            None => {
+                if self.peers.len() >= Self::MAX_PEERS {
+                    // Any packet older than the clock skew is stale, so a peer that sent nothing newer is done
+                    let oldest = now_us().saturating_sub(MAX_CLOCK_SKEW_US);
+                    self.peers.retain(|_, window| window.newest >= oldest);
+                    if self.peers.len() >= Self::MAX_PEERS {
+                        self.peers.clear();
+                    }
+                }
+                self.peers
+                    .insert(peer, ReplayWindow::new(timestamp));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The timestamps seen from a single peer in the last `REPLAY_WINDOW_US` microseconds.
+#[derive(Debug)]
+struct ReplayWindow {
+    /// The newest timestamp seen.
+    newest: u64,
+    /// Bit `t % REPLAY_WINDOW_US` is set if timestamp `t` was seen,
+    /// for every `t` in the window ending at `newest`.
+    seen: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new(timestamp: u64) -> Self {
+        let mut window = Self {
+            newest: timestamp,
+            seen: vec![0; (REPLAY_WINDOW_US / 64) as usize],
+        };
+        window.set(timestamp);
+        window
+    }
+
+    fn check(&mut self, timestamp: u64) -> Result<(), AuthError> {
+        if timestamp > self.newest {
+            // Forget the timestamps that are now out of the window
+            if timestamp - self.newest >= REPLAY_WINDOW_US {
+                self.seen.fill(0);
+            } else {
+                for t in self.newest + 1..timestamp {
+                    self.clear(t);
+                }
+            }
+            self.newest = timestamp;
+            self.set(timestamp);
+            return Ok(());
+        }
+        if self.newest - timestamp >= REPLAY_WINDOW_US || self.is_set(timestamp) {
+            return Err(AuthError::Replayed(timestamp));
+        }
+        self.set(timestamp);
+        Ok(())
+    }
+
+    fn position(timestamp: u64) -> (usize, u64) {
+        let bit = timestamp % REPLAY_WINDOW_US;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn is_set(&self, timestamp: u64) -> bool {
+        let (word, mask) = Self::position(timestamp);
+        self.seen[word] & mask != 0
+    }
+
+    fn set(&mut self, timestamp: u64) {
+        let (word, mask) = Self::position(timestamp);
+        self.seen[word] |= mask;
+    }
+
+    fn clear(&mut self, timestamp: u64) {
+        let (word, mask) = Self::position(timestamp);
+        self.seen[word] &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replayed_packets_are_dropped() {
+        let mut filter = ReplayFilter::new();
+        let t = next_timestamp();
+        assert!(filter.check(1, t).is_ok());
+        assert!(matches!(filter.check(1, t), Err(AuthError::Replayed(_))));
+        // Reordered packets are fine, as long as they are new
+        assert!(filter.check(1, t + 10).is_ok());
+        assert!(filter.check(1, t + 5).is_ok());
+        assert!(matches!(filter.check(1, t + 5), Err(AuthError::Replayed(_))));
+        // Other peers have their own timestamps
+        assert!(filter.check(2, t).is_ok());
+        // Packets from too long ago are dropped
+        assert!(matches!(
+            filter.check(1, t - 2 * MAX_CLOCK_SKEW_US),
+            Err(AuthError::Stale(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay_filter_forgets_old_peers() {
+        let mut filter = ReplayFilter::new();
+        let t = next_timestamp();
+        // Peers that have not sent anything for longer than the clock skew are forgotten first
+        filter.peers.insert(0, ReplayWindow::new(t - 2 * MAX_CLOCK_SKEW_US));
+        for peer in 1..ReplayFilter::MAX_PEERS as u32 {
+            assert!(filter.check(peer, t).is_ok());
+        }
+        assert!(filter.check(ReplayFilter::MAX_PEERS as u32, t).is_ok());
+        assert_eq!(filter.peers.len(), ReplayFilter::MAX_PEERS);
+        assert!(!filter.peers.contains_key(&0));
+        assert!(matches!(filter.check(1, t), Err(AuthError::Replayed(_))));
+    }
+
+    #[test]
+    fn test_mac_depends_on_key() {
+        let key = Psk::from_secret(b"hunter2");
+        let mac = key.mac(&[b"some ", b"packet"]);
+        assert!(key.verify(&[b"some packet"], &mac).is_ok());
+        assert!(key.verify(&[b"some other packet"], &mac).is_err());
+        assert!(key.verify_short(&[b"some packet"], &mac[..SHORT_MAC_LENGTH]).is_ok());
+        assert!(key.verify_short(&[b"some packet"], &mac[..4]).is_err());
+        assert!(Psk::from_secret(b"hunter3")
+            .verify(&[b"some packet"], &mac)
+            .is_err());
+    }
+
+    #[test]
+    fn test_encryption() {
+        let key = Psk::from_secret(b"hunter2");
+        let ciphertext = key.encrypt(key.salt(), 1, b"header", b"secret message");
+        assert_eq!(ciphertext.len(), b"secret message".len() + TAG_LENGTH);
+        assert!(!ciphertext.windows(6).any(|w| w == b"secret"));
+
+        // Another process with the same key can decrypt it
+        let other = Psk::from_secret(b"hunter2");
+        let plaintext = other.decrypt(key.salt(), 1, b"header", &ciphertext).unwrap();
+        assert_eq!(plaintext, b"secret message");
+
+        // But not if anything was changed, or the key is wrong
+        assert!(other.decrypt(key.salt(), 2, b"header", &ciphertext).is_err());
+        assert!(other.decrypt(key.salt(), 1, b"Header", &ciphertext).is_err());
+        assert!(Psk::from_secret(b"hunter3")
+            .decrypt(key.salt(), 1, b"header", &ciphertext)
+            .is_err());
+    }
+}
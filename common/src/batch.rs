@@ -0,0 +1,676 @@
+/// Batched sending and receiving of datagrams.
+///
+/// On Linux, a batch of datagrams is sent with a single `sendmmsg` call,
+/// and received with a single `recvmmsg` call.
+/// Consecutive datagrams of the same size to the same address are also merged into one message
+/// with UDP generic segmentation offload (GSO), which the kernel or the network card splits up again.
+/// With generic receive offload (GRO), the kernel can hand us several datagrams in one buffer.
+///
+/// Support for each of these is detected at runtime: if the kernel refuses one of them,
+/// we stop using it and fall back to the simpler method, down to one system call per datagram.
+/// On other systems, the fallback is always used.
+use std::net::SocketAddr;
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::UdpSocket;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The most datagrams that are sent or received in one batch.
+pub const MAX_BATCH: usize = 32;
+
+/// How many datagrams of the largest size each receive buffer has room for when it is allocated.
+/// Smaller datagrams are split off the same allocation, until it has no room for one of the largest size.
+const BUFFER_DATAGRAMS: usize = 4;
+
+/// Sends batches of datagrams from a socket.
+#[derive(Debug)]
+pub struct BatchSender {
+    /// Whether to use `sendmmsg`.
+    mmsg: bool,
+    /// Whether to merge runs of equal-sized datagrams with UDP GSO.
+    gso: bool,
+    #[cfg(target_os = "linux")]
+    scratch: linux::Scratch,
+}
+
+impl BatchSender {
+    /// Make a sender for the given socket, checking what the kernel supports.
+    pub fn new(socket: &UdpSocket) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            let gso = linux::supports_gso(socket);
+            debug!("Sending batches with sendmmsg, GSO: {gso}");
+            Self {
+                mmsg: true,
+                gso,
+                scratch: linux::Scratch::new(),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = socket;
+            Self {
+                mmsg: false,
+                gso: false,
+            }
+        }
+    }
+
+    /// Send every packet to every address.
+    pub async fn send(
+        &mut self,
+        socket: &UdpSocket,
+        packets: &[Vec<u8>],
+        addrs: &[SocketAddr],
+    ) -> Result<(), std::io::Error> {
+        #[cfg(target_os = "linux")]
+        if self.mmsg {
+            match linux::send(socket, packets, addrs, &mut self.gso, &mut self.scratch).await {
+                Err(e) if linux::is_unsupported(&e) => {
+                    warn!("sendmmsg is not supported ({e}), sending one datagram at a time");
+                    self.mmsg = false;
+                }
+                result => return result,
+            }
+        }
+        for packet in packets {
+            for addr in addrs {
+                socket.send_to(packet, addr).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A datagram that was received into one of the buffers of a `BatchReceiver`.
+#[derive(Debug, Clone)]
+struct Received {
+    /// The bytes received, split off the buffer.
+    data: Bytes,
+    /// Where the datagram came from.
+    addr: SocketAddr,
+    /// If the kernel merged several datagrams with GRO, the size of each of them (except the last).
+    segment_size: Option<usize>,
+}
+
+/// Receives batches of datagrams on a socket.
+#[derive(Debug)]
+pub struct BatchReceiver {
+    /// The datagrams are split off these buffers, so that they can be kept without copying them.
+    /// A buffer gets its memory back once all the datagrams split off it are dropped.
+    buffers: Vec<BytesMut>,
+    /// The most bytes that a buffer must have room for before receiving.
+    buffer_size: usize,
+    received: Vec<Received>,
+    /// Whether to use `recvmmsg`.
+    mmsg: bool,
+    /// Whether the kernel may merge datagrams with GRO.
+    gro: bool,
+    #[cfg(target_os = "linux")]
+    scratch: linux::Scratch,
+}
+
+impl BatchReceiver {
+    /// Make a receiver for the given socket, for datagrams of up to `datagram_size` bytes.
+    /// Longer datagrams are truncated.
+    ///
+    /// This turns on GRO for the socket if the kernel supports it.
+    pub fn new(socket: &UdpSocket, datagram_size: usize) -> Self {
+        #[cfg(target_os = "linux")]
+        let (mmsg, gro) = (true, linux::enable_gro(socket));
+        #[cfg(not(target_os = "linux"))]
+        let (mmsg, gro) = {
+            let _ = socket;
+            (false, false)
+        };
+        // Merged datagrams can be up to the largest UDP payload
+        let buffer_size = if gro { u16::MAX as usize } else { datagram_size };
+        let batch = if mmsg { MAX_BATCH } else { 1 };
+        Self {
+            buffers: vec![BytesMut::new(); batch],
+            buffer_size,
+            received: Vec::with_capacity(batch),
+            mmsg,
+            gro,
+            #[cfg(target_os = "linux")]
+            scratch: linux::Scratch::new(),
+        }
+    }
+
+    /// Wait for at least one datagram, and receive as many as are available, up to a batch.
+    /// Returns an iterator over the datagrams and their sources.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> Result<Datagrams<'_>, std::io::Error> {
+        self.received.clear();
+        for buffer in &mut self.buffers {
+            buffer.clear();
+            if buffer.capacity() < self.buffer_size {
+                buffer.reserve(self.buffer_size * BUFFER_DATAGRAMS);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if self.mmsg {
+            let result = linux::recv(
+                socket,
+                &mut self.buffers,
+                &mut self.received,
+                self.gro,
+                &mut self.scratch,
+            )
+            .await;
+            match result {
+                Err(e) if linux::is_unsupported(&e) => {
+                    warn!("recvmmsg is not supported ({e}), receiving one datagram at a time");
+                    self.mmsg = false;
+                }
+                Err(e) => return Err(e),
+                Ok(()) => return Ok(self.datagrams()),
+            }
+        }
+        let buffer = &mut self.buffers[0];
+        buffer.resize(self.buffer_size, 0);
+        let (len, addr) = socket.recv_from(buffer).await?;
+        buffer.truncate(len);
+        self.received.push(Received {
+            data: buffer.split().freeze(),
+            addr,
+            segment_size: None,
+        });
+        Ok(self.datagrams())
+    }
+
+    fn datagrams(&self) -> Datagrams<'_> {
+        Datagrams {
+            receiver: self,
+            message: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// The datagrams received by `BatchReceiver::recv`.
+pub struct Datagrams<'a> {
+    receiver: &'a BatchReceiver,
+    /// The index of the current message in `received`.
+    message: usize,
+    /// How far into the current message we are.
+    offset: usize,
+}
+
+impl Iterator for Datagrams<'_> {
+    type Item = (Bytes, SocketAddr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let received = self.receiver.received.get(self.message)?;
+            if self.offset >= received.data.len() {
+                self.message += 1;
+                self.offset = 0;
+                continue;
+            }
+            // Split merged datagrams back up
+            let len = match received.segment_size {
+                Some(segment_size) => segment_size.min(received.data.len() - self.offset),
+                None => received.data.len(),
+            };
+            let data = received.data.slice(self.offset..self.offset + len);
+            self.offset += len;
+            return Some((data, received.addr));
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io::ErrorKind,
+        mem::{size_of, zeroed},
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::unix::io::AsRawFd,
+    };
+
+    use bytes::BytesMut;
+    use tokio::{io::Interest, net::UdpSocket};
+
+    use super::{Received, MAX_BATCH};
+
+    #[allow(unused_imports)]
+    use log::{debug, error, info, trace, warn};
+
+    /// The most datagrams that the kernel accepts in one GSO message.
+    const MAX_GSO_SEGMENTS: usize = 64;
+
+    /// The most bytes in one GSO message: it must fit in a single IP packet before it is split.
+    const MAX_GSO_BYTES: usize = 65000;
+
+    /// Whether an error means that a system call or option is not available.
+    pub fn is_unsupported(e: &std::io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::ENOPROTOOPT)
+        )
+    }
+
+    /// Whether an error from a GSO message means that GSO does not work on this route.
+    fn is_gso_error(e: &std::io::Error) -> bool {
+        is_unsupported(e) || matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL))
+    }
+
+    /// Check if the kernel supports UDP GSO.
+    pub fn supports_gso(socket: &UdpSocket) -> bool {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        result == 0
+    }
+
+    /// Turn on UDP GRO for the socket, returning whether the kernel supports it.
+    pub fn enable_gro(socket: &UdpSocket) -> bool {
+        let value: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &value as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        result == 0
+    }
+
+    fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    /// The space for a control message with a payload of type `T`.
+    fn cmsg_space<T>() -> usize {
+        unsafe { libc::CMSG_SPACE(size_of::<T>() as u32) as usize }
+    }
+
+    /// Buffer for control messages, aligned like a `cmsghdr`.
+    #[derive(Clone, Copy)]
+    #[repr(C, align(8))]
+    struct CmsgBuffer([u8; 64]);
+
+    /// The headers for one `sendmmsg` or `recvmmsg` call, and the iovecs and control messages they point to.
+    #[derive(Default)]
+    struct Messages {
+        iovecs: Vec<libc::iovec>,
+        cmsgs: Vec<CmsgBuffer>,
+        headers: Vec<libc::mmsghdr>,
+    }
+
+    // The pointers in the iovecs and headers are only used during the call that fills them in.
+    unsafe impl Send for Messages {}
+    unsafe impl Sync for Messages {}
+
+    /// Space that is kept between calls, so that sending or receiving a batch does not allocate.
+    pub struct Scratch {
+        messages: Messages,
+        /// The addresses to send to, or the sources of the received datagrams.
+        addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)>,
+        runs: Vec<Run>,
+    }
+
+    impl Scratch {
+        pub fn new() -> Self {
+            Self {
+                messages: Messages {
+                    iovecs: Vec::with_capacity(MAX_BATCH),
+                    cmsgs: Vec::with_capacity(MAX_BATCH),
+                    headers: Vec::with_capacity(MAX_BATCH),
+                },
+                addrs: Vec::with_capacity(MAX_BATCH),
+                runs: Vec::with_capacity(MAX_BATCH),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for Scratch {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Scratch").finish_non_exhaustive()
+        }
+    }
+
+    /// A message to send: a run of datagrams to one address.
+    struct Run {
+        addr: usize,
+        first: usize,
+        count: usize,
+    }
+
+    /// Group the datagrams into messages.
+    /// With GSO, a message holds a run of datagrams of the same size, and maybe a shorter last one;
+    /// otherwise, every datagram is a message of its own.
+    fn make_runs(packets: &[Vec<u8>], addr_count: usize, gso: bool, runs: &mut Vec<Run>) {
+        runs.clear();
+        for addr in 0..addr_count {
+            let mut first = 0;
+            while first < packets.len() {
+                let size = packets[first].len();
+                let mut count = 1;
+                if gso {
+                    while first + count < packets.len()
+                        && count < MAX_GSO_SEGMENTS
+                        && (count + 1) * size <= MAX_GSO_BYTES
+                    {
+                        let next = packets[first + count].len();
+                        if next > size {
+                            break;
+                        }
+                        count += 1;
+                        if next < size {
+                            // Only the last segment can be shorter
+                            break;
+                        }
+                    }
+                }
+                runs.push(Run { addr, first, count });
+                first += count;
+            }
+        }
+    }
+
+    /// Send the runs with a single `sendmmsg` call, returning how many were sent.
+    fn send_runs(
+        socket: &UdpSocket,
+        runs: &[Run],
+        packets: &[Vec<u8>],
+        addrs: &[(libc::sockaddr_storage, libc::socklen_t)],
+        messages: &mut Messages,
+    ) -> std::io::Result<usize> {
+        let Messages {
+            iovecs,
+            cmsgs,
+            headers,
+        } = messages;
+        iovecs.clear();
+        for run in runs {
+            for packet in &packets[run.first..run.first + run.count] {
+                iovecs.push(libc::iovec {
+                    iov_base: packet.as_ptr() as *mut libc::c_void,
+                    iov_len: packet.len(),
+                });
+            }
+        }
+        cmsgs.clear();
+        cmsgs.resize(runs.len(), CmsgBuffer([0; 64]));
+        headers.clear();
+        let mut iovec_idx = 0;
+        for (run, cmsg_buffer) in runs.iter().zip(cmsgs.iter_mut()) {
+            let (addr, addr_len) = &addrs[run.addr];
+            let mut header: libc::msghdr = unsafe { zeroed() };
+            header.msg_name = addr as *const _ as *mut libc::c_void;
+            header.msg_namelen = *addr_len;
+            header.msg_iov = unsafe { iovecs.as_mut_ptr().add(iovec_idx) };
+            header.msg_iovlen = run.count as _;
+            iovec_idx += run.count;
+            if run.count > 1 {
+                // Tell the kernel to split the message into datagrams of this size
+                header.msg_control = cmsg_buffer.0.as_mut_ptr() as *mut libc::c_void;
+                header.msg_controllen = cmsg_space::<u16>() as _;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&header);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+                    let segment_size = packets[run.first].len() as u16;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+                }
+            }
+            headers.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// Send every packet to every address with `sendmmsg`, using GSO if `gso` is set.
+    /// If GSO turns out not to work, `gso` is cleared, and the packets are sent without it.
+    pub async fn send(
+        socket: &UdpSocket,
+        packets: &[Vec<u8>],
+        addrs: &[SocketAddr],
+        gso: &mut bool,
+        scratch: &mut Scratch,
+    ) -> std::io::Result<()> {
+        let Scratch {
+            messages,
+            addrs: sockaddrs,
+            runs,
+        } = scratch;
+        sockaddrs.clear();
+        sockaddrs.extend(addrs.iter().map(to_sockaddr));
+        make_runs(packets, sockaddrs.len(), *gso, runs);
+        let mut sent = 0;
+        while sent < runs.len() {
+            socket.writable().await?;
+            let result = socket.try_io(Interest::WRITABLE, || {
+                send_runs(socket, &runs[sent..], packets, sockaddrs, messages)
+            });
+            match result {
+                Ok(count) => sent += count,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) if *gso && is_gso_error(&e) => {
+                    warn!("UDP GSO does not work ({e}), sending datagrams separately");
+                    *gso = false;
+                    // Send the rest without GSO: the runs are in the same order, one datagram each
+                    let datagrams_sent: usize = runs[..sent].iter().map(|run| run.count).sum();
+                    make_runs(packets, sockaddrs.len(), false, runs);
+                    runs.drain(..datagrams_sent);
+                    sent = 0;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive as many datagrams as are available into the spare capacity of the buffers
+    /// with a single `recvmmsg` call, waiting until there is at least one.
+    pub async fn recv(
+        socket: &UdpSocket,
+        buffers: &mut [BytesMut],
+        received: &mut Vec<Received>,
+        gro: bool,
+        scratch: &mut Scratch,
+    ) -> std::io::Result<()> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || {
+                recv_messages(socket, buffers, received, gro, scratch)
+            }) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn recv_messages(
+        socket: &UdpSocket,
+        buffers: &mut [BytesMut],
+        received: &mut Vec<Received>,
+        gro: bool,
+        scratch: &mut Scratch,
+    ) -> std::io::Result<()> {
+        let Messages {
+            iovecs,
+            cmsgs,
+            headers,
+        } = &mut scratch.messages;
+        let addrs = &mut scratch.addrs;
+        iovecs.clear();
+        iovecs.extend(buffers.iter_mut().map(|buffer| {
+            let spare = buffer.spare_capacity_mut();
+            libc::iovec {
+                iov_base: spare.as_mut_ptr() as *mut libc::c_void,
+                iov_len: spare.len(),
+            }
+        }));
+        addrs.clear();
+        addrs.resize(buffers.len(), (unsafe { zeroed() }, 0));
+        cmsgs.clear();
+        cmsgs.resize(buffers.len(), CmsgBuffer([0; 64]));
+        headers.clear();
+        headers.extend(
+            iovecs
+                .iter_mut()
+                .zip(addrs.iter_mut())
+                .zip(cmsgs.iter_mut())
+                .map(|((iovec, (addr, _)), cmsg_buffer)| {
+                    let mut header: libc::msghdr = unsafe { zeroed() };
+                    header.msg_name = addr as *mut _ as *mut libc::c_void;
+                    header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                    header.msg_iov = iovec;
+                    header.msg_iovlen = 1;
+                    if gro {
+                        header.msg_control = cmsg_buffer.0.as_mut_ptr() as *mut libc::c_void;
+                        header.msg_controllen = cmsg_buffer.0.len() as _;
+                    }
+                    libc::mmsghdr {
+                        msg_hdr: header,
+                        msg_len: 0,
+                    }
+                }),
+        );
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        for ((message, (addr, _)), buffer) in headers
+            .iter()
+            .zip(addrs.iter())
+            .zip(buffers.iter_mut())
+            .take(count as usize)
+        {
+            let addr = match from_sockaddr(addr) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            // The kernel wrote this many bytes into the spare capacity of the buffer
+            unsafe { buffer.set_len(message.msg_len as usize) };
+            received.push(Received {
+                data: buffer.split().freeze(),
+                addr,
+                segment_size: if gro { gro_segment_size(&message.msg_hdr) } else { None },
+            });
+        }
+        Ok(())
+    }
+
+    /// Find the size of the merged datagrams in the control messages, if the kernel merged any.
+    fn gro_segment_size(header: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let size = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(size as usize).filter(|&size| size > 0);
+                }
+                cmsg = libc::CMSG_NXTHDR(header, cmsg);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batch_round_trip() {
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver_socket.local_addr().unwrap();
+
+        // Runs of equal sizes can be merged, with a shorter one at the end of each run
+        let packets: Vec<Vec<u8>> = (0..20u8)
+            .map(|i| vec![i; if i % 5 == 4 { 50 } else { 100 }])
+            .collect();
+        let mut sender = BatchSender::new(&sender_socket);
+        sender.send(&sender_socket, &packets, &[addr]).await.unwrap();
+
+        let mut receiver = BatchReceiver::new(&receiver_socket, 1024);
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            for (data, src) in receiver.recv(&receiver_socket).await.unwrap() {
+                assert_eq!(src, sender_socket.local_addr().unwrap());
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, packets);
+    }
+}
@@ -0,0 +1,125 @@
+/// Optional features that peers tell each other about when a client joins a server.
+///
+/// A feature with a capability can be rolled out without a new protocol version:
+/// the server only uses it while every joined client has the capability,
+/// and peers ignore the capabilities that they don't know.
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+
+/// A set of capabilities.
+/// On the wire, this is a bit set, so that unknown capabilities take no room to skip.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// No capabilities at all.
+    pub const NONE: Self = Self(0);
+    /// Rebuilding chunks from `FileChunkRepair` shards.
+    pub const FEC: Self = Self(1 << 0);
+    /// Decompressing chunks compressed with Zstandard.
+    pub const ZSTD: Self = Self(1 << 1);
+    /// Decompressing chunks compressed with LZ4.
+    pub const LZ4: Self = Self(1 << 2);
+    /// Putting together file listing entries from `FileListingPart` messages.
+    pub const LISTING_PARTS: Self = Self(1 << 3);
+    /// Checking chunks against the `ChunkHashes` that the server broadcasts.
+    pub const CHUNK_HASHES: Self = Self(1 << 4);
+    /// Telling the server which chunks were taken from old copies of files, in `CopiedChunks` messages,
+    /// so that it can leave them out of the broadcasts.
+    pub const DELTA: Self = Self(1 << 5);
+
+    /// The capabilities of this build.
+    pub const SUPPORTED: Self = Self(
+        Self::FEC.0
+            | Self::ZSTD.0
+            | Self::LZ4.0
+            | Self::LISTING_PARTS.0
+            | Self::CHUNK_HASHES.0
+            | Self::DELTA.0,
+    );
+
+    /// The names of the capabilities, for display.
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::FEC, "fec"),
+        (Self::ZSTD, "zstd"),
+        (Self::LZ4, "lz4"),
+        (Self::LISTING_PARTS, "listing-parts"),
+        (Self::CHUNK_HASHES, "chunk-hashes"),
+        (Self::DELTA, "delta"),
+    ];
+
+    /// The capabilities of a peer that talks in the given protocol version
+    /// but did not tell us its capabilities, because it is older than them.
+    pub fn implied_by(version: u16) -> Self {
+        match version {
+            0..=1 => Self::NONE,
+            2 => Self::FEC,
+            _ => Self(Self::FEC.0 | Self::ZSTD.0 | Self::LZ4.0),
+        }
+    }
+
+    /// Check if every capability in `other` is in this set.
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities that are in both sets.
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// The capability to decompress chunks compressed with the given algorithm.
+    /// Every peer can read uncompressed chunks.
+    pub fn for_compression(compression: Compression) -> Self {
+        match compression {
+            Compression::None => Self::NONE,
+            Compression::Zstd => Self::ZSTD,
+            Compression::Lz4 => Self::LZ4,
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<String> = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let known = Self::NAMES
+            .iter()
+            .fold(Self::NONE, |known, (capability, _)| known | *capability);
+        let unknown = self.0 & !known.0;
+        if unknown != 0 {
+            names.push(format!("{unknown:#x}"));
+        }
+        write!(f, "{{{}}}", names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let theirs = Capabilities::FEC | Capabilities(1 << 40);
+        assert_eq!(Capabilities::SUPPORTED.intersection(theirs), Capabilities::FEC);
+        assert!(Capabilities::SUPPORTED.contains(Capabilities::for_compression(Compression::Lz4)));
+        assert!(!theirs.contains(Capabilities::ZSTD));
+        assert!(theirs.contains(Capabilities::for_compression(Compression::None)));
+        assert_eq!(theirs.to_string(), "{fec, 0x10000000000}");
+        assert_eq!(Capabilities::implied_by(1), Capabilities::NONE);
+        assert!(Capabilities::implied_by(4).contains(Capabilities::ZSTD));
+        assert!(!Capabilities::implied_by(4).contains(Capabilities::LISTING_PARTS));
+    }
+}
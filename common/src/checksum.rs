@@ -0,0 +1,92 @@
+/// Checksums that protect packets against network corruption.
+///
+/// The checksum is not a security measure: anyone can compute it.
+/// To protect against forged packets, use a pre-shared key instead,
+/// which replaces the checksum with a MAC.
+use sha2::Digest;
+
+/// An algorithm to compute the checksum of a packet with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    /// SHA-256: slow and long, but this is what every version of the protocol understands.
+    #[default]
+    Sha256,
+    /// CRC-32C, which is computed in hardware on most CPUs.
+    Crc32c,
+    /// The 64-bit XXH3 hash.
+    Xxh3,
+}
+
+impl ChecksumAlgorithm {
+    /// The identifier of the algorithm in the packet header.
+    pub fn id(&self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Sha256 => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+            ChecksumAlgorithm::Xxh3 => 2,
+        }
+    }
+
+    /// Get the algorithm with the given identifier, if we know it.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ChecksumAlgorithm::Sha256),
+            1 => Some(ChecksumAlgorithm::Crc32c),
+            2 => Some(ChecksumAlgorithm::Xxh3),
+            _ => None,
+        }
+    }
+
+    /// The length of the checksum in bytes.
+    pub fn length(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Sha256 => 32,
+            ChecksumAlgorithm::Crc32c => 4,
+            ChecksumAlgorithm::Xxh3 => 8,
+        }
+    }
+
+    /// Compute the checksum of the data into `out`, which must be `length()` bytes long.
+    pub fn compute_into(&self, data: &[u8], out: &mut [u8]) {
+        match self {
+            ChecksumAlgorithm::Sha256 => out.copy_from_slice(&sha2::Sha256::digest(data)),
+            ChecksumAlgorithm::Crc32c => out.copy_from_slice(&crc32c::crc32c(data).to_be_bytes()),
+            ChecksumAlgorithm::Xxh3 => {
+                out.copy_from_slice(&xxhash_rust::xxh3::xxh3_64(data).to_be_bytes())
+            }
+        }
+    }
+
+    /// Check that `checksum` is the checksum of the data.
+    pub fn verify(&self, data: &[u8], checksum: &[u8]) -> bool {
+        let mut expected = [0; 32];
+        let expected = &mut expected[..self.length()];
+        self.compute_into(data, expected);
+        expected == checksum
+    }
+}
+
+impl std::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Xxh3 => "xxh3",
+        })
+    }
+}
+
+impl std::str::FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "crc32c" => Ok(ChecksumAlgorithm::Crc32c),
+            "xxh3" => Ok(ChecksumAlgorithm::Xxh3),
+            _ => Err(format!(
+                "unknown checksum algorithm {s:?}, expected one of sha256, crc32c, xxh3"
+            )),
+        }
+    }
+}
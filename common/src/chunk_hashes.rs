@@ -0,0 +1,47 @@
+/// Hashes of the chunks of files, which let the client check every chunk as it arrives,
+/// and tell which chunks of a file that doesn't match its hash are bad.
+///
+/// The hash of a chunk is its SHA-256 hash. A list of hashes is the hashes of consecutive chunks,
+/// one after the other, as in hashlists and `ChunkHashes` messages.
+use sha2::Digest;
+
+/// The size of the hash of a chunk.
+pub const HASH_SIZE: usize = 32;
+
+/// Get the hash of a chunk.
+pub fn hash_chunk(data: &[u8]) -> [u8; HASH_SIZE] {
+    sha2::Sha256::digest(data).into()
+}
+
+/// The number of hashes to send in each `ChunkHashes` message for a file with chunks of the given size.
+///
+/// A `ChunkHashes` message is encoded like a `FileChunk`, so that it fits wherever a chunk does.
+/// It also has the rolling checksums of the same chunks, in a second `bin` object
+/// whose header takes up to 3 bytes: see the `rolling` module.
+pub fn hashes_per_message(chunk_size: u16) -> usize {
+    ((chunk_size as usize).saturating_sub(3) / (HASH_SIZE + crate::rolling::CHECKSUM_SIZE)).max(1)
+}
+
+/// Split a list of hashes into the hashes of each chunk.
+/// A partial hash at the end, which a broken list may have, is left out.
+pub fn split(hashes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    hashes.chunks_exact(HASH_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        let hashes: Vec<u8> = [&b"first"[..], b"second"]
+            .iter()
+            .flat_map(|chunk| hash_chunk(chunk))
+            .collect();
+        let split: Vec<&[u8]> = split(&hashes).collect();
+        assert_eq!(split, [hash_chunk(b"first"), hash_chunk(b"second")]);
+        assert_ne!(split[0], split[1]);
+        assert_eq!(hashes_per_message(1400), 38);
+        assert_eq!(hashes_per_message(10), 1);
+    }
+}
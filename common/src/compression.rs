@@ -0,0 +1,126 @@
+/// Compression of file chunks on the wire.
+///
+/// Chunks are compressed one at a time, so that every chunk can still be decoded on its own.
+/// Files are always stored and hashed uncompressed: compression only changes the packets.
+use serde::{Deserialize, Serialize};
+
+/// An algorithm to compress chunks with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Chunks are sent as they are.
+    None,
+    /// Zstandard, which compresses well at a moderate speed.
+    #[default]
+    Zstd,
+    /// LZ4, which compresses less but is very fast.
+    Lz4,
+}
+
+/// The Zstandard level to compress with: its own default, which is a good balance of speed and size.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Errors that can occur when decompressing a chunk.
+#[derive(Debug)]
+pub enum DecompressionError {
+    /// The compressed data is not valid.
+    Corrupt,
+    /// The data decompressed to the wrong size.
+    /// The first value is the expected size, the second is the actual size.
+    WrongSize(usize, usize),
+}
+
+impl Compression {
+    /// The identifier of the algorithm in the compact encoding of messages.
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    /// Get the algorithm with the given identifier, if we know it.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Compress the data.
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Zstd => {
+                zstd::bulk::compress(data, ZSTD_LEVEL).expect("Failed to compress chunk")
+            }
+            Compression::Lz4 => lz4_flex::compress(data),
+        }
+    }
+
+    /// Decompress data that was compressed with `compress`.
+    ///
+    /// The size of the uncompressed data must be known in advance:
+    /// for a chunk, it follows from the file size and the chunk size.
+    pub fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>, DecompressionError> {
+        let decompressed = match self {
+            Compression::None => data.to_vec(),
+            Compression::Zstd => {
+                zstd::bulk::decompress(data, size).map_err(|_| DecompressionError::Corrupt)?
+            }
+            Compression::Lz4 => {
+                lz4_flex::decompress(data, size).map_err(|_| DecompressionError::Corrupt)?
+            }
+        };
+        if decompressed.len() != size {
+            return Err(DecompressionError::WrongSize(size, decompressed.len()));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!(
+                "unknown compression algorithm {s:?}, expected one of none, zstd, lz4"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"Hello, world! Hello, world! Hello, world! Hello, world!".repeat(10);
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&data);
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len());
+            }
+            assert_eq!(compression.decompress(&compressed, data.len()).unwrap(), data);
+            assert!(compression.decompress(&compressed, data.len() + 1).is_err());
+            assert_eq!(Compression::from_id(compression.id()), Some(compression));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+/// Forward error correction for file chunks.
+///
+/// The chunks of a file are grouped into blocks of consecutive chunks.
+/// For every block, some repair shards are computed with a Reed-Solomon code.
+/// A client that has received any `data_shards` shards of a block
+/// (data chunks or repair shards, in any combination) can rebuild the rest of the block.
+///
+/// All shards of a block are as long as the file's chunk size:
+/// the last chunk of a file is padded with zeros before encoding.
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+pub use reed_solomon_erasure::Error as FecError;
+
+/// The maximum number of shards (data and repair) in a single block.
+/// This is a limit of the Reed-Solomon code over GF(2^8).
+pub const MAX_SHARDS: usize = 256;
+
+/// Parameters for generating repair shards.
+#[derive(Debug, Clone, Copy)]
+pub struct FecParams {
+    /// The number of chunks in a block.
+    pub block_size: u16,
+    /// The number of repair shards per data chunk.
+    /// For example, 0.25 means one repair shard for every 4 chunks.
+    /// If this is zero, no repair shards are sent.
+    pub overhead: f64,
+}
+
+impl FecParams {
+    /// The number of repair shards to send for a block of the given number of chunks.
+    /// The result is rounded up, and capped so that the block fits in the code.
+    pub fn parity_shards(&self, data_shards: usize) -> usize {
+        if data_shards == 0 {
+            return 0;
+        }
+        let parity = (data_shards as f64 * self.overhead).ceil() as usize;
+        parity.min(MAX_SHARDS.saturating_sub(data_shards))
+    }
+}
+
+/// Compute the repair shards for a block of data chunks.
+///
+/// The chunks are padded with zeros to `shard_size` bytes.
+/// Returns `parity_shards` repair shards, each `shard_size` bytes long.
+pub fn make_repair_shards(
+    data: &[impl AsRef<[u8]>],
+    parity_shards: usize,
+    shard_size: usize,
+) -> Result<Vec<Vec<u8>>, FecError> {
+    let codec = ReedSolomon::new(data.len(), parity_shards)?;
+    let mut shards: Vec<Vec<u8>> = data
+        .iter()
+        .map(|chunk| {
+            let mut shard = chunk.as_ref().to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect();
+    shards.extend((0..parity_shards).map(|_| vec![0; shard_size]));
+    codec.encode(&mut shards)?;
+    Ok(shards.split_off(data.len()))
+}
+
+/// Rebuild the missing data chunks of a block.
+///
+/// `shards` must contain `data_shards` data chunks (padded to the shard size),
+/// followed by the repair shards, with `None` in place of every shard that was not received.
+/// On success, every data shard is filled in; repair shards may be left as `None`.
+pub fn reconstruct(shards: &mut [Option<Vec<u8>>], data_shards: usize) -> Result<(), FecError> {
+    let codec = ReedSolomon::new(data_shards, shards.len() - data_shards)?;
+    codec.reconstruct_data(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct_missing_chunks() {
+        let data: Vec<Vec<u8>> = vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![9, 10]];
+        let repair = make_repair_shards(&data, 2, 4).unwrap();
+        assert_eq!(repair.len(), 2);
+
+        // Lose two of the data chunks, keep both repair shards
+        let mut shards = vec![
+            None,
+            Some(vec![5, 6, 7, 8]),
+            None,
+            Some(repair[0].clone()),
+            Some(repair[1].clone()),
+        ];
+        reconstruct(&mut shards, 3).unwrap();
+        assert_eq!(shards[0].as_deref(), Some(&[1, 2, 3, 4][..]));
+        // The short last chunk comes back padded
+        assert_eq!(shards[2].as_deref(), Some(&[9, 10, 0, 0][..]));
+    }
+
+    #[test]
+    fn test_parity_shards() {
+        let params = FecParams {
+            block_size: 32,
+            overhead: 0.1,
+        };
+        assert_eq!(params.parity_shards(32), 4);
+        assert_eq!(params.parity_shards(1), 1);
+        assert_eq!(params.parity_shards(0), 0);
+        let disabled = FecParams {
+            block_size: 32,
+            overhead: 0.0,
+        };
+        assert_eq!(disabled.parity_shards(32), 0);
+    }
+}
@@ -1,8 +1,9 @@
 /// Functions for dealing with the filesystem.
-use std::path::PathBuf;
+use sha2::Digest;
+use std::path::{Path, PathBuf};
 use tokio::{
     fs,
-    io::{AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
 
 #[allow(unused_imports)]
@@ -90,5 +91,59 @@ pub async fn write_chunk(
     }
     file.seek(std::io::SeekFrom::Start(offset)).await?;
     file.write_all(data).await?;
+    // Tokio finishes the write in the background: wait for it,
+    // so that the chunk can be read back as soon as this returns.
+    file.flush().await?;
     Ok(())
 }
+
+/// Get the SHA-256 hash of a whole file, as in hashlists and file listings.
+pub async fn hash_file(path: impl AsRef<Path>) -> Result<[u8; 32], std::io::Error> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Get the SHA-256 hash of a whole file, like `hash_file`,
+/// and the hashes and rolling checksums of its chunks of the given size, one after the other:
+/// see the `chunk_hashes` and `rolling` modules.
+pub async fn hash_file_and_chunks(
+    path: impl AsRef<Path>,
+    chunk_size: usize,
+) -> Result<([u8; 32], Vec<u8>, Vec<u8>), std::io::Error> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = sha2::Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut checksums = Vec::new();
+    let mut buf = vec![0; chunk_size];
+
+    loop {
+        // Fill the whole chunk, unless the file ends first
+        let mut filled = 0;
+        while filled < chunk_size {
+            let n = file.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        hasher.update(&buf[..filled]);
+        chunk_hashes.extend(crate::chunk_hashes::hash_chunk(&buf[..filled]));
+        checksums.extend(crate::rolling::checksum(&buf[..filled]).to_le_bytes());
+    }
+
+    Ok((hasher.finalize().into(), chunk_hashes, checksums))
+}
@@ -0,0 +1,103 @@
+/// Discovery of the local network interfaces, and of the addresses to broadcast to on them.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// An address of a local network interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    /// The name of the interface, like `eth0`.
+    pub name: String,
+    /// The index of the interface, which is the zone of its IPv6 link-local addresses.
+    pub index: u32,
+    /// The address of the interface.
+    pub ip: IpAddr,
+    /// The netmask of the subnet that the address is in.
+    pub netmask: IpAddr,
+}
+
+impl InterfaceAddr {
+    /// Whether this is an address of a loopback interface, which leads nowhere.
+    pub fn is_loopback(&self) -> bool {
+        self.ip.is_loopback()
+    }
+
+    /// The address that reaches every host on this subnet:
+    /// the subnet's broadcast address for IPv4,
+    /// or the all-nodes multicast group on this interface for IPv6.
+    pub fn broadcast_addr(&self, port: u16) -> SocketAddr {
+        match (self.ip, self.netmask) {
+            (IpAddr::V4(ip), IpAddr::V4(netmask)) => {
+                SocketAddr::new(broadcast_address(ip, netmask).into(), port)
+            }
+            _ => SocketAddrV6::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), port, 0, self.index)
+                .into(),
+        }
+    }
+}
+
+/// The broadcast address of the IPv4 subnet: the address with all the host bits set.
+pub fn broadcast_address(ip: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(ip) | !u32::from(netmask))
+}
+
+/// List the addresses of all the local network interfaces.
+pub fn list() -> Result<Vec<InterfaceAddr>, std::io::Error> {
+    let mut addrs = vec![];
+    for interface in if_addrs::get_if_addrs()? {
+        let (ip, netmask) = match interface.addr {
+            if_addrs::IfAddr::V4(addr) => (addr.ip.into(), addr.netmask.into()),
+            if_addrs::IfAddr::V6(addr) => (addr.ip.into(), addr.netmask.into()),
+        };
+        let index = match crate::networking::interface_index(&interface.name) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("Skipping interface {}: {e}", interface.name);
+                continue;
+            }
+        };
+        addrs.push(InterfaceAddr {
+            name: interface.name,
+            index,
+            ip,
+            netmask,
+        });
+    }
+    Ok(addrs)
+}
+
+/// The addresses that reach every host on every subnet that we are on, except the loopback.
+///
+/// Each IPv4 subnet has its own broadcast address,
+/// and each interface with IPv6 gets the all-nodes multicast group, with the interface as its zone.
+pub fn broadcast_addrs(port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+    let mut addrs: Vec<SocketAddr> = vec![];
+    for interface in list()? {
+        if interface.is_loopback() {
+            continue;
+        }
+        let addr = interface.broadcast_addr(port);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast_address() {
+        assert_eq!(
+            broadcast_address([192, 168, 1, 20].into(), [255, 255, 255, 0].into()),
+            Ipv4Addr::from([192, 168, 1, 255])
+        );
+        assert_eq!(
+            broadcast_address([10, 1, 2, 3].into(), [255, 255, 240, 0].into()),
+            Ipv4Addr::from([10, 1, 15, 255])
+        );
+    }
+}
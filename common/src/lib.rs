@@ -1,22 +1,125 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
+pub mod auth;
+pub mod batch;
+pub mod capabilities;
 pub mod channels;
+pub mod checksum;
+pub mod chunk_hashes;
+pub mod compression;
+pub mod fec;
 pub mod filesystem;
+pub mod interfaces;
+pub mod listing;
 pub mod magic;
 pub mod messages;
+pub mod multicast;
 pub mod networking;
+pub mod paths;
 pub mod ping_reply;
+pub mod resume;
+pub mod rolling;
+pub mod sequence;
 
 use crate::messages::Message;
 
 type DecodeError = rmp_serde::decode::Error;
-pub type MessageGroup = (SocketAddr, String, Message);
+
+/// The id of a running process on the network, which it puts into the header of its packets.
+///
+/// Every process makes up a random one when it starts, so that peers with the same name are told apart,
+/// and a peer that restarts is a new peer.
+pub type InstanceId = u32;
+
+/// The peer that sent a packet, as received in its header.
+///
+/// Peers are told apart by their instance ids: the name is only for display, and two peers may share it.
+/// The listener shares one allocation of the name between all packets from the same peer.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// The instance id of the peer.
+    pub id: InstanceId,
+    /// The name of the peer.
+    pub name: Arc<str>,
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Peer {}
+
+impl std::hash::Hash for Peer {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+pub type MessageGroup = (SocketAddr, Peer, Message);
 pub type MessageReceiver = tokio::sync::mpsc::Receiver<MessageGroup>;
 pub type HashType = [u8; 32]; // sha256
 
+/// Who we are on the network: what we put in the header of the packets we send.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// Our name, which is shown to other peers.
+    pub name: String,
+    /// Our instance id, which tells us apart from other peers, even if they have the same name.
+    /// Make it with `make_instance_id`.
+    pub instance: InstanceId,
+    /// The pre-shared key to authenticate our packets with, if any.
+    /// Peers with a key also drop any packets not authenticated with it.
+    pub psk: Option<auth::Psk>,
+    /// Whether to encrypt our packets with the pre-shared key, rather than only authenticating them.
+    /// Has no effect without a key.
+    pub encrypt: bool,
+    /// The algorithm for the checksums of our packets, or `None` for the default of each version.
+    /// Has no effect with a pre-shared key, because then the packets have a MAC instead.
+    pub checksum: Option<checksum::ChecksumAlgorithm>,
+}
+
+impl Identity {
+    /// Make an identity with the given name, a new instance id, no pre-shared key, and the default checksums.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            instance: make_instance_id(),
+            psk: None,
+            encrypt: false,
+            checksum: None,
+        }
+    }
+
+    /// The algorithm for the checksums of packets in the given protocol version.
+    ///
+    /// Unless one was chosen, this is SHA-256 in the old header, which every version understands,
+    /// and the much shorter CRC-32C in the compact header.
+    pub fn checksum_for(&self, version: u16) -> checksum::ChecksumAlgorithm {
+        match self.checksum {
+            Some(checksum) => checksum,
+            None if version >= magic::COMPACT_VERSION => checksum::ChecksumAlgorithm::Crc32c,
+            None => checksum::ChecksumAlgorithm::Sha256,
+        }
+    }
+}
+
 /// Make a random name for an object.
 ///
 /// Uses the `petname` crate for human-readable names.
 pub fn make_name() -> String {
     petname::petname(3, "-")
 }
+
+/// Make a random instance id for this process.
+pub fn make_instance_id() -> InstanceId {
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    OsRng.next_u32()
+}
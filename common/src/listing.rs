@@ -0,0 +1,144 @@
+/// File listing entries that are too large for one datagram, like those of files with long paths.
+///
+/// The server encodes such an entry, and sends it in `FileListingPart` messages of the largest size that fits.
+/// The client collects the parts of every entry until it has all of them, and decodes the entry.
+/// Parts are only sent to clients with `Capabilities::LISTING_PARTS`: the others get the whole entry.
+///
+/// The entry is encoded as a `FileListing` message in the compact encoding,
+/// so that the parts carry every field that the whole entry would.
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::{
+    magic::COMPACT_VERSION,
+    messages::{FileListingFragment, FileListingPartData, Message},
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// Split a file listing entry into parts with at most `part_size` bytes of data each.
+///
+/// Panics if `part_size` is 0, or so small that there would be more than `u16::MAX` parts.
+pub fn split(entry: &FileListingFragment, part_size: usize) -> Vec<FileListingPartData> {
+    assert!(part_size > 0, "File listing parts must have some data");
+    let encoded = Message::FileListing(entry.clone())
+        .serialize(COMPACT_VERSION)
+        .expect("File listings exist in every version");
+    let encoded = Bytes::from(encoded);
+    let parts: u16 = encoded
+        .len()
+        .div_ceil(part_size)
+        .try_into()
+        .expect("Too many parts for a file listing entry");
+    (0..parts)
+        .map(|part| {
+            let start = part as usize * part_size;
+            let end = (start + part_size).min(encoded.len());
+            FileListingPartData {
+                idx: entry.idx,
+                part,
+                parts,
+                data: encoded.slice(start..end),
+            }
+        })
+        .collect()
+}
+
+/// The parts of the file listing entries that are still missing some.
+#[derive(Debug, Default)]
+pub struct ListingAssembler {
+    entries: HashMap<u32, Vec<Option<Bytes>>>,
+}
+
+impl ListingAssembler {
+    /// Forget all incomplete entries once there are this many, so that forged parts can't use up all memory.
+    const MAX_ENTRIES: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a part of an entry, and return the entry if this was the last part that was missing.
+    ///
+    /// Parts that don't add up are dropped, along with the parts that we have of their entry.
+    pub fn add(&mut self, part: FileListingPartData) -> Option<FileListingFragment> {
+        if part.part >= part.parts {
+            return None;
+        }
+        if !self.entries.contains_key(&part.idx) && self.entries.len() >= Self::MAX_ENTRIES {
+            self.entries.clear();
+        }
+        let parts = self
+            .entries
+            .entry(part.idx)
+            .or_insert_with(|| vec![None; part.parts.into()]);
+        if parts.len() != part.parts as usize {
+            // The entry is split differently than we thought: start over
+            *parts = vec![None; part.parts.into()];
+        }
+        parts[part.part as usize] = Some(part.data);
+        if parts.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let parts = self.entries.remove(&part.idx).unwrap();
+        let encoded: Vec<u8> = parts.into_iter().flatten().flatten().collect();
+        match Message::deserialize(COMPACT_VERSION, &encoded) {
+            Ok(Some(Message::FileListing(entry))) if entry.idx == part.idx => Some(entry),
+            Ok(Some(Message::FileListing(entry))) => {
+                warn!(
+                    "Parts of file listing entry {} decoded to entry {}",
+                    part.idx, entry.idx
+                );
+                None
+            }
+            Ok(other) => {
+                warn!(
+                    "Parts of file listing entry {} decoded to {other:?}",
+                    part.idx
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Failed to decode file listing entry {} from its parts: {e}", part.idx);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join() {
+        let entry = FileListingFragment {
+            idx: 3,
+            total: 4,
+            path: ("deeply/".repeat(500) + "nested").into_bytes(),
+            size: 5,
+            hash: [6; 32],
+            chunk_size: 7,
+            chunk_hashes: true,
+        };
+        let mut parts = split(&entry, 1000);
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|part| part.data.len() <= 1000));
+
+        // The parts may arrive in any order, and more than once
+        let mut assembler = ListingAssembler::new();
+        let last = parts.remove(1);
+        for part in parts.iter().rev() {
+            assert!(assembler.add(part.clone()).is_none());
+        }
+        assert!(assembler.add(parts[0].clone()).is_none());
+        let joined = assembler.add(last).unwrap();
+        assert_eq!(joined.path, entry.path);
+        assert_eq!(joined.hash, entry.hash);
+        assert!(joined.chunk_hashes);
+        assert!(assembler.entries.is_empty());
+    }
+}
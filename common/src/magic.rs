@@ -1,11 +1,64 @@
-use sha2::Digest;
+use std::borrow::Cow;
 
-use crate::{messages::Message, DecodeError, HashType};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{AuthError, Psk, SALT_LENGTH, SHORT_MAC_LENGTH, TAG_LENGTH},
+    checksum::ChecksumAlgorithm,
+    messages::Message,
+    DecodeError, Identity, InstanceId,
+};
 
 /// Convenience functions for working with magic over the network.
 
-/// The version of the protocol. This must match on both sides of the connection.
-static VERSION: u16 = 1;
+/// The newest version of the protocol that we speak.
+pub const VERSION: u16 = 4;
+
+/// The oldest version of the protocol that we can still encode and decode.
+pub const MIN_VERSION: u16 = 1;
+
+/// The first version of the protocol whose packets have the compact header,
+/// and whose messages have the compact encoding.
+pub const COMPACT_VERSION: u16 = 4;
+
+/// A range of protocol versions, both ends included.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VersionRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl VersionRange {
+    /// The versions that this build supports.
+    pub const SUPPORTED: Self = Self {
+        min: MIN_VERSION,
+        max: VERSION,
+    };
+
+    /// A range containing only the given version.
+    pub fn only(version: u16) -> Self {
+        Self {
+            min: version,
+            max: version,
+        }
+    }
+
+    /// Check if the given version is in this range.
+    pub fn contains(&self, version: u16) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// The highest version that is in both ranges, if any.
+    pub fn highest_common(&self, other: &Self) -> Option<u16> {
+        let max = self.max.min(other.max);
+        if max >= self.min.max(other.min) {
+            Some(max)
+        } else {
+            None
+        }
+    }
+}
 
 /// Errors that can occur when parsing a magic prefix.
 #[derive(Debug)]
@@ -14,7 +67,7 @@ pub enum MagicError {
     /// This probably means that the packet is not intended for us, do not say anything.
     InvalidMagic,
 
-    /// The version of the protocol does not match.
+    /// The version of the protocol is not one that we support.
     /// The value included is their version.
     /// You might want a warning upon seeing this.
     InvalidVersion(u16),
@@ -28,17 +81,158 @@ pub enum MagicError {
     /// This probably means that there is network corruption.
     HashMismatch,
 
+    /// The packet declares a checksum algorithm that we don't know.
+    /// The value is the identifier of the algorithm.
+    UnknownChecksum(u8),
+
     /// The magic prefix was valid, but the packet was otherwise invalid.
     DecodeError(DecodeError),
+
+    /// The packet is valid, but has a message of a type that we don't know.
+    /// This means that the peer is newer than us: ignore it.
+    UnknownMessage,
+
+    /// The packet failed authentication with the pre-shared key.
+    /// This means that the packet is forged, stale, or from a peer with a different key.
+    AuthError(AuthError),
+
+    /// The packet is authentic, but we already received a packet from this peer with its timestamp.
+    /// This is usually the network delivering it twice, as to a socket that receives both IPv4 and IPv6:
+    /// drop it quietly. The value is the timestamp of the packet.
+    Replayed(u64),
+}
+
+/// The magic prefix of packets with a plain SHA-256 hash.
+const MAGIC: &[u8] = b"RustUDPs";
+
+/// The magic prefix of packets with a checksum of a declared algorithm.
+/// These carry a byte identifying the algorithm, and the checksum in place of the hash.
+const MAGIC_CHECKSUM: &[u8] = b"RustUDPc";
+
+/// The magic prefix of packets authenticated with a pre-shared key.
+/// These carry a timestamp, and a MAC in place of the hash.
+const MAGIC_AUTHENTICATED: &[u8] = b"RustUDPa";
+
+/// The magic prefix of packets encrypted with a pre-shared key.
+/// These carry a timestamp and a salt, and the message is encrypted, followed by the AEAD tag.
+const MAGIC_ENCRYPTED: &[u8] = b"RustUDPe";
+
+/// The magic prefix of packets with the compact header, from version 4 on.
+///
+/// The compact header is:
+/// - the magic prefix, 4 bytes;
+/// - the version, 1 byte;
+/// - the flags, 1 byte: the mode in the low 2 bits (one of the `MODE_*`),
+///   `FLAG_NAME` if the name of the sender follows, `FLAG_SEQUENCE` if a sequence number does,
+///   and the checksum algorithm in the high 4 bits, if the mode is `MODE_CHECKSUM`;
+/// - the instance id of the sender, 4 bytes, which also stands in for the name;
+/// - if `FLAG_SEQUENCE` is set, the sequence number, 4 bytes;
+/// - if `FLAG_NAME` is set, the length of the name in 1 byte, and the name;
+/// - in authenticated and encrypted packets, the timestamp, 8 bytes;
+/// - in encrypted packets, the salt, 4 bytes.
+///
+/// Then comes the message, and then the checksum, the MAC truncated to `SHORT_MAC_LENGTH` bytes,
+/// or the AEAD tag. There is no length field: the packet ends where the datagram does.
+///
+/// Only the messages that introduce a peer carry its name (see `carries_name`),
+/// and only the packets of the server's broadcaster carry a sequence number (see `crate::sequence`).
+/// Without either, the header and trailer add `COMPACT_OVERHEAD` bytes to the message with CRC-32C,
+/// 34 bytes when authenticated and 38 when encrypted.
+/// The old header adds 45 bytes plus the name, or 53 and 41 plus the name.
+const MAGIC_COMPACT: &[u8] = b"RUDP";
+
+/// The length of the fixed part of the compact header: the magic prefix, version, flags and sender id.
+const COMPACT_HEADER_LENGTH: usize = 10;
+
+/// The bytes that the compact header and a CRC-32C checksum add to a message without a name.
+pub const COMPACT_OVERHEAD: usize = COMPACT_HEADER_LENGTH + 4;
+
+/// Compact packets with a checksum.
+const MODE_CHECKSUM: u8 = 0;
+/// Compact packets authenticated with a pre-shared key.
+const MODE_AUTHENTICATED: u8 = 1;
+/// Compact packets encrypted with a pre-shared key.
+const MODE_ENCRYPTED: u8 = 2;
+/// The flag that the name of the sender follows the sender id.
+const FLAG_NAME: u8 = 1 << 2;
+/// The flag that a sequence number follows the sender id.
+const FLAG_SEQUENCE: u8 = 1 << 3;
+
+/// The instance id of a peer with the old header, which has no room for one: it is made from the name,
+/// so peers with the old header and the same name can't be told apart.
+pub fn legacy_instance_id(name: &str) -> InstanceId {
+    xxhash_rust::xxh3::xxh3_64(name.as_bytes()) as u32
+}
+
+/// Whether the compact header of a packet with this message carries the name of the sender.
+///
+/// These are the messages that a peer sends when it first talks to another,
+/// which learns the name that goes with the instance id from them.
+pub fn carries_name(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Announce { .. }
+            | Message::JoinQuery { .. }
+            | Message::JoinResponse { .. }
+            | Message::Disconnect(_)
+    )
+}
+
+/// The header of a packet.
+#[derive(Debug, Clone)]
+pub struct PacketHeader<'a> {
+    /// The instance id of the peer that sent the packet.
+    /// Packets with the old header don't have one, so it is made from their name.
+    pub sender: InstanceId,
+    /// The name of the peer that sent the packet, if the packet carries it.
+    /// Packets with the old header always do.
+    /// This borrows from the packet, unless the name had to be fixed up to be valid UTF-8.
+    pub name: Option<Cow<'a, str>>,
+    /// The sequence number of the packet, if it has one.
+    /// Packets with the old header never do.
+    pub sequence: Option<u32>,
+    /// The protocol version that the packet is encoded in.
+    pub version: u16,
+    /// The timestamp of the packet, if it is authenticated.
+    /// The MAC has been checked, but the timestamp still needs to be checked for replays.
+    pub timestamp: Option<u64>,
+}
+
+/// Split off the first `n` bytes of the data, or fail if there are not enough.
+fn take(data: &[u8], n: usize) -> Result<(&[u8], &[u8]), MagicError> {
+    if data.len() < n {
+        return Err(MagicError::LengthMismatch(n as u16, data.len() as u16));
+    }
+    Ok(data.split_at(n))
 }
 
 /// Extract the contents of a packet, stripping the magic prefix.
-/// Returns the name of the peer and the rest of the packet.
-pub fn parse_magic(data: &[u8]) -> Result<(String, &[u8]), MagicError> {
+/// Returns the header of the packet and the rest of the packet, decrypted if it was encrypted.
+///
+/// If `psk` is given, only packets authenticated or encrypted with that key are accepted.
+/// Otherwise, only packets that are not authenticated are accepted.
+pub fn parse_magic<'a>(
+    packet: &'a [u8],
+    psk: Option<&Psk>,
+) -> Result<(PacketHeader<'a>, Cow<'a, [u8]>), MagicError> {
+    if packet.starts_with(MAGIC_COMPACT) {
+        return parse_compact(packet, psk);
+    }
     // The first 8 bytes are the magic prefix
-    let (magic, data) = data.split_at(8);
-    if magic != "RustUDPs".as_bytes() {
+    let (magic, data) = take(packet, 8).map_err(|_| MagicError::InvalidMagic)?;
+    let (authenticated, encrypted) = if magic == MAGIC || magic == MAGIC_CHECKSUM {
+        (false, false)
+    } else if magic == MAGIC_AUTHENTICATED {
+        (true, false)
+    } else if magic == MAGIC_ENCRYPTED {
+        (true, true)
+    } else {
         return Err(MagicError::InvalidMagic);
+    };
+    match (authenticated, psk) {
+        (false, Some(_)) => return Err(MagicError::AuthError(AuthError::Missing)),
+        (true, None) => return Err(MagicError::AuthError(AuthError::NoKey)),
+        _ => {}
     }
     // The next bytes until the null byte are the name
     // Find the null byte or return an error
@@ -48,64 +242,680 @@ pub fn parse_magic(data: &[u8]) -> Result<(String, &[u8]), MagicError> {
         None => return Err(MagicError::InvalidMagic),
     };
     let (name, data) = data.split_at(zero_position);
-    let name = String::from_utf8_lossy(name).to_string();
+    let name = String::from_utf8_lossy(name);
     // The next one byte is zero, then 2 bytes are the version
-    let (version, data) = data.split_at(3);
+    let (version, data) = take(data, 3)?;
     let version = u16::from_be_bytes([version[1], version[2]]);
-    if version != VERSION {
+    if !VersionRange::SUPPORTED.contains(version) || version >= COMPACT_VERSION {
         return Err(MagicError::InvalidVersion(version));
     }
     // The next 2 bytes are the length
-    let (length, data) = data.split_at(2);
+    let (length, data) = take(data, 2)?;
     let length = u16::from_be_bytes([length[0], length[1]]);
-    // The next bytes are the hash
-    let hash_length = std::mem::size_of::<crate::HashType>();
-    let (hash, data) = data.split_at(hash_length);
+    // Authenticated packets have an 8-byte timestamp next
+    let (timestamp, data) = if authenticated {
+        let (timestamp, data) = take(data, 8)?;
+        (Some(u64::from_be_bytes(timestamp.try_into().unwrap())), data)
+    } else {
+        (None, data)
+    };
+
+    if encrypted {
+        // The salt is next, then the encrypted message
+        let (salt, data) = take(data, SALT_LENGTH)?;
+        // Everything up to here is authenticated by the AEAD tag
+        let signed_header = &packet[..packet.len() - data.len()];
+        if data.len() != length as usize + TAG_LENGTH {
+            return Err(MagicError::LengthMismatch(
+                length,
+                data.len().saturating_sub(TAG_LENGTH) as u16,
+            ));
+        }
+        let data = psk
+            .unwrap()
+            .decrypt(
+                salt.try_into().unwrap(),
+                timestamp.unwrap(),
+                signed_header,
+                data,
+            )
+            .map_err(MagicError::AuthError)?;
+        let header = PacketHeader {
+            sender: legacy_instance_id(&name),
+            name: Some(name),
+            sequence: None,
+            version,
+            timestamp,
+        };
+        return Ok((header, Cow::Owned(data)));
+    }
+
+    // Packets with a declared checksum have the algorithm next
+    let (algorithm, data) = if magic == MAGIC_CHECKSUM {
+        let (algorithm, data) = take(data, 1)?;
+        let algorithm = ChecksumAlgorithm::from_id(algorithm[0])
+            .ok_or(MagicError::UnknownChecksum(algorithm[0]))?;
+        (algorithm, data)
+    } else {
+        (ChecksumAlgorithm::Sha256, data)
+    };
+    // Everything up to here is covered by the MAC
+    let signed_header = &packet[..packet.len() - data.len()];
+    // The next bytes are the hash, or the MAC
+    let hash_length = if authenticated {
+        std::mem::size_of::<crate::HashType>()
+    } else {
+        algorithm.length()
+    };
+    let (hash, data) = take(data, hash_length)?;
     // The rest is the data
     if data.len() as u16 != length {
         return Err(MagicError::LengthMismatch(length, data.len() as u16));
     }
 
-    // Hash the data
-    let hash2 = make_hash(data);
-    if hash != hash2.as_slice() {
-        return Err(MagicError::HashMismatch);
+    if let Some(psk) = psk {
+        // Check the MAC
+        psk.verify(&[signed_header, data], hash)
+            .map_err(MagicError::AuthError)?;
+    } else {
+        // Hash the data
+        if !algorithm.verify(data, hash) {
+            return Err(MagicError::HashMismatch);
+        }
     }
 
-    Ok((name, data))
+    let header = PacketHeader {
+        sender: legacy_instance_id(&name),
+        name: Some(name),
+        sequence: None,
+        version,
+        timestamp,
+    };
+    Ok((header, Cow::Borrowed(data)))
 }
 
-fn make_hash(data: &[u8]) -> HashType {
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(data);
-    let hash = hasher.finalize();
-    hash[..].try_into().unwrap()
-}
+/// Parse a packet with the compact header, like `parse_magic`.
+fn parse_compact<'a>(
+    packet: &'a [u8],
+    psk: Option<&Psk>,
+) -> Result<(PacketHeader<'a>, Cow<'a, [u8]>), MagicError> {
+    let (fixed, data) = take(packet, COMPACT_HEADER_LENGTH)?;
+    let version = fixed[4] as u16;
+    if !VersionRange::SUPPORTED.contains(version) || version < COMPACT_VERSION {
+        return Err(MagicError::InvalidVersion(version));
+    }
+    let flags = fixed[5];
+    let sender = u32::from_be_bytes(fixed[6..10].try_into().unwrap());
+    let mode = flags & 0b11;
+    let authenticated = match mode {
+        MODE_CHECKSUM => false,
+        MODE_AUTHENTICATED | MODE_ENCRYPTED => true,
+        _ => return Err(MagicError::InvalidMagic),
+    };
+    match (authenticated, psk) {
+        (false, Some(_)) => return Err(MagicError::AuthError(AuthError::Missing)),
+        (true, None) => return Err(MagicError::AuthError(AuthError::NoKey)),
+        _ => {}
+    }
+    let (sequence, data) = if flags & FLAG_SEQUENCE != 0 {
+        let (sequence, data) = take(data, 4)?;
+        (Some(u32::from_be_bytes(sequence.try_into().unwrap())), data)
+    } else {
+        (None, data)
+    };
+    let (name, data) = if flags & FLAG_NAME != 0 {
+        let (length, data) = take(data, 1)?;
+        let (name, data) = take(data, length[0] as usize)?;
+        (Some(String::from_utf8_lossy(name)), data)
+    } else {
+        (None, data)
+    };
+    let (timestamp, data) = if authenticated {
+        let (timestamp, data) = take(data, 8)?;
+        (Some(u64::from_be_bytes(timestamp.try_into().unwrap())), data)
+    } else {
+        (None, data)
+    };
+    let header = PacketHeader {
+        sender,
+        name,
+        sequence,
+        version,
+        timestamp,
+    };
 
-/// Make a packet with the magic prefix from the given message
-pub fn make_magic_packet(name: &str, data: &Message) -> Vec<u8> {
-    let data = data.serialize();
+    if mode == MODE_ENCRYPTED {
+        let (salt, data) = take(data, SALT_LENGTH)?;
+        let signed_header = &packet[..packet.len() - data.len()];
+        if data.len() < TAG_LENGTH {
+            return Err(MagicError::LengthMismatch(TAG_LENGTH as u16, data.len() as u16));
+        }
+        let data = psk
+            .unwrap()
+            .decrypt(
+                salt.try_into().unwrap(),
+                timestamp.unwrap(),
+                signed_header,
+                data,
+            )
+            .map_err(MagicError::AuthError)?;
+        return Ok((header, Cow::Owned(data)));
+    }
 
+    // The checksum or MAC is at the end, and covers everything before it
+    let trailer_length = match psk {
+        Some(_) => SHORT_MAC_LENGTH,
+        None => {
+            let algorithm = ChecksumAlgorithm::from_id(flags >> 4)
+                .ok_or(MagicError::UnknownChecksum(flags >> 4))?;
+            algorithm.length()
+        }
+    };
+    if data.len() < trailer_length {
+        return Err(MagicError::LengthMismatch(
+            trailer_length as u16,
+            data.len() as u16,
+        ));
+    }
+    let (signed, trailer) = packet.split_at(packet.len() - trailer_length);
+    let message = &data[..data.len() - trailer_length];
+    match psk {
+        Some(psk) => psk
+            .verify_short(&[signed], trailer)
+            .map_err(MagicError::AuthError)?,
+        None => {
+            let algorithm = ChecksumAlgorithm::from_id(flags >> 4).unwrap();
+            if !algorithm.verify(signed, trailer) {
+                return Err(MagicError::HashMismatch);
+            }
+        }
+    }
+    Ok((header, Cow::Borrowed(message)))
+}
+
+/// Make a packet with the magic prefix from the given message,
+/// encoded for the given protocol version.
+///
+/// If the identity has a pre-shared key, the packet is authenticated with it,
+/// and encrypted if the identity asks for that.
+/// Otherwise, the packet has a checksum with the identity's algorithm.
+///
+/// Returns `None` if the message does not exist in that version.
+pub fn make_magic_packet(identity: &Identity, version: u16, data: &Message) -> Option<Vec<u8>> {
     let mut packet = Vec::new();
+    make_magic_packet_into(identity, version, data, &mut packet)?;
+    Some(packet)
+}
+
+/// Make a packet like `make_magic_packet`, but write it into the given buffer,
+/// replacing its contents.
+///
+/// The message is encoded straight into the packet, and encrypted in place,
+/// so no memory is allocated once the buffer is large enough.
+pub fn make_magic_packet_into(
+    identity: &Identity,
+    version: u16,
+    data: &Message,
+    packet: &mut Vec<u8>,
+) -> Option<()> {
+    packet.clear();
+    if version >= COMPACT_VERSION {
+        return make_compact_packet_into(identity, version, None, data, packet);
+    }
+    let checksum = identity.checksum_for(version);
     // Magic prefix
-    packet.extend("RustUDPs".as_bytes());
-    packet.extend(name.as_bytes());
+    match identity.psk {
+        Some(_) if identity.encrypt => packet.extend(MAGIC_ENCRYPTED),
+        Some(_) => packet.extend(MAGIC_AUTHENTICATED),
+        // SHA-256 packets use the original format, so that old peers can read them
+        None if checksum == ChecksumAlgorithm::Sha256 => packet.extend(MAGIC),
+        None => packet.extend(MAGIC_CHECKSUM),
+    }
+    packet.extend(identity.name.as_bytes());
     packet.push(0);
-    packet.extend(VERSION.to_be_bytes().iter());
-    // Length
-    packet.extend((data.len() as u16).to_be_bytes().iter());
-    // Hash
-    let hash = make_hash(&data);
-    packet.extend(hash);
-    // Data
-    packet.extend(data);
-    packet
+    packet.extend(version.to_be_bytes().iter());
+    // Length, which is filled in once the message is encoded
+    let length_position = packet.len();
+    packet.extend([0, 0]);
+    match &identity.psk {
+        Some(psk) if identity.encrypt => {
+            // Timestamp and salt, which together make the nonce
+            let timestamp = crate::auth::next_timestamp();
+            packet.extend(timestamp.to_be_bytes().iter());
+            let salt = psk.salt();
+            packet.extend(salt);
+            // Then the encrypted data, then the tag
+            let header_length = packet.len();
+            encode_message(data, version, packet, length_position)?;
+            let (header, message) = packet.split_at_mut(header_length);
+            let tag = psk.encrypt_in_place(salt, timestamp, header, message);
+            packet.extend(tag);
+        }
+        Some(psk) => {
+            // Timestamp, then the MAC of everything
+            packet.extend(crate::auth::next_timestamp().to_be_bytes().iter());
+            let header_length = packet.len();
+            let mac_length = std::mem::size_of::<crate::HashType>();
+            packet.resize(header_length + mac_length, 0);
+            encode_message(data, version, packet, length_position)?;
+            let (header, rest) = packet.split_at_mut(header_length);
+            let (mac, message) = rest.split_at_mut(mac_length);
+            mac.copy_from_slice(&psk.mac(&[header, message]));
+        }
+        None => {
+            // Algorithm, unless it's the default, then the checksum
+            if checksum != ChecksumAlgorithm::Sha256 {
+                packet.push(checksum.id());
+            }
+            let header_length = packet.len();
+            packet.resize(header_length + checksum.length(), 0);
+            encode_message(data, version, packet, length_position)?;
+            let (hash, message) = packet[header_length..].split_at_mut(checksum.length());
+            checksum.compute_into(message, hash);
+        }
+    }
+    Some(())
+}
+
+/// Make a packet like `make_magic_packet_into`, with the given sequence number in its header.
+///
+/// Only the compact header has a sequence number:
+/// in older versions, this makes the same packet as `make_magic_packet_into`.
+pub fn make_sequenced_packet_into(
+    identity: &Identity,
+    version: u16,
+    sequence: u32,
+    data: &Message,
+    packet: &mut Vec<u8>,
+) -> Option<()> {
+    if version >= COMPACT_VERSION {
+        packet.clear();
+        return make_compact_packet_into(identity, version, Some(sequence), data, packet);
+    }
+    make_magic_packet_into(identity, version, data, packet)
+}
+
+/// Make a packet with the compact header, like `make_magic_packet_into`.
+fn make_compact_packet_into(
+    identity: &Identity,
+    version: u16,
+    sequence: Option<u32>,
+    data: &Message,
+    packet: &mut Vec<u8>,
+) -> Option<()> {
+    let checksum = identity.checksum_for(version);
+    let mut flags = match identity.psk {
+        Some(_) if identity.encrypt => MODE_ENCRYPTED,
+        Some(_) => MODE_AUTHENTICATED,
+        None => MODE_CHECKSUM | checksum.id() << 4,
+    };
+    let name = carries_name(data).then(|| truncate_name(&identity.name));
+    if name.is_some() {
+        flags |= FLAG_NAME;
+    }
+    if sequence.is_some() {
+        flags |= FLAG_SEQUENCE;
+    }
+    packet.extend(MAGIC_COMPACT);
+    packet.push(version as u8);
+    packet.push(flags);
+    packet.extend(identity.instance.to_be_bytes());
+    if let Some(sequence) = sequence {
+        packet.extend(sequence.to_be_bytes());
+    }
+    if let Some(name) = name {
+        packet.push(name.len() as u8);
+        packet.extend(name.as_bytes());
+    }
+    match &identity.psk {
+        Some(psk) if identity.encrypt => {
+            let timestamp = crate::auth::next_timestamp();
+            packet.extend(timestamp.to_be_bytes());
+            let salt = psk.salt();
+            packet.extend(salt);
+            let header_length = packet.len();
+            data.serialize_into(version, packet)?;
+            let (header, message) = packet.split_at_mut(header_length);
+            let tag = psk.encrypt_in_place(salt, timestamp, header, message);
+            packet.extend(tag);
+        }
+        Some(psk) => {
+            packet.extend(crate::auth::next_timestamp().to_be_bytes());
+            data.serialize_into(version, packet)?;
+            let mac = psk.mac(&[packet.as_slice()]);
+            packet.extend(&mac[..SHORT_MAC_LENGTH]);
+        }
+        None => {
+            data.serialize_into(version, packet)?;
+            let signed_length = packet.len();
+            packet.resize(signed_length + checksum.length(), 0);
+            let (signed, hash) = packet.split_at_mut(signed_length);
+            checksum.compute_into(signed, hash);
+        }
+    }
+    Some(())
+}
+
+/// Cut the name down to the 255 bytes that fit in the compact header, without splitting a character.
+fn truncate_name(name: &str) -> &str {
+    let mut end = name.len().min(u8::MAX as usize);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Encode the message at the end of the packet, and write its length at `length_position`.
+fn encode_message(
+    data: &Message,
+    version: u16,
+    packet: &mut Vec<u8>,
+    length_position: usize,
+) -> Option<()> {
+    let start = packet.len();
+    data.serialize_into(version, packet)?;
+    let length = (packet.len() - start) as u16;
+    packet[length_position..length_position + 2].copy_from_slice(&length.to_be_bytes());
+    Some(())
 }
 
 /// Parse a packet with the magic prefix into a message
-/// Returns the name of the peer and the message
-pub fn parse_magic_packet(data: &[u8]) -> Result<(String, Message), MagicError> {
-    let (name, data) = parse_magic(data)?;
-    let message = Message::deserialize(data).map_err(MagicError::DecodeError)?;
-    Ok((name, message))
+/// Returns the header of the packet and the message
+///
+/// If `psk` is given, only packets authenticated or encrypted with that key are accepted.
+pub fn parse_magic_packet<'a>(
+    data: &'a [u8],
+    psk: Option<&Psk>,
+) -> Result<(PacketHeader<'a>, Message), MagicError> {
+    let (header, data) = parse_magic(data, psk)?;
+    let message = Message::deserialize(header.version, &data)
+        .map_err(MagicError::DecodeError)?
+        .ok_or(MagicError::UnknownMessage)?;
+    Ok((header, message))
+}
+
+/// Parse a packet with the magic prefix into a message, like `parse_magic_packet`,
+/// but take the chunk data of the message out of the packet without copying it.
+pub fn parse_magic_packet_bytes<'a>(
+    packet: &'a Bytes,
+    psk: Option<&Psk>,
+) -> Result<(PacketHeader<'a>, Message), MagicError> {
+    let (header, data) = parse_magic(packet, psk)?;
+    let data = match data {
+        Cow::Borrowed(data) => packet.slice_ref(data),
+        Cow::Owned(data) => Bytes::from(data),
+    };
+    let message = Message::deserialize_bytes(header.version, &data)
+        .map_err(MagicError::DecodeError)?
+        .ok_or(MagicError::UnknownMessage)?;
+    Ok((header, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{FileChunkData, FileListingFragment};
+
+    /// The last version with the old header, and the byte of the header to forge in each version:
+    /// the first letter of the name in the old header, and the instance id in the compact one.
+    const HEADERS: [(u16, usize); 2] = [(COMPACT_VERSION - 1, 8), (VERSION, 6)];
+
+    #[test]
+    fn test_authenticated_packets() {
+        let psk = Psk::from_secret(b"hunter2");
+        let identity = Identity {
+            name: "server".to_string(),
+            instance: 1337,
+            psk: Some(psk.clone()),
+            encrypt: false,
+            checksum: None,
+        };
+        let message = Message::Pong { nonce: 42 };
+        for (version, forged_byte) in HEADERS {
+            let packet = make_magic_packet(&identity, version, &message).unwrap();
+
+            let (header, _) = parse_magic_packet(&packet, Some(&psk)).unwrap();
+            let sender = match version {
+                v if v < COMPACT_VERSION => legacy_instance_id("server"),
+                _ => identity.instance,
+            };
+            assert_eq!(header.sender, sender);
+            assert!(header.timestamp.is_some());
+
+            // Without the key, or with another key, the packet is rejected
+            assert!(matches!(
+                parse_magic_packet(&packet, None),
+                Err(MagicError::AuthError(AuthError::NoKey))
+            ));
+            assert!(matches!(
+                parse_magic_packet(&packet, Some(&Psk::from_secret(b"hunter3"))),
+                Err(MagicError::AuthError(AuthError::BadMac))
+            ));
+
+            // Changing the sender in the header breaks the MAC
+            let mut forged = packet.clone();
+            forged[forged_byte] ^= 0x20;
+            assert!(matches!(
+                parse_magic_packet(&forged, Some(&psk)),
+                Err(MagicError::AuthError(AuthError::BadMac))
+            ));
+
+            // Packets without a MAC are rejected when we have a key
+            let plain = make_magic_packet(&Identity::new("server".to_string()), version, &message).unwrap();
+            assert!(matches!(
+                parse_magic_packet(&plain, Some(&psk)),
+                Err(MagicError::AuthError(AuthError::Missing))
+            ));
+            assert!(parse_magic_packet(&plain, None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_encrypted_packets() {
+        let psk = Psk::from_secret(b"hunter2");
+        let identity = Identity {
+            name: "server".to_string(),
+            instance: 1337,
+            psk: Some(psk.clone()),
+            encrypt: true,
+            checksum: None,
+        };
+        let message = Message::FileListing(FileListingFragment {
+            idx: 0,
+            total: 1,
+            path: b"confidential.txt".to_vec(),
+            size: 123,
+            hash: [0; 32],
+            chunk_size: 1000,
+            chunk_hashes: false,
+        });
+        for (version, forged_byte) in HEADERS {
+            let packet = make_magic_packet(&identity, version, &message).unwrap();
+            assert!(!packet
+                .windows(b"confidential".len())
+                .any(|w| w == b"confidential"));
+
+            // A receiver with the same key decrypts it, even if it does not encrypt itself
+            let (header, decoded) = parse_magic_packet(&packet, Some(&Psk::from_secret(b"hunter2"))).unwrap();
+            let sender = match version {
+                v if v < COMPACT_VERSION => legacy_instance_id("server"),
+                _ => identity.instance,
+            };
+            assert_eq!(header.sender, sender);
+            assert!(matches!(decoded, Message::FileListing(fragment) if fragment.path == b"confidential.txt"));
+
+            assert!(matches!(
+                parse_magic_packet(&packet, None),
+                Err(MagicError::AuthError(AuthError::NoKey))
+            ));
+            assert!(matches!(
+                parse_magic_packet(&packet, Some(&Psk::from_secret(b"hunter3"))),
+                Err(MagicError::AuthError(AuthError::BadMac))
+            ));
+
+            // The header is authenticated too
+            let mut forged = packet.clone();
+            forged[forged_byte] ^= 0x20;
+            assert!(matches!(
+                parse_magic_packet(&forged, Some(&psk)),
+                Err(MagicError::AuthError(AuthError::BadMac))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_checksum_algorithms() {
+        let version = COMPACT_VERSION - 1;
+        let message = Message::Pong { nonce: 42 };
+        let sha256 = make_magic_packet(&Identity::new("server".to_string()), version, &message).unwrap();
+        assert_eq!(&sha256[..8], MAGIC);
+
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Xxh3] {
+            let identity = Identity {
+                checksum: Some(algorithm),
+                ..Identity::new("server".to_string())
+            };
+            let packet = make_magic_packet(&identity, version, &message).unwrap();
+            assert_eq!(&packet[..8], MAGIC_CHECKSUM);
+            // The header is shorter than with SHA-256
+            assert_eq!(packet.len(), sha256.len() + 1 + algorithm.length() - 32);
+            assert!(parse_magic_packet(&packet, None).is_ok());
+
+            // Corruption is detected
+            let mut corrupted = packet.clone();
+            *corrupted.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                parse_magic_packet(&corrupted, None),
+                Err(MagicError::HashMismatch)
+            ));
+        }
+
+        // Unknown algorithms are rejected
+        let identity = Identity {
+            checksum: Some(ChecksumAlgorithm::Crc32c),
+            ..Identity::new("server".to_string())
+        };
+        let mut packet = make_magic_packet(&identity, version, &message).unwrap();
+        packet[8 + "server".len() + 5] = 200;
+        assert!(matches!(
+            parse_magic_packet(&packet, None),
+            Err(MagicError::UnknownChecksum(200))
+        ));
+    }
+
+    #[test]
+    fn test_compact_checksums() {
+        let message = Message::Pong { nonce: 42 };
+        for algorithm in [None, Some(ChecksumAlgorithm::Sha256), Some(ChecksumAlgorithm::Xxh3)] {
+            let identity = Identity {
+                checksum: algorithm,
+                ..Identity::new("server".to_string())
+            };
+            let packet = make_magic_packet(&identity, VERSION, &message).unwrap();
+            assert_eq!(&packet[..4], MAGIC_COMPACT);
+            let (header, decoded) = parse_magic_packet(&packet, None).unwrap();
+            assert_eq!(header.version, VERSION);
+            assert!(matches!(decoded, Message::Pong { nonce: 42 }));
+
+            // Corruption of the header or the message is detected
+            for byte in [6, packet.len() - 1] {
+                let mut corrupted = packet.clone();
+                corrupted[byte] ^= 1;
+                assert!(matches!(
+                    parse_magic_packet(&corrupted, None),
+                    Err(MagicError::HashMismatch)
+                ));
+            }
+            // So is truncation
+            assert!(parse_magic_packet(&packet[..packet.len() - 1], None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_compact_names() {
+        let identity = Identity::new("server".to_string());
+        let announce = Message::Announce {
+            port: 1337,
+            versions: VersionRange::SUPPORTED,
+        };
+        let packet = make_magic_packet(&identity, VERSION, &announce).unwrap();
+        let (header, _) = parse_magic_packet(&packet, None).unwrap();
+        assert_eq!(header.name.as_deref(), Some("server"));
+        assert_eq!(header.sender, identity.instance);
+
+        let packet = make_magic_packet(&identity, VERSION, &Message::Pong { nonce: 42 }).unwrap();
+        let (header, _) = parse_magic_packet(&packet, None).unwrap();
+        assert_eq!(header.name, None);
+        assert_eq!(header.sender, identity.instance);
+
+        // Names that are too long are cut short at a character boundary
+        let identity = Identity::new("ё".repeat(200));
+        let packet = make_magic_packet(&identity, VERSION, &announce).unwrap();
+        let (header, _) = parse_magic_packet(&packet, None).unwrap();
+        assert_eq!(header.name.unwrap(), "ё".repeat(127));
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let identity = Identity::new("server".to_string());
+        let message = Message::Pong { nonce: 42 };
+        let mut packet = Vec::new();
+        make_sequenced_packet_into(&identity, VERSION, 1337, &message, &mut packet).unwrap();
+        let (header, _) = parse_magic_packet(&packet, None).unwrap();
+        assert_eq!(header.sequence, Some(1337));
+        let unsequenced = make_magic_packet(&identity, VERSION, &message).unwrap();
+        assert_eq!(packet.len(), unsequenced.len() + 4);
+        assert_eq!(parse_magic_packet(&unsequenced, None).unwrap().0.sequence, None);
+
+        // The old header has no room for it
+        make_sequenced_packet_into(&identity, COMPACT_VERSION - 1, 1337, &message, &mut packet).unwrap();
+        assert_eq!(parse_magic_packet(&packet, None).unwrap().0.sequence, None);
+    }
+
+    #[test]
+    fn test_compact_overhead() {
+        let pong = Message::Pong { nonce: 42 };
+        let chunk = Message::FileChunk(FileChunkData {
+            idx: 3,
+            chunk: 1000,
+            data: vec![0xff; 1024].into(),
+        });
+        for message in [&pong, &chunk] {
+            let length = message.serialize(VERSION).unwrap().len();
+            let plain = make_magic_packet(&Identity::new("server".to_string()), VERSION, message).unwrap();
+            assert_eq!(plain.len(), length + COMPACT_OVERHEAD);
+            let psk = Some(Psk::from_secret(b"hunter2"));
+            let authenticated = Identity {
+                psk: psk.clone(),
+                ..Identity::new("server".to_string())
+            };
+            let packet = make_magic_packet(&authenticated, VERSION, message).unwrap();
+            assert_eq!(packet.len(), length + 34);
+            let encrypted = Identity {
+                psk,
+                encrypt: true,
+                ..Identity::new("server".to_string())
+            };
+            let packet = make_magic_packet(&encrypted, VERSION, message).unwrap();
+            assert_eq!(packet.len(), length + 38);
+        }
+
+        // A chunk is its data, plus at most 19 bytes for its indices and lengths, plus the header
+        let packet = make_magic_packet(&Identity::new("server".to_string()), VERSION, &chunk).unwrap();
+        assert_eq!(packet.len(), 1024 + 9 + COMPACT_OVERHEAD);
+        let largest = Message::FileChunk(FileChunkData {
+            idx: u32::MAX,
+            chunk: u64::MAX,
+            data: vec![0xff; 1024].into(),
+        });
+        let largest = make_magic_packet(&Identity::new("server".to_string()), VERSION, &largest).unwrap();
+        assert_eq!(largest.len(), 1024 + 19 + COMPACT_OVERHEAD);
+        match parse_magic_packet(&packet, None).unwrap().1 {
+            Message::FileChunk(decoded) => assert_eq!(decoded.data, vec![0xff; 1024]),
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+        // The old format spends two bytes on each of these bytes
+        let old = make_magic_packet(&Identity::new("server".to_string()), COMPACT_VERSION - 1, &chunk).unwrap();
+        assert!(old.len() > 2 * 1024);
+    }
 }
@@ -1,7 +1,27 @@
 /// Module for network messages
+///
+/// Messages are encoded with MessagePack.
+/// Up to version 3, a message is an array that starts with the name of its type,
+/// and byte strings are arrays of integers, so that bytes from 128 up take two bytes each.
+/// From version 4 on, a message is a flat array that starts with the numeric tag of its type,
+/// followed by its fields, and byte strings are `bin` objects: see the `compact` module.
+///
+/// The compact encoding can be extended without a new protocol version.
+/// A message with a tag that we don't know is skipped, and so are fields after the ones that we know,
+/// so newer peers can add messages, and optional fields at the end of existing ones.
+/// Both are counted, in `unknown_messages` and `unknown_fields`.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
-use crate::DecodeError;
+use crate::{
+    capabilities::Capabilities,
+    compression::Compression,
+    magic::{VersionRange, COMPACT_VERSION},
+    sequence::SequenceGap,
+    DecodeError,
+};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum JoinReason {
@@ -27,16 +47,40 @@ pub enum Message {
     Announce {
         /// The port that the server is listening on for return communications.
         port: u16,
+        /// The protocol versions that the server supports.
+        /// The server sends an `Announce` in every one of these versions.
+        versions: VersionRange,
     },
 
     /// A request by the client to join a server.
     ///
     /// The IP address is implied by the UDP packet.
     /// The port is implied: it is the port that the server used to reach the client.
-    JoinQuery {},
+    ///
+    /// The client sends this in the highest version that both it and the server support.
+    JoinQuery {
+        /// The protocol versions that the client supports.
+        versions: VersionRange,
+        /// The optional features that the client supports.
+        ///
+        /// Only in the compact encoding: a client that does not send them
+        /// has the capabilities implied by its version.
+        #[serde(skip)]
+        capabilities: Option<Capabilities>,
+    },
 
     /// A response to a `JoinQuery`.
-    JoinResponse(JoinReason),
+    JoinResponse {
+        reason: JoinReason,
+        /// The protocol versions that the server supports.
+        /// Both sides use the highest version that they have in common.
+        versions: VersionRange,
+        /// The optional features that the server supports.
+        ///
+        /// Only in the compact encoding, like in `JoinQuery`.
+        #[serde(skip)]
+        capabilities: Option<Capabilities>,
+    },
 
     /// A ping request. Whoever sends this expects a `Pong` in response.
     Ping {
@@ -46,7 +90,17 @@ pub enum Message {
         /// since the last time that the peer sent a `Ping`.
         /// The other peer should compare this to the number of messages that it sent,
         /// and adjust its rate-limiting accordingly.
+        ///
+        /// If `lost` is given, this is only the messages from the other peer,
+        /// and does not count duplicates.
         recvs: u64,
+        /// The gaps in the sequence numbers of the other peer's packets
+        /// since the last time that the peer sent a `Ping`, if its packets have them.
+        /// Then `recvs` and the lengths of the gaps add up to the number of packets that it sent.
+        ///
+        /// Only in the compact encoding: older versions have no sequence numbers.
+        #[serde(skip)]
+        lost: Option<Vec<SequenceGap>>,
     },
 
     /// A response to a `Ping`.
@@ -80,20 +134,564 @@ pub enum Message {
     /// The server sends this to the client.
     FileChunk(FileChunkData),
 
+    /// A chunk of a file, compressed.
+    /// The server sends this instead of a `FileChunk` when compression makes the chunk smaller.
+    FileChunkCompressed(CompressedFileChunkData),
+
+    /// A repair shard for a block of chunks of a file.
+    /// The server sends these after the chunks of the block,
+    /// so that the client can rebuild chunks that it missed.
+    FileChunkRepair(FileChunkRepairData),
+
     /// A disconnect message.
     /// The client sends this to inform the server that it is no longer listening.
     Disconnect(DisconnectReason),
+
+    /// A part of a `FileListing` that is too large for one datagram.
+    /// The server sends these in place of the `FileListing` to clients that can put them together:
+    /// see the `listing` module.
+    FileListingPart(FileListingPartData),
+
+    /// A request by the client for the hashes of the chunks of a file, from the given chunk on.
+    /// The server responds with a `ChunkHashes` message, if it has the hashes of the file's chunks.
+    ChunkHashesRequest {
+        /// The file index.
+        idx: u32,
+        /// The index of the first chunk to send the hash of.
+        first_chunk: u64,
+    },
+
+    /// The SHA-256 hashes of some consecutive chunks of a file, which the client checks the chunks against,
+    /// and their rolling checksums, which let the client find the chunks in an old copy of the file.
+    /// The server broadcasts these ahead of the chunks, and sends them when asked.
+    ChunkHashes(ChunkHashesData),
+
+    /// The chunks of a file that the client took from its old copy of the file, so that it doesn't need them.
+    /// The server leaves the chunks that every joined client took out of its broadcasts.
+    ///
+    /// The client sends this every so often while it downloads the file, and each one replaces the last,
+    /// so that a lost one only means that the server broadcasts more than it needs to.
+    /// Only sent to servers with the `DELTA` capability.
+    CopiedChunks {
+        /// The file index.
+        idx: u32,
+        /// The runs of chunks, in order.
+        ranges: Vec<ChunkRange>,
+    },
 }
 
 impl Message {
-    /// Serialize a message to a byte array.
-    pub fn serialize(&self) -> Vec<u8> {
-        rmp_serde::to_vec(self).unwrap()
+    /// The oldest protocol version that can carry this message.
+    pub fn min_version(&self) -> u16 {
+        match self {
+            Message::FileChunkRepair(_) => 2,
+            Message::FileChunkCompressed(_) => 3,
+            Message::FileListingPart(_)
+            | Message::ChunkHashesRequest { .. }
+            | Message::ChunkHashes(_)
+            | Message::CopiedChunks { .. } => COMPACT_VERSION,
+            _ => 1,
+        }
+    }
+
+    /// Serialize a message to a byte array, in the format of the given protocol version.
+    ///
+    /// Returns `None` if the message does not exist in that version.
+    pub fn serialize(&self, version: u16) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        self.serialize_into(version, &mut data)?;
+        Some(data)
+    }
+
+    /// Serialize a message to the end of the buffer, like `serialize`.
+    /// This lets the caller reuse the buffer, and put a header in front of the message.
+    ///
+    /// Returns `None`, leaving the buffer unchanged, if the message does not exist in that version.
+    pub fn serialize_into(&self, version: u16, buf: &mut Vec<u8>) -> Option<()> {
+        if version < self.min_version() {
+            return None;
+        }
+        match version {
+            1 => rmp_serde::encode::write(buf, &MessageV1::from_current(self)?).unwrap(),
+            v if v < COMPACT_VERSION => rmp_serde::encode::write(buf, self).unwrap(),
+            _ => rmp_serde::encode::write(buf, &compact::Encode(self)).unwrap(),
+        }
+        Some(())
+    }
+
+    /// Deserialize a message from a byte array, in the format of the given protocol version.
+    ///
+    /// Returns `Ok(None)` for a message of a type that we don't know, which a newer peer may send:
+    /// it should be ignored. Only the compact encoding can tell these apart from invalid messages.
+    pub fn deserialize(version: u16, data: &[u8]) -> Result<Option<Self>, DecodeError> {
+        match version {
+            1 => rmp_serde::from_slice::<MessageV1>(data).map(|message| Some(message.into_current())),
+            v if v < COMPACT_VERSION => rmp_serde::from_slice(data).map(Some),
+            _ => rmp_serde::from_slice::<compact::Decode>(data).map(|decoded| decoded.0),
+        }
+    }
+
+    /// Deserialize a message from a packet buffer, like `deserialize`.
+    ///
+    /// In the compact encoding, chunk data, listing parts and chunk hashes are slices of `data`,
+    /// rather than copies of it. The older encodings store bytes in a way that has to be copied.
+    pub fn deserialize_bytes(version: u16, data: &Bytes) -> Result<Option<Self>, DecodeError> {
+        match version {
+            v if v < COMPACT_VERSION => Self::deserialize(version, data),
+            _ => compact::decode_from(data),
+        }
+    }
+}
+
+/// The number of messages of types that we don't know, in all listeners of this process.
+static UNKNOWN_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of fields that we don't know, at the end of messages that we do.
+static UNKNOWN_FIELDS: AtomicU64 = AtomicU64::new(0);
+
+/// Get the number of messages that were skipped, because we don't know their types.
+///
+/// These come from peers with a newer version of the compact encoding than ours.
+pub fn unknown_messages() -> u64 {
+    UNKNOWN_MESSAGES.load(Ordering::Relaxed)
+}
+
+/// Get the number of optional fields that were skipped, because we don't know them.
+pub fn unknown_fields() -> u64 {
+    UNKNOWN_FIELDS.load(Ordering::Relaxed)
+}
+
+/// The messages of version 1 of the protocol.
+///
+/// This is kept so that we can still talk to peers that only support version 1.
+/// Do not change it: add new fields and messages to `Message` instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+enum MessageV1 {
+    Announce { port: u16 },
+    JoinQuery {},
+    JoinResponse(JoinReason),
+    Ping { nonce: u64, recvs: u64 },
+    Pong { nonce: u64 },
+    FileListing(FileListingFragment),
+    FileListingRequest { idx: u32 },
+    FileChunkRequest { idx: u32, chunk: u64 },
+    FileChunk(FileChunkData),
+    Disconnect(DisconnectReason),
+}
+
+impl MessageV1 {
+    /// Convert a message to its version 1 form, if there is one.
+    fn from_current(message: &Message) -> Option<Self> {
+        Some(match message.clone() {
+            Message::Announce { port, .. } => MessageV1::Announce { port },
+            Message::JoinQuery { .. } => MessageV1::JoinQuery {},
+            Message::JoinResponse { reason, .. } => MessageV1::JoinResponse(reason),
+            Message::Ping { nonce, recvs, .. } => MessageV1::Ping { nonce, recvs },
+            Message::Pong { nonce } => MessageV1::Pong { nonce },
+            Message::FileListing(listing) => MessageV1::FileListing(listing),
+            Message::FileListingRequest { idx } => MessageV1::FileListingRequest { idx },
+            Message::FileChunkRequest { idx, chunk } => MessageV1::FileChunkRequest { idx, chunk },
+            Message::FileChunk(chunk) => MessageV1::FileChunk(chunk),
+            Message::FileChunkRepair(_)
+            | Message::FileChunkCompressed(_)
+            | Message::FileListingPart(_)
+            | Message::ChunkHashesRequest { .. }
+            | Message::ChunkHashes(_)
+            | Message::CopiedChunks { .. } => return None,
+            Message::Disconnect(reason) => MessageV1::Disconnect(reason),
+        })
+    }
+
+    /// Convert a version 1 message to the current form.
+    /// A peer that sends version 1 messages only supports version 1.
+    fn into_current(self) -> Message {
+        let versions = VersionRange::only(1);
+        match self {
+            MessageV1::Announce { port } => Message::Announce { port, versions },
+            MessageV1::JoinQuery {} => Message::JoinQuery {
+                versions,
+                capabilities: None,
+            },
+            MessageV1::JoinResponse(reason) => Message::JoinResponse {
+                reason,
+                versions,
+                capabilities: None,
+            },
+            MessageV1::Ping { nonce, recvs } => Message::Ping {
+                nonce,
+                recvs,
+                lost: None,
+            },
+            MessageV1::Pong { nonce } => Message::Pong { nonce },
+            MessageV1::FileListing(listing) => Message::FileListing(listing),
+            MessageV1::FileListingRequest { idx } => Message::FileListingRequest { idx },
+            MessageV1::FileChunkRequest { idx, chunk } => Message::FileChunkRequest { idx, chunk },
+            MessageV1::FileChunk(chunk) => Message::FileChunk(chunk),
+            MessageV1::Disconnect(reason) => Message::Disconnect(reason),
+        }
+    }
+}
+
+/// The compact encoding of messages, from version 4 on.
+///
+/// A message is a MessagePack array of its tag and then its fields, in the order that they are declared,
+/// with nested structs and version ranges flattened into it.
+/// Chunk data and hashes are `bin` objects, and enums are numbered.
+/// A `FileChunk` with 256 bytes of data or more is 8 to 19 bytes longer than its data,
+/// depending on the size of its indices.
+///
+/// New fields go at the end of a message, and must be optional:
+/// older peers skip them, and newer peers decode them with `next_optional`,
+/// so that they are `None` when an older peer leaves them out.
+/// New messages get new tags, and older peers skip them.
+/// Never change the meaning of an existing tag or field.
+mod compact {
+    use bytes::Bytes;
+    use serde::{
+        de::{self, DeserializeSeed, IgnoredAny, SeqAccess, Visitor},
+        Deserialize, Deserializer, Serialize, Serializer,
+    };
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    const ANNOUNCE: u8 = 0;
+    const JOIN_QUERY: u8 = 1;
+    const JOIN_RESPONSE: u8 = 2;
+    const PING: u8 = 3;
+    const PONG: u8 = 4;
+    const FILE_LISTING: u8 = 5;
+    const FILE_LISTING_REQUEST: u8 = 6;
+    const FILE_CHUNK_REQUEST: u8 = 7;
+    const FILE_CHUNK: u8 = 8;
+    const FILE_CHUNK_COMPRESSED: u8 = 9;
+    const FILE_CHUNK_REPAIR: u8 = 10;
+    const DISCONNECT: u8 = 11;
+    const FILE_LISTING_PART: u8 = 12;
+    const CHUNK_HASHES_REQUEST: u8 = 13;
+    const CHUNK_HASHES: u8 = 14;
+    const COPIED_CHUNKS: u8 = 15;
+
+    /// A message to encode in the compact encoding.
+    pub struct Encode<'a>(pub &'a Message);
+
+    /// A path to encode, as a string if it can be.
+    struct Path<'a>(&'a [u8]);
+
+    impl Serialize for Path<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            crate::paths::str_or_bytes::serialize(self.0, serializer)
+        }
+    }
+
+    /// A message decoded from the compact encoding,
+    /// or `None` if it has a tag that we don't know.
+    pub struct Decode(pub Option<Message>);
+
+    impl Serialize for Encode<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde_bytes::Bytes as Bin;
+            match self.0 {
+                Message::Announce { port, versions } => {
+                    (ANNOUNCE, port, versions.min, versions.max).serialize(serializer)
+                }
+                Message::JoinQuery {
+                    versions,
+                    capabilities,
+                } => (JOIN_QUERY, versions.min, versions.max, capabilities).serialize(serializer),
+                Message::JoinResponse {
+                    reason,
+                    versions,
+                    capabilities,
+                } => {
+                    let reason = match reason {
+                        JoinReason::Accepted => 0u8,
+                        JoinReason::WrongName => 1,
+                    };
+                    (JOIN_RESPONSE, reason, versions.min, versions.max, capabilities)
+                        .serialize(serializer)
+                }
+                Message::Ping { nonce, recvs, lost } => {
+                    (PING, nonce, recvs, lost).serialize(serializer)
+                }
+                Message::Pong { nonce } => (PONG, nonce).serialize(serializer),
+                Message::FileListing(listing) => (
+                    FILE_LISTING,
+                    listing.idx,
+                    listing.total,
+                    Path(&listing.path),
+                    listing.size,
+                    Bin::new(&listing.hash),
+                    listing.chunk_size,
+                    listing.chunk_hashes,
+                )
+                    .serialize(serializer),
+                Message::FileListingRequest { idx } => {
+                    (FILE_LISTING_REQUEST, idx).serialize(serializer)
+                }
+                Message::FileChunkRequest { idx, chunk } => {
+                    (FILE_CHUNK_REQUEST, idx, chunk).serialize(serializer)
+                }
+                Message::FileChunk(chunk) => {
+                    (FILE_CHUNK, chunk.idx, chunk.chunk, Bin::new(&chunk.data)).serialize(serializer)
+                }
+                Message::FileChunkCompressed(chunk) => (
+                    FILE_CHUNK_COMPRESSED,
+                    chunk.idx,
+                    chunk.chunk,
+                    chunk.compression.id(),
+                    Bin::new(&chunk.data),
+                )
+                    .serialize(serializer),
+                Message::FileChunkRepair(repair) => (
+                    FILE_CHUNK_REPAIR,
+                    repair.idx,
+                    repair.first_chunk,
+                    repair.data_shards,
+                    repair.parity_shards,
+                    repair.shard,
+                    Bin::new(&repair.data),
+                )
+                    .serialize(serializer),
+                Message::Disconnect(reason) => {
+                    let reason = match reason {
+                        DisconnectReason::Done => 0u8,
+                    };
+                    (DISCONNECT, reason).serialize(serializer)
+                }
+                Message::FileListingPart(part) => (
+                    FILE_LISTING_PART,
+                    part.idx,
+                    part.part,
+                    part.parts,
+                    Bin::new(&part.data),
+                )
+                    .serialize(serializer),
+                Message::ChunkHashesRequest { idx, first_chunk } => {
+                    (CHUNK_HASHES_REQUEST, idx, first_chunk).serialize(serializer)
+                }
+                Message::ChunkHashes(hashes) => (
+                    CHUNK_HASHES,
+                    hashes.idx,
+                    hashes.first_chunk,
+                    Bin::new(&hashes.hashes),
+                    hashes.checksums.as_deref().map(Bin::new),
+                )
+                    .serialize(serializer),
+                Message::CopiedChunks { idx, ranges } => {
+                    (COPIED_CHUNKS, idx, ranges).serialize(serializer)
+                }
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Decode {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_seq(MessageVisitor { packet: None })
+        }
+    }
+
+    /// Decode a message from a packet buffer, slicing its bytes out of the buffer.
+    pub fn decode_from(packet: &Bytes) -> Result<Option<Message>, DecodeError> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(packet.as_ref());
+        let decoded = (&mut deserializer).deserialize_seq(MessageVisitor {
+            packet: Some(packet),
+        })?;
+        Ok(decoded.0)
+    }
+
+    struct MessageVisitor<'a> {
+        /// The buffer that the message is decoded from, if we have it in `Bytes`.
+        packet: Option<&'a Bytes>,
+    }
+
+    impl<'de> Visitor<'de> for MessageVisitor<'_> {
+        type Value = Decode;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("an array of a message tag and its fields")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Decode, A::Error> {
+            let tag: u8 = next(&mut seq)?;
+            let message = match tag {
+                ANNOUNCE => Message::Announce {
+                    port: next(&mut seq)?,
+                    versions: next_versions(&mut seq)?,
+                },
+                JOIN_QUERY => Message::JoinQuery {
+                    versions: next_versions(&mut seq)?,
+                    capabilities: next_optional(&mut seq)?,
+                },
+                JOIN_RESPONSE => Message::JoinResponse {
+                    reason: match next::<_, u8>(&mut seq)? {
+                        0 => JoinReason::Accepted,
+                        1 => JoinReason::WrongName,
+                        other => return Err(de::Error::custom(format!("unknown join reason {other}"))),
+                    },
+                    versions: next_versions(&mut seq)?,
+                    capabilities: next_optional(&mut seq)?,
+                },
+                PING => Message::Ping {
+                    nonce: next(&mut seq)?,
+                    recvs: next(&mut seq)?,
+                    lost: next_optional(&mut seq)?,
+                },
+                PONG => Message::Pong {
+                    nonce: next(&mut seq)?,
+                },
+                FILE_LISTING => Message::FileListing(FileListingFragment {
+                    idx: next(&mut seq)?,
+                    total: next(&mut seq)?,
+                    path: next::<_, ByteBuf>(&mut seq)?.into_vec(),
+                    size: next(&mut seq)?,
+                    hash: next::<_, ByteBuf>(&mut seq)?
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| de::Error::custom("the hash must be 32 bytes long"))?,
+                    chunk_size: next(&mut seq)?,
+                    chunk_hashes: next_optional(&mut seq)?.unwrap_or(false),
+                }),
+                FILE_LISTING_REQUEST => Message::FileListingRequest {
+                    idx: next(&mut seq)?,
+                },
+                FILE_CHUNK_REQUEST => Message::FileChunkRequest {
+                    idx: next(&mut seq)?,
+                    chunk: next(&mut seq)?,
+                },
+                FILE_CHUNK => Message::FileChunk(FileChunkData {
+                    idx: next(&mut seq)?,
+                    chunk: next(&mut seq)?,
+                    data: next_bytes(&mut seq, self.packet)?,
+                }),
+                FILE_CHUNK_COMPRESSED => Message::FileChunkCompressed(CompressedFileChunkData {
+                    idx: next(&mut seq)?,
+                    chunk: next(&mut seq)?,
+                    compression: {
+                        let id: u8 = next(&mut seq)?;
+                        Compression::from_id(id).ok_or_else(|| {
+                            de::Error::custom(format!("unknown compression algorithm {id}"))
+                        })?
+                    },
+                    data: next_bytes(&mut seq, self.packet)?,
+                }),
+                FILE_CHUNK_REPAIR => Message::FileChunkRepair(FileChunkRepairData {
+                    idx: next(&mut seq)?,
+                    first_chunk: next(&mut seq)?,
+                    data_shards: next(&mut seq)?,
+                    parity_shards: next(&mut seq)?,
+                    shard: next(&mut seq)?,
+                    data: next_bytes(&mut seq, self.packet)?,
+                }),
+                DISCONNECT => Message::Disconnect(match next::<_, u8>(&mut seq)? {
+                    0 => DisconnectReason::Done,
+                    other => {
+                        return Err(de::Error::custom(format!("unknown disconnect reason {other}")))
+                    }
+                }),
+                FILE_LISTING_PART => Message::FileListingPart(FileListingPartData {
+                    idx: next(&mut seq)?,
+                    part: next(&mut seq)?,
+                    parts: next(&mut seq)?,
+                    data: next_bytes(&mut seq, self.packet)?,
+                }),
+                CHUNK_HASHES_REQUEST => Message::ChunkHashesRequest {
+                    idx: next(&mut seq)?,
+                    first_chunk: next(&mut seq)?,
+                },
+                CHUNK_HASHES => Message::ChunkHashes(ChunkHashesData {
+                    idx: next(&mut seq)?,
+                    first_chunk: next(&mut seq)?,
+                    hashes: next_bytes(&mut seq, self.packet)?,
+                    checksums: next_optional::<_, ByteBuf>(&mut seq)?
+                        .map(|checksums| Bytes::from(checksums.into_vec())),
+                }),
+                COPIED_CHUNKS => Message::CopiedChunks {
+                    idx: next(&mut seq)?,
+                    ranges: next(&mut seq)?,
+                },
+                _ => {
+                    // A message from a newer peer: skip the whole of it
+                    while seq.next_element::<IgnoredAny>()?.is_some() {}
+                    UNKNOWN_MESSAGES.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Decode(None));
+                }
+            };
+            // Fields from a newer peer
+            let mut unknown_fields = 0;
+            while seq.next_element::<IgnoredAny>()?.is_some() {
+                unknown_fields += 1;
+            }
+            if unknown_fields > 0 {
+                UNKNOWN_FIELDS.fetch_add(unknown_fields, Ordering::Relaxed);
+            }
+            Ok(Decode(Some(message)))
+        }
     }
 
-    /// Deserialize a message from a byte array.
-    pub fn deserialize(data: &[u8]) -> Result<Self, DecodeError> {
-        rmp_serde::from_slice(data)
+    /// The next field of the message, which must be there.
+    fn next<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(seq: &mut A) -> Result<T, A::Error> {
+        seq.next_element()?
+            .ok_or_else(|| de::Error::custom("the message has too few fields"))
+    }
+
+    /// The next field of the message, which older peers may leave out, or send as nil.
+    fn next_optional<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(
+        seq: &mut A,
+    ) -> Result<Option<T>, A::Error> {
+        Ok(seq.next_element::<Option<T>>()?.flatten())
+    }
+
+    fn next_versions<'de, A: SeqAccess<'de>>(seq: &mut A) -> Result<VersionRange, A::Error> {
+        Ok(VersionRange {
+            min: next(seq)?,
+            max: next(seq)?,
+        })
+    }
+
+    /// The next field of the message, which must be a `bin` object.
+    /// It is a slice of `packet` if the message is decoded from it, and a copy otherwise.
+    fn next_bytes<'de, A: SeqAccess<'de>>(
+        seq: &mut A,
+        packet: Option<&Bytes>,
+    ) -> Result<Bytes, A::Error> {
+        seq.next_element_seed(BytesSeed(packet))?
+            .ok_or_else(|| de::Error::custom("the message has too few fields"))
+    }
+
+    struct BytesSeed<'a>(Option<&'a Bytes>);
+
+    impl<'de> DeserializeSeed<'de> for BytesSeed<'_> {
+        type Value = Bytes;
+
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Bytes, D::Error> {
+            deserializer.deserialize_bytes(self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for BytesSeed<'_> {
+        type Value = Bytes;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a byte array")
+        }
+
+        fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Bytes, E> {
+            Ok(match self.0 {
+                // The bytes are borrowed from the packet that we are decoding
+                Some(packet) => packet.slice_ref(v),
+                None => Bytes::copy_from_slice(v),
+            })
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+            Ok(Bytes::copy_from_slice(v))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
     }
 }
 
@@ -103,14 +701,28 @@ pub struct FileListingFragment {
     pub idx: u32,
     /// The total number of fragments in the file listing.
     pub total: u32,
-    /// The path of the file.
-    pub path: String,
+    /// The path of the file, relative to the directory being transferred: see the `paths` module.
+    #[serde(with = "crate::paths::str_or_bytes")]
+    pub path: Vec<u8>,
     /// The size of the file in bytes.
     pub size: u64, // 16 exabytes
     /// The SHA-256 hash of the file.
     pub hash: [u8; 32],
     /// The size of chunks that the file is split into.
     pub chunk_size: u16, // Up to 64KB (jumbo packet size)
+    /// Whether the server has the hashes of the file's chunks, and sends them in `ChunkHashes` messages.
+    ///
+    /// Only in the compact encoding: servers older than it have no chunk hashes.
+    #[serde(skip)]
+    pub chunk_hashes: bool,
+}
+
+impl FileListingFragment {
+    /// The path of the file relative to the directory being transferred, on this platform.
+    /// Fails if the path would leave that directory, or has names that this platform can't store.
+    pub fn local_path(&self) -> Result<std::path::PathBuf, crate::paths::PathError> {
+        crate::paths::decode(&self.path)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -120,5 +732,335 @@ pub struct FileChunkData {
     /// The index of this chunk.
     pub chunk: u64,
     /// The data of this chunk.
-    pub data: Vec<u8>,
+    #[serde(with = "byte_seq")]
+    pub data: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompressedFileChunkData {
+    /// The index of the file that this chunk is part of.
+    pub idx: u32,
+    /// The index of this chunk.
+    pub chunk: u64,
+    /// The algorithm that the data is compressed with.
+    pub compression: Compression,
+    /// The compressed data of this chunk.
+    /// It decompresses to the full chunk size, or to the rest of the file for the last chunk.
+    #[serde(with = "byte_seq")]
+    pub data: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileChunkRepairData {
+    /// The index of the file that this shard is part of.
+    pub idx: u32,
+    /// The index of the first chunk in the block.
+    pub first_chunk: u64,
+    /// The number of chunks in the block.
+    pub data_shards: u16,
+    /// The number of repair shards for the block.
+    pub parity_shards: u16,
+    /// The index of this repair shard, less than `parity_shards`.
+    pub shard: u16,
+    /// The contents of the repair shard.
+    /// This is always as long as the file's chunk size.
+    #[serde(with = "byte_seq")]
+    pub data: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileListingPartData {
+    /// The index of the file listing fragment that this is part of.
+    pub idx: u32,
+    /// The index of this part, less than `parts`.
+    pub part: u16,
+    /// The number of parts that the fragment is split into.
+    pub parts: u16,
+    /// This part of the encoded fragment.
+    #[serde(with = "byte_seq")]
+    pub data: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkHashesData {
+    /// The index of the file that the chunks are part of.
+    pub idx: u32,
+    /// The index of the chunk of the first hash.
+    pub first_chunk: u64,
+    /// The hashes of the chunks from `first_chunk` on, one after the other:
+    /// see the `chunk_hashes` module.
+    #[serde(with = "byte_seq")]
+    pub hashes: Bytes,
+    /// The rolling checksums of the same chunks, one after the other: see the `rolling` module.
+    ///
+    /// Only in the compact encoding, and servers whose hashlists have no checksums leave them out.
+    #[serde(skip)]
+    pub checksums: Option<Bytes>,
+}
+
+/// A run of consecutive chunks of a file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRange {
+    /// The index of the first chunk in the run.
+    pub first: u64,
+    /// The number of chunks in the run.
+    pub count: u64,
+}
+
+impl ChunkRange {
+    /// The index of the chunk after the run.
+    pub fn end(&self) -> u64 {
+        self.first.saturating_add(self.count)
+    }
+}
+
+/// Encoding of chunk data as a sequence of bytes, which is how a `Vec<u8>` is encoded.
+///
+/// Chunk data is kept in `Bytes`, so that it can be shared without copying,
+/// but the wire format must stay the same.
+/// Unlike a `bin` object in the compact encoding, a sequence can't be sliced out of the packet when it is decoded.
+mod byte_seq {
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(data.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(Bytes::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_1_encoding_is_unchanged() {
+        // This is how a version 1 peer encodes an `Announce`
+        let v1_announce = [146, 168, 65, 110, 110, 111, 117, 110, 99, 101, 205, 5, 57];
+        let message = Message::Announce {
+            port: 1337,
+            versions: VersionRange::SUPPORTED,
+        };
+        assert_eq!(message.serialize(1).unwrap(), v1_announce);
+        match Message::deserialize(1, &v1_announce).unwrap().unwrap() {
+            Message::Announce { port, versions } => {
+                assert_eq!(port, 1337);
+                assert_eq!(versions, VersionRange::only(1));
+            }
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_new_messages_are_not_sent_to_old_peers() {
+        let message = Message::FileChunkRepair(FileChunkRepairData {
+            idx: 0,
+            first_chunk: 0,
+            data_shards: 1,
+            parity_shards: 1,
+            shard: 0,
+            data: Bytes::new(),
+        });
+        assert!(message.serialize(1).is_none());
+        assert!(message.serialize(2).is_some());
+
+        let message = Message::FileChunkCompressed(CompressedFileChunkData {
+            idx: 0,
+            chunk: 0,
+            compression: Compression::Zstd,
+            data: Bytes::new(),
+        });
+        assert!(message.serialize(2).is_none());
+        assert!(message.serialize(3).is_some());
+    }
+
+    #[test]
+    fn test_chunk_data_encoding_is_unchanged() {
+        // Chunk data used to be a `Vec<u8>`
+        #[derive(Serialize)]
+        struct OldFileChunkData {
+            idx: u32,
+            chunk: u64,
+            data: Vec<u8>,
+        }
+        let old = OldFileChunkData {
+            idx: 3,
+            chunk: 4,
+            data: vec![0, 1, 127, 128, 255],
+        };
+        let new = FileChunkData {
+            idx: 3,
+            chunk: 4,
+            data: Bytes::from_static(&[0, 1, 127, 128, 255]),
+        };
+        let encoded = rmp_serde::to_vec(&old).unwrap();
+        assert_eq!(rmp_serde::to_vec(&new).unwrap(), encoded);
+        let decoded: FileChunkData = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(decoded.data, new.data);
+    }
+
+    #[test]
+    fn test_compact_encoding_round_trip() {
+        let data = Bytes::from_static(&[0, 1, 127, 128, 255]);
+        let messages = [
+            Message::Announce {
+                port: 1337,
+                versions: VersionRange::SUPPORTED,
+            },
+            Message::JoinQuery {
+                versions: VersionRange::SUPPORTED,
+                capabilities: Some(Capabilities::SUPPORTED),
+            },
+            Message::JoinResponse {
+                reason: JoinReason::WrongName,
+                versions: VersionRange::SUPPORTED,
+                capabilities: None,
+            },
+            Message::Ping {
+                nonce: 1,
+                recvs: 2,
+                lost: Some(vec![SequenceGap { start: 3, length: 4 }]),
+            },
+            Message::Pong { nonce: 3 },
+            Message::FileListing(FileListingFragment {
+                idx: 4,
+                total: 5,
+                path: b"some/\xfffile".to_vec(),
+                size: 6,
+                hash: [7; 32],
+                chunk_size: 8,
+                chunk_hashes: true,
+            }),
+            Message::FileListingRequest { idx: 9 },
+            Message::FileChunkRequest { idx: 10, chunk: 11 },
+            Message::FileChunk(FileChunkData {
+                idx: 12,
+                chunk: 13,
+                data: data.clone(),
+            }),
+            Message::FileChunkCompressed(CompressedFileChunkData {
+                idx: 14,
+                chunk: 15,
+                compression: Compression::Lz4,
+                data: data.clone(),
+            }),
+            Message::FileChunkRepair(FileChunkRepairData {
+                idx: 16,
+                first_chunk: 17,
+                data_shards: 18,
+                parity_shards: 19,
+                shard: 20,
+                data,
+            }),
+            Message::Disconnect(DisconnectReason::Done),
+            Message::FileListingPart(FileListingPartData {
+                idx: 21,
+                part: 22,
+                parts: 23,
+                data: Bytes::from_static(b"part"),
+            }),
+            Message::ChunkHashesRequest {
+                idx: 24,
+                first_chunk: 25,
+            },
+            Message::ChunkHashes(ChunkHashesData {
+                idx: 26,
+                first_chunk: 27,
+                hashes: Bytes::from_static(&[28; 64]),
+                checksums: Some(Bytes::from_static(&[29; 8])),
+            }),
+            Message::CopiedChunks {
+                idx: 30,
+                ranges: vec![ChunkRange { first: 31, count: 32 }],
+            },
+        ];
+        for (tag, message) in messages.iter().enumerate() {
+            let encoded = message.serialize(COMPACT_VERSION).unwrap();
+            // A flat array of the numeric tag and the fields
+            assert_eq!(encoded[1], tag as u8);
+            let decoded = Message::deserialize(COMPACT_VERSION, &encoded).unwrap().unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{message:?}"));
+            let decoded = Message::deserialize_bytes(COMPACT_VERSION, &Bytes::from(encoded))
+                .unwrap()
+                .unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{message:?}"));
+        }
+
+        // Chunk data is a `bin` object: its length, then the bytes as they are
+        let encoded = messages[8].serialize(COMPACT_VERSION).unwrap();
+        assert_eq!(encoded, [0x94, 8, 12, 13, 0xc4, 5, 0, 1, 127, 128, 255]);
+    }
+
+    #[test]
+    fn test_chunk_data_is_sliced_out_of_the_packet() {
+        let message = Message::FileChunk(FileChunkData {
+            idx: 1,
+            chunk: 2,
+            data: Bytes::from_static(&[3; 100]),
+        });
+        let packet = Bytes::from(message.serialize(COMPACT_VERSION).unwrap());
+        match Message::deserialize_bytes(COMPACT_VERSION, &packet).unwrap().unwrap() {
+            Message::FileChunk(chunk) => {
+                assert_eq!(chunk.data, [3; 100][..]);
+                assert_eq!(chunk.data.as_ptr(), packet[packet.len() - 100..].as_ptr());
+            }
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_messages_and_fields_are_skipped() {
+        // A message with a tag from the future, with a nested field
+        let unknown = rmp_serde::to_vec(&(200u8, 1u32, ("nested", [2u8, 3]))).unwrap();
+        let skipped = unknown_messages();
+        assert!(Message::deserialize(COMPACT_VERSION, &unknown).unwrap().is_none());
+        assert!(unknown_messages() > skipped);
+
+        // A `Pong` with two more fields
+        let extended = rmp_serde::to_vec(&(4u8, 5u64, "new", [6u8])).unwrap();
+        let skipped = unknown_fields();
+        match Message::deserialize(COMPACT_VERSION, &extended).unwrap().unwrap() {
+            Message::Pong { nonce } => assert_eq!(nonce, 5),
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+        assert!(unknown_fields() >= skipped + 2);
+
+        // A `JoinQuery` from before capabilities
+        let old = rmp_serde::to_vec(&(1u8, 1u16, 4u16)).unwrap();
+        match Message::deserialize(COMPACT_VERSION, &old).unwrap().unwrap() {
+            Message::JoinQuery {
+                versions,
+                capabilities,
+            } => {
+                assert_eq!(versions, VersionRange { min: 1, max: 4 });
+                assert_eq!(capabilities, None);
+            }
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+
+        // A `Ping` from before sequence numbers
+        let old = rmp_serde::to_vec(&(3u8, 7u64, 42u64)).unwrap();
+        match Message::deserialize(COMPACT_VERSION, &old).unwrap().unwrap() {
+            Message::Ping { nonce, recvs, lost } => {
+                assert_eq!((nonce, recvs), (7, 42));
+                assert_eq!(lost, None);
+            }
+            other => panic!("Decoded the wrong message: {other:?}"),
+        }
+
+        // Broken messages are still errors
+        let truncated = rmp_serde::to_vec(&(7u8, 1u32)).unwrap();
+        assert!(Message::deserialize(COMPACT_VERSION, &truncated).is_err());
+    }
+
+    #[test]
+    fn test_highest_common_version() {
+        let ours = VersionRange { min: 1, max: 3 };
+        assert_eq!(ours.highest_common(&VersionRange::only(1)), Some(1));
+        assert_eq!(ours.highest_common(&VersionRange { min: 2, max: 5 }), Some(3));
+        assert_eq!(ours.highest_common(&VersionRange::only(4)), None);
+    }
 }
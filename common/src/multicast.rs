@@ -0,0 +1,166 @@
+/// Delivery to IP multicast groups, as an alternative to broadcast.
+///
+/// Unlike a broadcast, a multicast packet only reaches the hosts that joined its group,
+/// and can cross routers if the network is set up for it, up to the packet's TTL.
+/// Clients join with IGMP (IPv4) or MLD (IPv6). With source-specific multicast (SSM),
+/// they only receive the group's packets from one server: SSM groups are in 232.0.0.0/8 and ff3x::/32.
+use std::net::{IpAddr, SocketAddr};
+
+use socket2::{InterfaceIndexOrAddress, SockRef};
+use tokio::net::UdpSocket;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// A multicast group to receive from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MulticastGroup {
+    /// The address of the group.
+    pub group: IpAddr,
+    /// The index of the interface to join the group on, or 0 to let the kernel choose.
+    pub interface: u32,
+    /// For source-specific multicast, the only sender to receive from.
+    pub source: Option<IpAddr>,
+}
+
+impl MulticastGroup {
+    /// Parse a group given on the command line, and the source, if any.
+    ///
+    /// The group is parsed like the other addresses, so an IPv6 group can name its interface,
+    /// like `ff12::1337%eth0`.
+    pub fn parse(group: &str, source: Option<&str>) -> Result<Self, String> {
+        let addr = crate::networking::parse_addr(group, 0)?;
+        if !addr.ip().is_multicast() {
+            return Err(format!("{group:?} is not a multicast address"));
+        }
+        let interface = match addr {
+            SocketAddr::V6(addr) => addr.scope_id(),
+            SocketAddr::V4(_) => 0,
+        };
+        let source = match source {
+            Some(source) => {
+                let source = crate::networking::parse_addr(source, 0)?.ip();
+                if source.is_ipv4() != addr.is_ipv4() {
+                    return Err(format!(
+                        "the source {source} and the group {group:?} must both be IPv4 or both IPv6"
+                    ));
+                }
+                Some(source)
+            }
+            None => None,
+        };
+        Ok(Self {
+            group: addr.ip(),
+            interface,
+            source,
+        })
+    }
+
+    /// Join the group on the socket, which must be bound to the unspecified address
+    /// of the same family, or to `[::]` if it is dual-stack.
+    pub fn join(&self, socket: &UdpSocket) -> Result<(), std::io::Error> {
+        match (self.group, self.source) {
+            (IpAddr::V4(group), None) if self.interface == 0 => {
+                socket.join_multicast_v4(group, [0, 0, 0, 0].into())
+            }
+            (IpAddr::V4(group), None) => SockRef::from(socket)
+                .join_multicast_v4_n(&group, &InterfaceIndexOrAddress::Index(self.interface)),
+            (IpAddr::V6(group), None) => socket.join_multicast_v6(&group, self.interface),
+            (_, Some(source)) => join_source_specific(socket, self.group, source, self.interface),
+        }
+    }
+}
+
+impl std::fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.source {
+            Some(source) => write!(f, "({source}, {})", self.group),
+            None => write!(f, "(*, {})", self.group),
+        }
+    }
+}
+
+/// Join a source-specific group with `MCAST_JOIN_SOURCE_GROUP`, which works for IPv4 and IPv6 alike.
+#[cfg(target_os = "linux")]
+fn join_source_specific(
+    socket: &UdpSocket,
+    group: IpAddr,
+    source: IpAddr,
+    interface: u32,
+) -> Result<(), std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `struct group_source_req`, which the `libc` crate does not have.
+    #[repr(C)]
+    struct GroupSourceReq {
+        interface: u32,
+        group: libc::sockaddr_storage,
+        source: libc::sockaddr_storage,
+    }
+
+    fn to_storage(ip: IpAddr) -> libc::sockaddr_storage {
+        let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                addr.as_ptr() as *const u8,
+                &mut storage as *mut _ as *mut u8,
+                addr.len() as usize,
+            )
+        };
+        storage
+    }
+
+    let request = GroupSourceReq {
+        interface,
+        group: to_storage(group),
+        source: to_storage(source),
+    };
+    let level = match group {
+        IpAddr::V4(_) => libc::IPPROTO_IP,
+        IpAddr::V6(_) => libc::IPPROTO_IPV6,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            libc::MCAST_JOIN_SOURCE_GROUP,
+            &request as *const _ as *const libc::c_void,
+            std::mem::size_of::<GroupSourceReq>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn join_source_specific(
+    _socket: &UdpSocket,
+    _group: IpAddr,
+    _source: IpAddr,
+    _interface: u32,
+) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "source-specific multicast is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let group = MulticastGroup::parse("239.255.13.37", None).unwrap();
+        assert_eq!(group.group, IpAddr::from([239, 255, 13, 37]));
+        assert_eq!(group.source, None);
+        let group = MulticastGroup::parse("ff32::8000:1%2", Some("2001:db8::1")).unwrap();
+        assert_eq!(group.interface, 2);
+        assert_eq!(group.source, Some("2001:db8::1".parse().unwrap()));
+        assert!(MulticastGroup::parse("10.0.0.1", None).is_err());
+        assert!(MulticastGroup::parse("232.1.1.1", Some("2001:db8::1")).is_err());
+    }
+}
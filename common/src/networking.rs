@@ -3,62 +3,508 @@
 /// This module contains functions to send and receive UDP packets.
 ///
 /// Uses tokio for async I/O.
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use socket2::SockRef;
 use tokio::net::UdpSocket;
 
-use crate::{magic::parse_magic_packet, messages::Message, MessageReceiver};
+use crate::{
+    auth::{AuthError, ReplayFilter},
+    batch::BatchReceiver,
+    magic::{make_magic_packet_into, parse_magic_packet_bytes, MagicError},
+    messages::Message,
+    multicast::MulticastGroup,
+    sequence::SequenceTrackers,
+    Identity, InstanceId, MessageReceiver, Peer,
+};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
 /// Send a UDP packet to a given address.
-pub async fn send_packet(addr: SocketAddr, data: &[u8]) -> Result<(), std::io::Error> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+///
+/// Use the socket returned by `make_listener`, so that the peer sees the packet come from the port we listen on.
+pub async fn send_packet(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    // A dual-stack socket can only send to IPv4 addresses in their IPv6 form
+    let addr = match (addr, socket.local_addr()?) {
+        (SocketAddr::V4(addr), SocketAddr::V6(_)) => {
+            SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+        }
+        _ => addr,
+    };
     socket.send_to(data, addr).await?;
     Ok(())
 }
 
+/// Parse an IP address given on the command line, and give it a port.
+///
+/// IPv6 addresses can be in brackets, and can have a zone after a `%`:
+/// the name or index of the interface that a link-local address is on, like `fe80::1%eth0`.
+pub fn parse_addr(addr: &str, port: u16) -> Result<SocketAddr, String> {
+    let addr = addr.trim();
+    let unbracketed = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.strip_suffix(']'))
+        .unwrap_or(addr);
+    let (ip, zone) = match unbracketed.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (unbracketed, None),
+    };
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|e| format!("invalid IP address {addr:?}: {e}"))?;
+    match (ip, zone) {
+        (IpAddr::V6(ip), Some(zone)) => {
+            Ok(SocketAddrV6::new(ip, port, 0, interface_index(zone)?).into())
+        }
+        (IpAddr::V4(_), Some(_)) => Err(format!("only IPv6 addresses can have a zone: {addr:?}")),
+        (ip, None) => Ok(SocketAddr::new(ip, port)),
+    }
+}
+
+/// Get the index of a network interface from its name, or from the index itself.
+pub fn interface_index(name: &str) -> Result<u32, String> {
+    if let Ok(index) = name.parse() {
+        return Ok(index);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+        let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if index != 0 {
+            return Ok(index);
+        }
+    }
+    Err(format!("unknown network interface {name:?}"))
+}
+
+/// The same address with another port.
+///
+/// Unlike `SocketAddr::new(addr.ip(), port)`, this keeps the zone of an IPv6 link-local address,
+/// without which the address can't be reached.
+pub fn with_port(mut addr: SocketAddr, port: u16) -> SocketAddr {
+    addr.set_port(port);
+    addr
+}
+
+/// Turn an IPv4 address that a dual-stack socket received from,
+/// which is in its IPv6-mapped form, back into a plain IPv4 address.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Bind a UDP socket to the address, and to the network interface with the given name, if any.
+///
+/// A socket bound to the unspecified IPv6 address (`[::]`) is dual-stack:
+/// it also sends and receives IPv4.
+/// If IPv6 is not available on this host, it is bound to the unspecified IPv4 address instead.
+pub fn bind_socket(
+    addr: SocketAddr,
+    interface: Option<&str>,
+) -> Result<std::net::UdpSocket, std::io::Error> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let (socket, addr) =
+        match Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP)) {
+            Ok(socket) => (socket, addr),
+            Err(e) if addr.ip() == Ipv6Addr::UNSPECIFIED => {
+                warn!("IPv6 is not available ({e}), listening on IPv4 only");
+                let addr = SocketAddr::new([0, 0, 0, 0].into(), addr.port());
+                (Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?, addr)
+            }
+            Err(e) => return Err(e),
+        };
+    if addr.ip() == Ipv6Addr::UNSPECIFIED {
+        socket.set_only_v6(false)?;
+    }
+    if let Some(interface) = interface {
+        bind_to_device(&socket, interface)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Only send and receive through the network interface with the given name,
+/// with `SO_BINDTODEVICE`.
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &socket2::Socket, interface: &str) -> Result<(), std::io::Error> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &socket2::Socket, _interface: &str) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to a network interface is only supported on Linux",
+    ))
+}
+
+/// Check that the address can be sent to: that we have IPv6 if it is an IPv6 address,
+/// and that there is a route to it.
+pub fn check_route(addr: SocketAddr) -> Result<(), std::io::Error> {
+    let socket = bind_socket(
+        match addr {
+            SocketAddr::V4(_) => SocketAddr::new([0, 0, 0, 0].into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        },
+        None,
+    )?;
+    socket.set_broadcast(true)?;
+    let addr = match addr {
+        SocketAddr::V6(v6) if v6.ip().is_multicast() && v6.scope_id() == 0 => {
+            // The kernel won't connect to a link-local group without knowing the interface,
+            // though it sends to one on the interface of the multicast route:
+            // so look up the route to the same group at global scope instead
+            let mut octets = v6.ip().octets();
+            octets[1] = (octets[1] & 0xf0) | 0x0e;
+            SocketAddr::new(Ipv6Addr::from(octets).into(), v6.port())
+        }
+        _ => addr,
+    };
+    // Connecting a UDP socket sends nothing, but looks up the route
+    socket.connect(addr)
+}
+
+/// The largest datagram that the protocol allows: the length in the header is 16 bits.
+/// Listeners can receive datagrams of up to this size.
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// The number of packets that failed authentication, in all listeners of this process.
+static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Get the number of packets that were dropped because they failed authentication.
+///
+/// If this keeps growing while nothing else arrives,
+/// the peers are probably using a different pre-shared key than we are.
+pub fn auth_failures() -> u64 {
+    AUTH_FAILURES.load(Ordering::Relaxed)
+}
+
+/// The number of authentic packets that were received again, in all listeners of this process.
+/// These are not counted as failures: see `MagicError::Replayed`.
+static REPLAYS: AtomicU64 = AtomicU64::new(0);
+
+/// Get the number of packets that were dropped because they were already received.
+pub fn replays() -> u64 {
+    REPLAYS.load(Ordering::Relaxed)
+}
+
+/// The peers that a listener has heard from, by their instance ids,
+/// so that the packets from a peer all share one copy of its name.
+///
+/// Packets with the compact header only carry the name of their sender when it introduces itself,
+/// so this is also where the names of the other packets come from.
+#[derive(Default)]
+struct PeerInterner {
+    peers: HashMap<InstanceId, Peer>,
+}
+
+impl PeerInterner {
+    /// Forget all peers once there are this many, so that forged ids can't use up all memory.
+    const MAX_PEERS: usize = 4096;
+
+    /// The sender of a packet, learning its name if the packet carries it.
+    /// Until we learn the name of a sender, it goes by its id, like `#0123abcd`.
+    fn intern(&mut self, sender: InstanceId, name: Option<&str>) -> Peer {
+        match (self.peers.get(&sender), name) {
+            (Some(known), None) => return known.clone(),
+            (Some(known), Some(name)) if &*known.name == name => return known.clone(),
+            _ => {}
+        }
+        if self.peers.len() >= Self::MAX_PEERS {
+            self.peers.clear();
+        }
+        let name = match name {
+            Some(name) => name.into(),
+            None => format!("#{sender:08x}").into(),
+        };
+        let peer = Peer { id: sender, name };
+        self.peers.insert(sender, peer.clone());
+        peer
+    }
+}
+
+/// Options for the sockets that we send and receive on.
+/// The options that are `None` are left at the defaults of the kernel.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    /// The size of the kernel's receive buffer (`SO_RCVBUF`), in bytes.
+    /// A larger buffer drops fewer packets when they arrive in bursts.
+    pub recv_buffer: Option<usize>,
+    /// The size of the kernel's send buffer (`SO_SNDBUF`), in bytes.
+    pub send_buffer: Option<usize>,
+    /// The DSCP class to mark the packets we send with, from 0 to 63,
+    /// so that the network can prioritize or deprioritize them.
+    pub dscp: Option<u8>,
+    /// The TTL (IPv4) or hop limit (IPv6) of unicast and broadcast packets.
+    pub ttl: Option<u32>,
+    /// The TTL or hop limit of multicast packets: how many routers they can cross.
+    /// With 1, they stay on the local network.
+    pub multicast_ttl: Option<u32>,
+    /// Whether the multicast packets we send are also delivered to this host.
+    pub multicast_loop: Option<bool>,
+}
+
+impl SocketOptions {
+    /// Set the options on the socket.
+    ///
+    /// A dual-stack socket gets both the IPv4 and the IPv6 form of every option.
+    pub fn apply(&self, socket: &UdpSocket) -> Result<(), std::io::Error> {
+        let sock = SockRef::from(socket);
+        let (ipv4, ipv6) = match socket.local_addr()? {
+            SocketAddr::V4(_) => (true, false),
+            SocketAddr::V6(_) => (!sock.only_v6()?, true),
+        };
+        if let Some(size) = self.recv_buffer {
+            sock.set_recv_buffer_size(size)?;
+            let actual = sock.recv_buffer_size()?;
+            if actual < size {
+                warn!("The receive buffer is only {actual} bytes, not {size}: raise net.core.rmem_max to allow more");
+            }
+        }
+        if let Some(size) = self.send_buffer {
+            sock.set_send_buffer_size(size)?;
+            let actual = sock.send_buffer_size()?;
+            if actual < size {
+                warn!("The send buffer is only {actual} bytes, not {size}: raise net.core.wmem_max to allow more");
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            // The DSCP is the upper six bits of the TOS or traffic class byte
+            let tos = (dscp as u32) << 2;
+            if ipv4 {
+                sock.set_tos(tos)?;
+            }
+            if ipv6 {
+                set_traffic_class_v6(socket, tos)?;
+            }
+        }
+        if let Some(ttl) = self.ttl {
+            if ipv4 {
+                sock.set_ttl(ttl)?;
+            }
+            if ipv6 {
+                sock.set_unicast_hops_v6(ttl)?;
+            }
+        }
+        if let Some(ttl) = self.multicast_ttl {
+            if ipv4 {
+                sock.set_multicast_ttl_v4(ttl)?;
+            }
+            if ipv6 {
+                sock.set_multicast_hops_v6(ttl)?;
+            }
+        }
+        if let Some(multicast_loop) = self.multicast_loop {
+            if ipv4 {
+                sock.set_multicast_loop_v4(multicast_loop)?;
+            }
+            if ipv6 {
+                sock.set_multicast_loop_v6(multicast_loop)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the traffic class of the IPv6 packets sent from the socket, which the `socket2` crate can't do.
+#[cfg(target_os = "linux")]
+fn set_traffic_class_v6(socket: &UdpSocket, class: u32) -> Result<(), std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let class = class as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &class as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_traffic_class_v6(_socket: &UdpSocket, _class: u32) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "setting the DSCP of IPv6 packets is only supported on Linux",
+    ))
+}
+
+/// How `make_listener` sets up its sockets, besides the addresses that they are bound to.
+#[derive(Debug, Clone, Default)]
+pub struct ListenOptions {
+    /// The multicast groups to join.
+    pub groups: Vec<MulticastGroup>,
+    /// The names of the network interfaces to listen on.
+    /// If empty, the sockets receive from every interface.
+    pub interfaces: Vec<String>,
+    /// The options to set on every socket.
+    pub socket: SocketOptions,
+    /// Where to record the sequence numbers of the packets received, if anywhere.
+    pub sequences: Option<SequenceTrackers>,
+}
+
 /// Make a channel to receive messages on any of these addresses, ignoring my own messages.
 ///
-/// Binds to the given list of SocketAddrs.
-/// Returns a channel that will receive packets.
-pub fn make_listener<I>(addrs: I, my_name: &str) -> MessageReceiver
+/// Binds to the given list of SocketAddrs, which must not be empty.
+/// Bind to `[::]` to receive both IPv4 and IPv6.
+/// If the options name any interfaces, every address is bound once on each of them.
+/// Returns a channel that will receive packets,
+/// and the first of the sockets, to send messages to single peers with.
+///
+/// Every socket joins the multicast groups of its address family:
+/// a dual-stack socket joins both IPv4 and IPv6 groups.
+/// A socket on an interface joins them on that interface.
+///
+/// Our own packets are dropped, by our instance id. So are packets that are not authenticated
+/// with the identity's pre-shared key, if it has one, and packets that were replayed.
+///
+/// The sequence numbers of the packets are recorded in the options' trackers, by their sender,
+/// and packets that were already received are dropped.
+pub fn make_listener<I>(
+    addrs: I,
+    identity: &Identity,
+    options: &ListenOptions,
+) -> (MessageReceiver, Arc<UdpSocket>)
 where
     I: IntoIterator<Item = SocketAddr>,
 {
     let (tx, rx) = tokio::sync::mpsc::channel(100);
-    for addr in addrs {
+    // Shared between the sockets, so that a packet can't be replayed to another one of them
+    let replay_filter = Arc::new(Mutex::new(ReplayFilter::new()));
+    // Also shared, because a peer may introduce itself on another socket than it sends the rest on
+    let peers = Arc::new(Mutex::new(PeerInterner::default()));
+    let interfaces: Vec<Option<&str>> = if options.interfaces.is_empty() {
+        vec![None]
+    } else {
+        options.interfaces.iter().map(|name| Some(name.as_str())).collect()
+    };
+    let mut sockets = vec![];
+    for (addr, interface) in addrs
+        .into_iter()
+        .flat_map(|addr| interfaces.iter().map(move |interface| (addr, *interface)))
+    {
+        debug!("Starting listener on {} (interface {:?})", addr, interface);
+        // Bind right away, so that nothing sent to us after this returns is lost
+        let socket = bind_socket(addr, interface)
+            .unwrap_or_else(|e| panic!("Failed to listen on {addr} (interface {interface:?}): {e}"));
+        socket.set_nonblocking(true).unwrap();
+        let socket = Arc::new(UdpSocket::from_std(socket).unwrap());
+        options
+            .socket
+            .apply(&socket)
+            .unwrap_or_else(|e| panic!("Failed to set socket options on {addr}: {e}"));
+        let local_addr = socket.local_addr().unwrap();
+        let interface_index = match interface {
+            Some(interface) => interface_index(interface).unwrap(),
+            None => 0,
+        };
+        for group in &options.groups {
+            if group.group.is_ipv6() && local_addr.is_ipv4() {
+                continue;
+            }
+            let mut group = *group;
+            if group.interface == 0 {
+                group.interface = interface_index;
+            }
+            group
+                .join(&socket)
+                .unwrap_or_else(|e| panic!("Failed to join multicast group {group}: {e}"));
+            info!("Joined multicast group {group} on {local_addr} (interface {interface:?})");
+        }
+        sockets.push(socket.clone());
+
         let tx = tx.clone();
-        let my_name = my_name.to_string();
+        let my_id = identity.instance;
+        let psk = identity.psk.clone();
+        let replay_filter = replay_filter.clone();
+        let peers = peers.clone();
+        let sequences = options.sequences.clone();
         tokio::spawn(async move {
-            debug!("Starting listener on {}", addr);
-            let socket = UdpSocket::bind(addr).await.unwrap();
+            let mut receiver = BatchReceiver::new(&socket, MAX_DATAGRAM_SIZE);
             loop {
-                let mut buf = [0; 1024];
-                let (amt, src) = socket.recv_from(&mut buf).await.unwrap();
-
-                let data = &buf[..amt];
-                let maybe_magic_decoded = parse_magic_packet(data);
-                match maybe_magic_decoded {
-                    Ok((name, message)) => {
-                        if name == my_name {
-                            continue;
+                for (data, src) in receiver.recv(&socket).await.unwrap() {
+                    let src = unmap(src);
+                    let maybe_magic_decoded = parse_magic_packet_bytes(&data, psk.as_ref()).and_then(|(header, message)| {
+                        if let Some(timestamp) = header.timestamp {
+                            replay_filter
+                                .lock()
+                                .unwrap()
+                                .check(header.sender, timestamp)
+                                .map_err(|e| match e {
+                                    AuthError::Replayed(timestamp) => MagicError::Replayed(timestamp),
+                                    e => MagicError::AuthError(e),
+                                })?;
+                        }
+                        Ok((header, message))
+                    });
+                    match maybe_magic_decoded {
+                        Ok((header, message)) => {
+                            // Our own packets, like broadcasts that come back to us.
+                            // Peers that have our name are still heard.
+                            if header.sender == my_id {
+                                continue;
+                            }
+                            let peer = peers.lock().unwrap().intern(header.sender, header.name.as_deref());
+                            if let (Some(sequences), Some(sequence)) = (&sequences, header.sequence) {
+                                if !sequences.record(&peer, sequence) {
+                                    trace!("Dropping a duplicate of packet {sequence} from {src}");
+                                    continue;
+                                }
+                            }
+                            tx.send((src, peer, message)).await.unwrap();
+                        }
+                        //                    Err(MagicError::InvalidMagic) => {}, // Ignore
+                        //                    Err(MagicError::InvalidVersion(v)) => {}, // Ignore
+                        //                    Err(MagicError::DecodeError(e)) => {
+                        //                        eprintln!("Error decoding packet: {}", e);
+                        //                    }
+                        Err(MagicError::UnknownMessage) => {
+                            trace!("Skipping a message of an unknown type from {src}");
+                        }
+                        Err(MagicError::AuthError(e)) => {
+                            AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+                            warn!("Dropping packet from {src} that failed authentication: {e:?}");
+                        }
+                        Err(MagicError::Replayed(timestamp)) => {
+                            REPLAYS.fetch_add(1, Ordering::Relaxed);
+                            trace!("Dropping packet from {src} that was already received, with timestamp {timestamp}");
+                        }
+                        Err(e) => {
+                            eprintln!("Error in: {e:?}");
                         }
-                        tx.send((src, name, message)).await.unwrap();
-                    }
-                    //                    Err(MagicError::InvalidMagic) => {}, // Ignore
-                    //                    Err(MagicError::InvalidVersion(v)) => {}, // Ignore
-                    //                    Err(MagicError::DecodeError(e)) => {
-                    //                        eprintln!("Error decoding packet: {}", e);
-                    //                    }
-                    Err(e) => {
-                        eprintln!("Error in: {e:?}");
                     }
                 }
             }
         });
     }
-    rx
+    let socket = sockets
+        .into_iter()
+        .next()
+        .expect("Need an address to listen on");
+    (rx, socket)
 }
 
 /// Broadcast a packet to a list of addresses.
@@ -71,25 +517,170 @@ pub async fn broadcast_packet(socket: &UdpSocket, addrs: &[SocketAddr], data: &[
     Ok(())
 }
 
-/// Broadcast a message to a list of addresses.
+/// Broadcast a message to a list of addresses, encoded for the given protocol version.
+///
+/// The packet is built in `packet`, so that a caller sending many messages can reuse its memory.
 /// 
 /// The given `socket` must have `set_broadcast(true)` called on it.
 pub async fn broadcast_message(
     socket: &UdpSocket,
     addrs: &[SocketAddr],
-    my_name: &str,
+    identity: &Identity,
+    version: u16,
     message: &Message,
+    packet: &mut Vec<u8>,
 ) -> Result<(), std::io::Error> {
-    let data = crate::magic::make_magic_packet(my_name, message);
-    broadcast_packet(socket, addrs, &data).await
+    make_packet(identity, version, message, packet)?;
+    broadcast_packet(socket, addrs, packet).await
 }
 
-/// Send a message to a given address.
+/// Send a message to a given address, encoded for the given protocol version.
+///
+/// Use the socket returned by `make_listener`, so that the peer sees the message come from the port we listen on.
 pub async fn send_message(
+    socket: &UdpSocket,
     addr: SocketAddr,
-    my_name: &str,
+    identity: &Identity,
+    version: u16,
+    message: &Message,
+) -> Result<(), std::io::Error> {
+    let mut data = Vec::new();
+    make_packet(identity, version, message, &mut data)?;
+    send_packet(socket, addr, &data).await
+}
+
+/// Make a packet in the given buffer, failing if the message does not exist in the given protocol version.
+fn make_packet(
+    identity: &Identity,
+    version: u16,
     message: &Message,
+    packet: &mut Vec<u8>,
 ) -> Result<(), std::io::Error> {
-    let data = crate::magic::make_magic_packet(my_name, message);
-    send_packet(addr, &data).await
+    make_magic_packet_into(identity, version, message, packet).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Message {message:?} cannot be sent in protocol version {version}"),
+        )
+    })
+}
+
+/// Find the largest datagram that the route to the address carries without fragmentation.
+///
+/// This asks the kernel for the MTU of the route to the address, without sending anything:
+/// for a broadcast address, this is the MTU of the interface.
+/// A smaller MTU further along the path is only known if the kernel learned it already.
+/// Only works on Linux; returns `None` elsewhere, or if the kernel does not know.
+pub fn route_max_datagram_size(addr: SocketAddr) -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let bind_addr: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = std::net::UdpSocket::bind(bind_addr).ok()?;
+        socket.set_broadcast(true).ok()?;
+        // The kernel only knows the MTU of a connected socket
+        socket.connect(addr).ok()?;
+        let (level, option, headers) = if addr.is_ipv4() {
+            // IPv4 and UDP headers
+            (libc::IPPROTO_IP, libc::IP_MTU, 20 + 8)
+        } else {
+            // IPv6 and UDP headers
+            (libc::IPPROTO_IPV6, libc::IPV6_MTU, 40 + 8)
+        };
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                option,
+                &mut mtu as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            warn!(
+                "Could not get the MTU of the route to {addr}: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some((mtu as usize).saturating_sub(headers).min(MAX_DATAGRAM_SIZE))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = addr;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::VERSION;
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            parse_addr("255.255.255.255", 1337).unwrap(),
+            "255.255.255.255:1337".parse().unwrap()
+        );
+        assert_eq!(parse_addr("::1", 1337).unwrap(), "[::1]:1337".parse().unwrap());
+        assert_eq!(parse_addr("[::1]", 1337).unwrap(), "[::1]:1337".parse().unwrap());
+        assert_eq!(
+            parse_addr("fe80::1%3", 1337).unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 1337, 0, 3).into()
+        );
+        assert!(parse_addr("127.0.0.1%3", 1337).is_err());
+        assert!(parse_addr("not an address", 1337).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let socket = bind_socket("[::]:0".parse().unwrap(), None).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let socket = UdpSocket::from_std(socket).unwrap();
+        let options = SocketOptions {
+            recv_buffer: Some(65536),
+            ttl: Some(7),
+            multicast_ttl: Some(3),
+            multicast_loop: Some(false),
+            ..Default::default()
+        };
+        options.apply(&socket).unwrap();
+        let sock = SockRef::from(&socket);
+        assert!(sock.recv_buffer_size().unwrap() >= 65536);
+        assert_eq!(sock.unicast_hops_v6().unwrap(), 7);
+        assert_eq!(sock.multicast_hops_v6().unwrap(), 3);
+        assert!(!sock.multicast_loop_v6().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_told_apart_by_instance() {
+        let me = Identity::new("twin".to_string());
+        let (mut listener, socket) =
+            make_listener(vec!["127.0.0.1:0".parse().unwrap()], &me, &Default::default());
+        let addr = socket.local_addr().unwrap();
+        let message = Message::Pong { nonce: 42 };
+
+        // Our own packet is dropped, but not the one from another peer with our name
+        let twin = Identity::new("twin".to_string());
+        send_message(&socket, addr, &me, VERSION, &message).await.unwrap();
+        send_message(&socket, addr, &twin, VERSION, &message).await.unwrap();
+        let (_, peer, _) = listener.recv().await.unwrap();
+        assert_eq!(peer.id, twin.instance);
+        assert!(listener.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_unmap() {
+        let mapped = SocketAddr::new("::ffff:10.0.0.1".parse().unwrap(), 1337);
+        assert_eq!(unmap(mapped), "10.0.0.1:1337".parse().unwrap());
+        let v6: SocketAddr = "[fe80::1]:1337".parse().unwrap();
+        assert_eq!(unmap(v6), v6);
+    }
 }
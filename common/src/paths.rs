@@ -0,0 +1,183 @@
+/// Paths of files in hashlists and file listings, which are the same on every platform.
+///
+/// A path is a sequence of bytes: the names of the directories and of the file, separated by `/`.
+/// On Unix, the names are the bytes of the names on disk, so that every name round-trips exactly,
+/// even if it is not valid UTF-8. Elsewhere, names must be valid UTF-8.
+/// A path is always relative to the directory being transferred: it has no empty names, `.` or `..`,
+/// so that a peer can't make us write outside of that directory.
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+};
+
+/// Why a path can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The path is empty.
+    Empty,
+    /// The path has a component that would leave the directory, like `..`, or an absolute path.
+    NotRelative,
+    /// A name in the path is empty, `.` or `..`, or has a character that names can't have.
+    /// The value is the name, with any invalid UTF-8 replaced.
+    InvalidName(String),
+    /// The name is not valid UTF-8, which only Unix can store.
+    NotUtf8(String),
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "the path is empty"),
+            PathError::NotRelative => write!(f, "the path is not relative"),
+            PathError::InvalidName(name) => write!(f, "invalid file name {name:?}"),
+            PathError::NotUtf8(name) => write!(f, "the file name {name:?} is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Encode a path relative to the directory being transferred.
+pub fn encode(relative: &Path) -> Result<Vec<u8>, PathError> {
+    let mut encoded = Vec::new();
+    for component in relative.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => return Err(PathError::NotRelative),
+        };
+        let name = name_bytes(name)?;
+        if name.contains(&b'/') || name.contains(&0) {
+            return Err(PathError::InvalidName(String::from_utf8_lossy(name).into_owned()));
+        }
+        if !encoded.is_empty() {
+            encoded.push(b'/');
+        }
+        encoded.extend_from_slice(name);
+    }
+    if encoded.is_empty() {
+        return Err(PathError::Empty);
+    }
+    Ok(encoded)
+}
+
+/// Decode a path into one relative to the directory being transferred, checking that it stays inside.
+pub fn decode(path: &[u8]) -> Result<PathBuf, PathError> {
+    if path.is_empty() {
+        return Err(PathError::Empty);
+    }
+    let mut decoded = PathBuf::new();
+    for name in path.split(|byte| *byte == b'/') {
+        let invalid = || PathError::InvalidName(String::from_utf8_lossy(name).into_owned());
+        if name.is_empty() || name == b"." || name == b".." || name.contains(&0) {
+            return Err(invalid());
+        }
+        let name = os_str(name)?;
+        // Only a plain name, which can't be a prefix like `C:` or hold a separator like `\`
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(normal)), None) if normal == name => decoded.push(name),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Show a path to the user, replacing any invalid UTF-8.
+pub fn display(path: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(path)
+}
+
+#[cfg(unix)]
+fn name_bytes(name: &OsStr) -> Result<&[u8], PathError> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &OsStr) -> Result<&[u8], PathError> {
+    name.to_str()
+        .map(str::as_bytes)
+        .ok_or_else(|| PathError::NotUtf8(name.to_string_lossy().into_owned()))
+}
+
+#[cfg(unix)]
+fn os_str(name: &[u8]) -> Result<&OsStr, PathError> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(OsStr::from_bytes(name))
+}
+
+#[cfg(not(unix))]
+fn os_str(name: &[u8]) -> Result<&OsStr, PathError> {
+    std::str::from_utf8(name)
+        .map(OsStr::new)
+        .map_err(|_| PathError::NotUtf8(String::from_utf8_lossy(name).into_owned()))
+}
+
+/// Serialization of a path as a string if it is valid UTF-8, which is how paths have always been sent,
+/// so that peers and tools from before bytes still understand it, and as bytes otherwise.
+/// Either is accepted when deserializing.
+pub mod str_or_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(path) {
+            Ok(path) => serializer.serialize_str(path),
+            Err(_) => serializer.serialize_bytes(path),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        serde_bytes::ByteBuf::deserialize(deserializer).map(serde_bytes::ByteBuf::into_vec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let path = Path::new("some/./dir/file.txt");
+        let encoded = encode(path).unwrap();
+        assert_eq!(encoded, b"some/dir/file.txt");
+        assert_eq!(decode(&encoded).unwrap(), Path::new("some/dir/file.txt"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = OsStr::from_bytes(b"caf\xe9");
+            let path = Path::new("dir").join(name);
+            let encoded = encode(&path).unwrap();
+            assert_eq!(encoded, b"dir/caf\xe9");
+            assert_eq!(decode(&encoded).unwrap(), path);
+            assert_eq!(display(&encoded), "dir/caf\u{fffd}");
+        }
+    }
+
+    #[test]
+    fn test_unsafe_paths_are_rejected() {
+        assert_eq!(encode(Path::new("../secret")), Err(PathError::NotRelative));
+        assert_eq!(encode(Path::new("/etc/passwd")), Err(PathError::NotRelative));
+        assert_eq!(encode(Path::new(".")), Err(PathError::Empty));
+        for path in [&b""[..], b"/etc/passwd", b"a/../../b", b"a//b", b"./a", b"a/\0"] {
+            assert!(decode(path).is_err(), "{path:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn test_old_and_new_serialization() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Item {
+            #[serde(with = "str_or_bytes")]
+            path: Vec<u8>,
+        }
+        // Valid UTF-8 is a string, as it was before paths were bytes
+        let encoded = rmp_serde::to_vec(&Item { path: b"file".to_vec() }).unwrap();
+        assert_eq!(encoded, rmp_serde::to_vec(&("file",)).unwrap());
+        let encoded = rmp_serde::to_vec(&Item { path: b"\xff".to_vec() }).unwrap();
+        let decoded: Item = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(decoded.path, b"\xff");
+    }
+}
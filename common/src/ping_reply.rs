@@ -1,24 +1,30 @@
-use std::net::SocketAddr;
+use std::sync::Arc;
 
-use crate::{messages::Message, MessageReceiver};
+use crate::{messages::Message, sequence::SequenceGap, Identity, MessageReceiver, Peer};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use tokio::sync::mpsc;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+/// What a peer said in a `Ping` about the packets that it received from us:
+/// who it is, the number of packets, and the gaps in their sequence numbers, if it tracks them.
+pub type PingStats = (Peer, u64, Option<Vec<SequenceGap>>);
 
 pub async fn reply_to_pings(
     mut ping_listener: MessageReceiver,
-    my_name: String,
+    socket: Arc<UdpSocket>,
+    identity: Identity,
     send_port: u16,
-    recv_stat_collector: mpsc::Sender<(String, u64)>,
+    version: u16,
+    recv_stat_collector: mpsc::Sender<PingStats>,
 ) {
     loop {
         let (src, name, message) = ping_listener.recv().await.unwrap();
-        if let crate::messages::Message::Ping { nonce, recvs } = message {
+        if let crate::messages::Message::Ping { nonce, recvs, lost } = message {
             debug!("Received ping from {} ({}) with nonce {}", src, name, nonce);
-            recv_stat_collector.send((name, recvs)).await.ok();
+            recv_stat_collector.send((name, recvs, lost)).await.ok();
             let message = crate::messages::Message::Pong { nonce };
-            let dest = SocketAddr::new(src.ip(), send_port);
-            crate::networking::send_message(dest, &my_name, &message)
+            let dest = crate::networking::with_port(src, send_port);
+            crate::networking::send_message(&socket, dest, &identity, version, &message)
                 .await
                 .ok();
             debug!("Sent pong to {}", dest);
@@ -29,13 +35,13 @@ pub async fn reply_to_pings(
 pub async fn reply_to_pings_broadcast(
     mut ping_listener: MessageReceiver,
     broadcaster: mpsc::Sender<Message>,
-    recv_stat_collector: mpsc::Sender<(String, u64)>,
+    recv_stat_collector: mpsc::Sender<PingStats>,
 ) {
     loop {
         let (src, name, message) = ping_listener.recv().await.unwrap();
-        if let crate::messages::Message::Ping { nonce, recvs } = message {
+        if let crate::messages::Message::Ping { nonce, recvs, lost } = message {
             info!("Received ping from {} ({}) with nonce {}", src, name, nonce);
-            recv_stat_collector.send((name, recvs)).await.ok();
+            recv_stat_collector.send((name, recvs, lost)).await.ok();
             let message = crate::messages::Message::Pong { nonce };
             info!("Sent pong {message:?}");
             broadcaster.send(message).await.ok();
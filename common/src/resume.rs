@@ -0,0 +1,139 @@
+/// Saved download progress, so that a client that is stopped can continue where it stopped.
+///
+/// The client saves the bitmap of the chunks that it has of every file into a state directory,
+/// in a file named after the hash of the file's path.
+/// A saved bitmap is only used again if the server still lists the file with the same path, size, hash
+/// and chunk size: otherwise, the chunks that we have may not be the chunks of the file any more.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::messages::FileListingFragment;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The saved progress of the download of a file.
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedState {
+    /// The path of the file, as in its listing.
+    #[serde(with = "serde_bytes")]
+    path: Vec<u8>,
+    /// The size of the file.
+    size: u64,
+    /// The hash of the file.
+    hash: [u8; 32],
+    /// The size of the chunks of the file.
+    chunk_size: u16,
+    /// The bitmap of the chunks that we have.
+    bitmap: Vec<u64>,
+}
+
+impl SavedState {
+    /// Check if this is the state of the file in the given listing entry.
+    fn is_for(&self, file: &FileListingFragment) -> bool {
+        self.path == file.path
+            && self.size == file.size
+            && self.hash == file.hash
+            && self.chunk_size == file.chunk_size
+    }
+}
+
+/// The path of the file that the progress of a file is saved to.
+pub fn state_path(dir: &Path, file: &FileListingFragment) -> PathBuf {
+    dir.join(format!(
+        "{:016x}.state",
+        xxhash_rust::xxh3::xxh3_64(&file.path)
+    ))
+}
+
+/// Save the bitmap of the chunks that we have of a file.
+///
+/// The state is written to a temporary file, and then moved in place,
+/// so that a client that is stopped meanwhile leaves either the old state or the new one.
+pub async fn save(
+    dir: &Path,
+    file: &FileListingFragment,
+    bitmap: &[u64],
+) -> Result<(), std::io::Error> {
+    let state = SavedState {
+        path: file.path.clone(),
+        size: file.size,
+        hash: file.hash,
+        chunk_size: file.chunk_size,
+        bitmap: bitmap.to_vec(),
+    };
+    let encoded = rmp_serde::to_vec(&state).expect("Failed to encode download state");
+    fs::create_dir_all(dir).await?;
+    let path = state_path(dir, file);
+    let temporary = path.with_extension("state.tmp");
+    fs::write(&temporary, encoded).await?;
+    fs::rename(&temporary, &path).await
+}
+
+/// Load the saved bitmap of the chunks that we have of a file.
+///
+/// Returns `None` if there is no saved state, or if it is for a file with another size, hash or chunk size.
+pub async fn load(dir: &Path, file: &FileListingFragment) -> Option<Vec<u64>> {
+    let path = state_path(dir, file);
+    let encoded = match fs::read(&path).await {
+        Ok(encoded) => encoded,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read download state {path:?}: {e}");
+            return None;
+        }
+    };
+    match rmp_serde::from_slice::<SavedState>(&encoded) {
+        Ok(state) if state.is_for(file) => Some(state.bitmap),
+        Ok(_) => {
+            debug!("Download state {path:?} is for another version of the file");
+            None
+        }
+        Err(e) => {
+            warn!("Failed to decode download state {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Forget the saved progress of a file, once it is downloaded.
+pub async fn remove(dir: &Path, file: &FileListingFragment) {
+    let path = state_path(dir, file);
+    if let Err(e) = fs::remove_file(&path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove download state {path:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("resume-test-{}", std::process::id()));
+        let mut file = FileListingFragment {
+            idx: 0,
+            total: 1,
+            path: b"some/file".to_vec(),
+            size: 1000,
+            hash: [1; 32],
+            chunk_size: 100,
+            chunk_hashes: false,
+        };
+        save(&dir, &file, &[0b1011]).await.unwrap();
+        assert_eq!(load(&dir, &file).await, Some(vec![0b1011]));
+
+        // The server now has another version of the file
+        file.hash = [2; 32];
+        assert_eq!(load(&dir, &file).await, None);
+
+        file.hash = [1; 32];
+        remove(&dir, &file).await;
+        assert_eq!(load(&dir, &file).await, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+/// Rolling checksums of chunks, as in rsync, which let a client find the chunks of a new version of a file
+/// anywhere in its old copy, and not only where they were.
+///
+/// The checksum of a window of bytes can be updated in constant time as the window moves by one byte,
+/// so that a whole file can be searched for chunks with a known checksum.
+/// The checksum is weak, so a chunk that it finds must still be checked against its hash:
+/// see the `chunk_hashes` module.
+///
+/// A list of checksums is the checksums of consecutive chunks, one after the other,
+/// each as 4 bytes in little-endian order, as in hashlists and `ChunkHashes` messages.
+use std::num::Wrapping;
+
+/// The size of the checksum of a chunk.
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Get the checksum of a chunk.
+pub fn checksum(data: &[u8]) -> u32 {
+    Rolling::new(data).value()
+}
+
+/// Split a list of checksums into the checksums of each chunk.
+/// A partial checksum at the end, which a broken list may have, is left out.
+pub fn split(checksums: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    checksums
+        .chunks_exact(CHECKSUM_SIZE)
+        .map(|checksum| u32::from_le_bytes(checksum.try_into().unwrap()))
+}
+
+/// The checksum of a window of bytes that moves along a file.
+#[derive(Debug, Clone)]
+pub struct Rolling {
+    /// The sum of the bytes in the window.
+    a: Wrapping<u32>,
+    /// The sum of the bytes weighted by their distance from the end of the window.
+    b: Wrapping<u32>,
+    /// The size of the window.
+    len: Wrapping<u32>,
+}
+
+impl Rolling {
+    /// Start with the checksum of the given window.
+    pub fn new(window: &[u8]) -> Self {
+        let len = Wrapping(window.len() as u32);
+        let mut a = Wrapping(0);
+        let mut b = Wrapping(0);
+        for (i, byte) in window.iter().enumerate() {
+            a += Wrapping(*byte as u32);
+            b += (len - Wrapping(i as u32)) * Wrapping(*byte as u32);
+        }
+        Self { a, b, len }
+    }
+
+    /// Move the window by one byte: `out` leaves it at the start, and `into` joins it at the end.
+    pub fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a - Wrapping(out as u32) + Wrapping(into as u32);
+        self.b = self.b - self.len * Wrapping(out as u32) + self.a;
+    }
+
+    /// The checksum of the current window.
+    pub fn value(&self) -> u32 {
+        (self.a.0 & 0xffff) | (self.b.0 << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let window = 64;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..=data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(rolling.value(), checksum(&data[start..start + window]));
+        }
+        assert_ne!(checksum(&data[..window]), checksum(&data[1..window + 1]));
+
+        let checksums: Vec<u8> = [checksum(b"one"), checksum(b"two")]
+            .iter()
+            .flat_map(|checksum| checksum.to_le_bytes())
+            .collect();
+        let split: Vec<u32> = split(&checksums).collect();
+        assert_eq!(split, [checksum(b"one"), checksum(b"two")]);
+    }
+}
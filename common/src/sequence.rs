@@ -0,0 +1,314 @@
+/// Sequence numbers, to measure exactly which packets from a server are lost, duplicated or reordered.
+///
+/// From version 4 on, the broadcaster stamps every packet with a sequence number
+/// in the compact header, counting up from 0 for every run of the server.
+/// Every packet sent to several addresses has the same number on each of them.
+///
+/// A client tracks the numbers that it receives from every server.
+/// A gap in them is a run of packets that it did not receive, until they arrive late.
+/// In its pings, the client reports the gaps to the server, which knows exactly how many
+/// of its packets were delivered, rather than guessing from counts that mix servers together.
+///
+/// A packet that arrives more than once is only passed on the first time.
+/// A dual-stack client gets every packet twice from a server that sends to both an IPv4 and an IPv6 address.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Peer;
+
+/// How far below the highest sequence number we remember which numbers we have not seen.
+/// Packets that arrive later than this are counted as duplicates.
+const WINDOW: u64 = 1 << 16;
+
+/// The largest number of gaps that we remember for a server.
+/// When there are more, the oldest ones are forgotten.
+const MAX_GAPS: usize = 4096;
+
+/// The largest number of gaps to report in one `Ping`, so that it fits in a datagram.
+/// The rest are reported in the next one.
+pub const MAX_REPORTED_GAPS: usize = 64;
+
+/// A run of consecutive sequence numbers that were not received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    /// The first sequence number in the gap.
+    pub start: u32,
+    /// The number of sequence numbers in the gap.
+    pub length: u32,
+}
+
+/// What a client tells the server about the packets that it received from it since its last report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LossReport {
+    /// The number of packets received, not counting duplicates.
+    pub received: u64,
+    /// The packets that were not received.
+    pub gaps: Vec<SequenceGap>,
+}
+
+impl LossReport {
+    /// The number of packets in the gaps.
+    pub fn lost(&self) -> u64 {
+        self.gaps.iter().map(|gap| gap.length as u64).sum()
+    }
+}
+
+/// Counts of the packets received from a server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    /// The packets received, not counting duplicates.
+    pub received: u64,
+    /// The packets that were reported as lost.
+    pub lost: u64,
+    /// The packets that were received more than once.
+    pub duplicates: u64,
+    /// The packets that were received after a packet with a higher sequence number.
+    pub reordered: u64,
+    /// The number of times that the sequence numbers started over, because the server restarted.
+    pub restarts: u64,
+}
+
+/// The sequence numbers received from a single server.
+///
+/// Sequence numbers are 32 bits on the wire, but are extended to 64 bits here,
+/// so that they can wrap around.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    /// One past the highest sequence number received, or `None` before the first packet.
+    next: Option<u64>,
+    /// The gaps below `next`, from their first sequence number to one past their last.
+    gaps: BTreeMap<u64, u64>,
+    /// The gaps from here on have not been reported yet.
+    reported_up_to: u64,
+    /// The packets received since the last report.
+    received_since_report: u64,
+    stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    /// Record a packet with the given sequence number.
+    ///
+    /// Returns whether it is the first time that the packet was received.
+    pub fn record(&mut self, sequence: u32) -> bool {
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                self.start_at(sequence);
+                return true;
+            }
+        };
+        // The extended sequence number that is closest to what we have seen
+        let offset = sequence.wrapping_sub(next as u32) as i32 as i64;
+        let sequence = match next.checked_add_signed(offset) {
+            Some(sequence) => sequence,
+            None => {
+                self.restart(sequence);
+                return true;
+            }
+        };
+        if sequence >= next + WINDOW || sequence + WINDOW < next {
+            // Too far from what we have seen for a lost or late packet: the server started over
+            self.restart(sequence as u32);
+            return true;
+        }
+
+        if sequence >= next {
+            if sequence > next {
+                self.gaps.insert(next, sequence);
+            }
+            self.next = Some(sequence + 1);
+            self.received();
+        } else if let Some((&start, &end)) = self
+            .gaps
+            .range(..=sequence)
+            .next_back()
+            .filter(|(_, &end)| sequence < end)
+        {
+            // A late packet, which fills in part of a gap
+            self.gaps.remove(&start);
+            if start < sequence {
+                self.gaps.insert(start, sequence);
+            }
+            if sequence + 1 < end {
+                self.gaps.insert(sequence + 1, end);
+            }
+            self.stats.reordered += 1;
+            self.received();
+        } else {
+            self.stats.duplicates += 1;
+            return false;
+        }
+
+        // Forget the gaps that are too old to be filled in
+        let oldest = self.next.unwrap().saturating_sub(WINDOW);
+        while let Some((&start, &end)) = self.gaps.first_key_value() {
+            if end > oldest && self.gaps.len() <= MAX_GAPS {
+                break;
+            }
+            self.gaps.remove(&start);
+            if start >= self.reported_up_to {
+                // It will never be reported now, so count it here
+                self.stats.lost += end - start;
+                self.reported_up_to = end;
+            }
+        }
+        true
+    }
+
+    /// Report the packets received and lost since the last report.
+    ///
+    /// A gap is only reported once. A packet in it may still arrive later,
+    /// and then counts as received and reordered.
+    pub fn report(&mut self) -> LossReport {
+        let mut gaps = vec![];
+        let next = self.next.unwrap_or(0);
+        let mut reported_up_to = next;
+        for (&start, &end) in self.gaps.range(self.reported_up_to..) {
+            if gaps.len() == MAX_REPORTED_GAPS {
+                reported_up_to = start;
+                break;
+            }
+            gaps.push(SequenceGap {
+                start: start as u32,
+                length: (end - start) as u32,
+            });
+        }
+        self.reported_up_to = reported_up_to;
+        let report = LossReport {
+            received: std::mem::take(&mut self.received_since_report),
+            gaps,
+        };
+        self.stats.lost += report.lost();
+        report
+    }
+
+    /// The counts of the packets received so far.
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+
+    fn received(&mut self) {
+        self.stats.received += 1;
+        self.received_since_report += 1;
+    }
+
+    /// Start tracking from the first packet, whose sequence number is given.
+    fn start_at(&mut self, sequence: u32) {
+        // Leave room below, so that late packets don't go below zero
+        let sequence = (1 << 32) + sequence as u64;
+        self.next = Some(sequence + 1);
+        self.reported_up_to = sequence + 1;
+        self.gaps.clear();
+        self.received();
+    }
+
+    fn restart(&mut self, sequence: u32) {
+        self.stats.restarts += 1;
+        self.start_at(sequence);
+    }
+}
+
+/// The sequence trackers of all the servers that a listener hears from.
+/// This is shared between the listener, which records the packets, and whoever reports on them.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTrackers {
+    trackers: Arc<Mutex<HashMap<Peer, SequenceTracker>>>,
+}
+
+impl SequenceTrackers {
+    /// Forget all servers once there are this many, so that forged names can't use up all memory.
+    const MAX_PEERS: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a packet from the given peer.
+    ///
+    /// Returns whether it is the first time that the packet was received.
+    pub fn record(&self, peer: &Peer, sequence: u32) -> bool {
+        let mut trackers = self.trackers.lock().unwrap();
+        if !trackers.contains_key(peer) && trackers.len() >= Self::MAX_PEERS {
+            trackers.clear();
+        }
+        trackers.entry(peer.clone()).or_default().record(sequence)
+    }
+
+    /// Report on the packets from the given peer, if any had sequence numbers.
+    pub fn report(&self, peer: &Peer) -> Option<(LossReport, SequenceStats)> {
+        let mut trackers = self.trackers.lock().unwrap();
+        let tracker = trackers.get_mut(peer)?;
+        let report = tracker.report();
+        Some((report, tracker.stats()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gap(start: u32, length: u32) -> SequenceGap {
+        SequenceGap { start, length }
+    }
+
+    #[test]
+    fn test_loss_and_reordering() {
+        let mut tracker = SequenceTracker::default();
+        let first_times: Vec<bool> = [0, 1, 2, 5, 6, 4, 6, 10]
+            .into_iter()
+            .map(|sequence| tracker.record(sequence))
+            .collect();
+        assert_eq!(first_times, [true, true, true, true, true, true, false, true]);
+        let report = tracker.report();
+        assert_eq!(report.received, 7);
+        assert_eq!(report.gaps, [gap(3, 1), gap(7, 3)]);
+        let stats = tracker.stats();
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.lost, 4);
+
+        // Gaps are only reported once, even if a packet in them arrives late
+        tracker.record(8);
+        tracker.record(11);
+        let report = tracker.report();
+        assert_eq!(report.received, 2);
+        assert!(report.gaps.is_empty());
+        assert_eq!(tracker.stats().reordered, 2);
+    }
+
+    #[test]
+    fn test_wrap_around_and_restart() {
+        let mut tracker = SequenceTracker::default();
+        tracker.record(u32::MAX - 1);
+        tracker.record(1);
+        let report = tracker.report();
+        assert_eq!(report.gaps, [gap(u32::MAX, 2)]);
+        assert_eq!(report.lost(), 2);
+
+        // The server started over
+        tracker.record(1_000_000);
+        tracker.record(0);
+        tracker.record(2);
+        let report = tracker.report();
+        assert_eq!(report.gaps, [gap(1, 1)]);
+        assert_eq!(tracker.stats().restarts, 2);
+    }
+
+    #[test]
+    fn test_report_size_is_limited() {
+        let mut tracker = SequenceTracker::default();
+        for sequence in 0..(2 * MAX_REPORTED_GAPS as u32) {
+            tracker.record(2 * sequence);
+        }
+        let first = tracker.report();
+        assert_eq!(first.gaps.len(), MAX_REPORTED_GAPS);
+        let second = tracker.report();
+        assert_eq!(second.gaps.len(), MAX_REPORTED_GAPS - 1);
+        assert_eq!(second.gaps[0].start, first.gaps.last().unwrap().start + 2);
+        assert_eq!(second.received, 0);
+    }
+}
@@ -4,15 +4,106 @@ local field_version = ProtoField.uint16("rustudps.version", "Version", base.DEC)
 local field_name = ProtoField.string("rustudps.name", "Name")
 local field_length = ProtoField.uint32("rustudps.length", "Data length", base.DEC)
 local field_hash = ProtoField.bytes("rustudps.hash", "Hash")
+local field_checksum_algorithm = ProtoField.uint8("rustudps.checksum_algorithm", "Checksum algorithm", base.DEC, { [0] = "SHA-256", [1] = "CRC-32C", [2] = "XXH3" })
+local field_timestamp = ProtoField.uint64("rustudps.timestamp", "Timestamp (us)", base.DEC)
+local field_mac = ProtoField.bytes("rustudps.mac", "MAC")
+local field_salt = ProtoField.bytes("rustudps.salt", "Salt")
 local field_data = ProtoField.bytes("rustudps.data", "Data (messagepack)")
+local field_ciphertext = ProtoField.bytes("rustudps.ciphertext", "Encrypted data")
+local field_tag = ProtoField.bytes("rustudps.tag", "AEAD tag")
+local field_flags = ProtoField.uint8("rustudps.flags", "Flags", base.HEX)
+local field_mode = ProtoField.uint8("rustudps.mode", "Mode", base.DEC, { [0] = "Checksum", [1] = "Authenticated", [2] = "Encrypted" }, 0x03)
+local field_has_name = ProtoField.bool("rustudps.has_name", "Has name", 8, nil, 0x04)
+local field_compact_checksum_algorithm = ProtoField.uint8("rustudps.compact_checksum_algorithm", "Checksum algorithm", base.DEC, { [0] = "SHA-256", [1] = "CRC-32C", [2] = "XXH3" }, 0xf0)
+local field_sender = ProtoField.uint32("rustudps.sender", "Sender instance id", base.HEX)
+local field_has_sequence = ProtoField.bool("rustudps.has_sequence", "Has sequence number", 8, nil, 0x08)
+local field_sequence = ProtoField.uint32("rustudps.sequence", "Sequence number", base.DEC)
 
-proto_rustudps.fields = { field_version, field_name, field_data, field_length, field_hash }
+proto_rustudps.fields = { field_version, field_name, field_data, field_length, field_hash, field_timestamp, field_mac, field_salt, field_ciphertext, field_tag, field_checksum_algorithm, field_flags, field_mode, field_has_name, field_compact_checksum_algorithm, field_sender, field_has_sequence, field_sequence }
+
+-- Packets with the compact header, from version 4 on
+local function dissect_compact(buffer, pinfo, tree)
+    pinfo.cols.protocol = "RustUDPs"
+    local subtree = tree:add(proto_rustudps, buffer(), "Rust UDP Sender (compact header)")
+    local C = 4
+
+    -- The version is an 8-bit unsigned integer
+    subtree:add(field_version, buffer(C, 1))
+    C = C + 1
+
+    -- The flags hold the mode, whether there is a name, and the checksum algorithm
+    local flags = buffer(C, 1):uint()
+    local flags_tree = subtree:add(field_flags, buffer(C, 1))
+    flags_tree:add(field_mode, buffer(C, 1))
+    flags_tree:add(field_has_name, buffer(C, 1))
+    flags_tree:add(field_has_sequence, buffer(C, 1))
+    C = C + 1
+    local mode = bit.band(flags, 0x03)
+
+    -- The random instance id of the sender, which also stands in for its name
+    subtree:add(field_sender, buffer(C, 4))
+    C = C + 4
+
+    -- The packets of a server's broadcaster are numbered
+    if bit.band(flags, 0x08) ~= 0 then
+        subtree:add(field_sequence, buffer(C, 4))
+        C = C + 4
+    end
+
+    -- The name, if there is one, is prefixed with its length
+    if bit.band(flags, 0x04) ~= 0 then
+        local name_length = buffer(C, 1):uint()
+        subtree:add(field_name, buffer(C + 1, name_length))
+        C = C + 1 + name_length
+    end
+
+    if mode ~= 0 then
+        subtree:add(field_timestamp, buffer(C, 8))
+        C = C + 8
+    end
+
+    -- There is no length: the trailer takes up the end of the packet
+    local trailer_length
+    if mode == 2 then
+        subtree:add(field_salt, buffer(C, 4))
+        C = C + 4
+        trailer_length = 16
+        subtree:add(field_ciphertext, buffer(C, buffer:len() - C - trailer_length))
+    else
+        if mode == 1 then
+            trailer_length = 16
+        else
+            flags_tree:add(field_compact_checksum_algorithm, buffer(5, 1))
+            local checksum_lengths = { [0] = 32, [1] = 4, [2] = 8 }
+            trailer_length = checksum_lengths[bit.rshift(flags, 4)] or 0
+        end
+        -- The data is a messagepack array of the message tag and its fields
+        subtree:add(field_data, buffer(C, buffer:len() - C - trailer_length))
+    end
+    local trailer = buffer(buffer:len() - trailer_length, trailer_length)
+    if mode == 2 then
+        subtree:add(field_tag, trailer)
+    elseif mode == 1 then
+        subtree:add(field_mac, trailer)
+    else
+        subtree:add(field_hash, trailer)
+    end
+    return true
+end
 
 function proto_rustudps.dissector(buffer, pinfo, tree)
-    -- check that the first 8 bytes are "RustUDPs"
-    if buffer(0, 8):string() ~= "RustUDPs" then
+    if buffer:len() >= 4 and buffer(0, 4):string() == "RUDP" then
+        return dissect_compact(buffer, pinfo, tree)
+    end
+    -- check that the first 8 bytes are "RustUDPs", or "RustUDPa" for authenticated packets,
+    -- or "RustUDPe" for encrypted packets, or "RustUDPc" for packets with another checksum than SHA-256
+    local magic = buffer(0, 8):string()
+    if magic ~= "RustUDPs" and magic ~= "RustUDPa" and magic ~= "RustUDPe" and magic ~= "RustUDPc" then
         return false
     end
+    local authenticated = magic == "RustUDPa"
+    local encrypted = magic == "RustUDPe"
+    local checksummed = magic == "RustUDPc"
 
     pinfo.cols.protocol = "RustUDPs"
     local subtree = tree:add(proto_rustudps, buffer(), "Rust UDP Sender")
@@ -32,9 +123,42 @@ function proto_rustudps.dissector(buffer, pinfo, tree)
     subtree:add(field_length, buffer(C, 2), length)
     C = C + 2
 
-    -- The hash is a 32-byte array
-    subtree:add(field_hash, buffer(C, 32))
-    C = C + 32
+    if encrypted then
+        -- The timestamp is a 64-bit unsigned integer
+        subtree:add(field_timestamp, buffer(C, 8))
+        C = C + 8
+
+        -- The salt is 4 bytes; with the timestamp, it makes the nonce
+        subtree:add(field_salt, buffer(C, 4))
+        C = C + 4
+
+        -- The data can't be read without the key, and is followed by the 16-byte tag
+        subtree:add(field_ciphertext, buffer(C, length))
+        C = C + length
+        subtree:add(field_tag, buffer(C, 16))
+        return true
+    elseif authenticated then
+        -- The timestamp is a 64-bit unsigned integer
+        subtree:add(field_timestamp, buffer(C, 8))
+        C = C + 8
+
+        -- The MAC is a 32-byte array, in place of the hash
+        subtree:add(field_mac, buffer(C, 32))
+        C = C + 32
+    elseif checksummed then
+        -- The algorithm is one byte, and decides the length of the checksum
+        local algorithm = buffer(C, 1):uint()
+        subtree:add(field_checksum_algorithm, buffer(C, 1))
+        C = C + 1
+        local checksum_lengths = { [0] = 32, [1] = 4, [2] = 8 }
+        local checksum_length = checksum_lengths[algorithm] or 0
+        subtree:add(field_hash, buffer(C, checksum_length))
+        C = C + checksum_length
+    else
+        -- The hash is a 32-byte array
+        subtree:add(field_hash, buffer(C, 32))
+        C = C + 32
+    end
 
     -- The data is a messagepack array
     subtree:add(field_data, buffer(C, length))
@@ -25,6 +25,12 @@ pub(crate) struct HashOptions {
     /// File to write the hashlist to
     #[clap(short, long)]
     pub file: String,
+
+    /// Also record the hashes of the chunks of every file, for chunks of this many bytes,
+    /// so that clients can check every chunk as it arrives.
+    /// The server only uses them if it splits files into chunks of the same size: see its `--chunk-size`.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub chunk_size: Option<u16>,
 }
 
 #[derive(Parser, Debug)]
@@ -25,7 +25,7 @@ pub(crate) async fn make_hash(options: HashOptions) {
 
     let (sender, handle) = walk::collect_entries();
     let path2 = path.clone();
-    walk::walk_directory_and_hash(path, path2, sender).await;
+    walk::walk_directory_and_hash(path, path2, options.chunk_size, sender).await;
     let hashlist = handle.await.expect("Failed to get hashlist from thread");
 
     println!("Writing hashlist to: {file:?}...");
@@ -37,7 +37,7 @@ fn print_discrepancy(expected: &FileHashItem, actual: &FileHashItem) {
     // test/path: <size>-<hexhash> vs <size>-<hexhash>
     println!(
         "{:?}: {}-{} vs {}-{}",
-        expected.path,
+        common::paths::display(&expected.path),
         expected.size,
         hex::encode(&expected.hash),
         actual.size,
@@ -75,7 +75,15 @@ pub(crate) async fn verify_hash(options: VerifyOptions) {
         if idx % 100 == 0 {
             debug!("Checked {} files out of {}", idx, hashlist.files.len());
         }
-        let path = path.join(&entry.path);
+        let relative = match common::paths::decode(&entry.path) {
+            Ok(relative) => relative,
+            Err(e) => {
+                println!("{:?}: {e}", common::paths::display(&entry.path));
+                errors += 1;
+                continue;
+            }
+        };
+        let path = path.join(&relative);
         if !path.exists() {
             if options.ignore_missing {
                 continue;
@@ -86,11 +94,20 @@ pub(crate) async fn verify_hash(options: VerifyOptions) {
             }
         }
         let metadata = std::fs::metadata(&path).expect("Failed to get file metadata");
-        let hash = walk::get_file_hash(&path).await;
+        // If the hashlist has the hashes of the chunks, check them too
+        let chunk_size = entry.chunk_hashes.as_ref().map(|chunk_hashes| chunk_hashes.chunk_size);
+        let (hash, mut chunk_hashes) = walk::get_file_hashes(&path, chunk_size).await;
+        // Hashlists from before rolling checksums don't have them, so there is nothing to compare
+        if let (Some(expected), Some(actual)) = (&entry.chunk_hashes, &mut chunk_hashes) {
+            if expected.checksums.is_empty() {
+                actual.checksums.clear();
+            }
+        }
         let actual = FileHashItem {
             path: entry.path.clone(),
             size: metadata.len(),
             hash: hash.to_vec(),
+            chunk_hashes,
         };
         if entry != &actual {
             print_discrepancy(entry, &actual);
@@ -98,7 +115,7 @@ pub(crate) async fn verify_hash(options: VerifyOptions) {
         }
 
         if !options.ignore_new {
-            seen_paths.insert(PathBuf::from(&entry.path));
+            seen_paths.insert(relative);
         }
     }
 
@@ -132,12 +149,15 @@ pub(crate) async fn verify_hash(options: VerifyOptions) {
                             // Get the file's size and hash
                             let hash = walk::get_file_hash(base.join(path)).await;
                             let size = metadata.len();
+                            let encoded = common::paths::encode(path)
+                                .unwrap_or_else(|_| path.to_string_lossy().into_owned().into_bytes());
                             print_discrepancy(
-                                &FileHashItem::nonexistent(path.to_str().unwrap()),
+                                &FileHashItem::nonexistent(&encoded),
                                 &FileHashItem {
-                                    path: path.to_str().unwrap().to_string(),
+                                    path: encoded.clone(),
                                     size,
                                     hash: hash.to_vec(),
+                                    chunk_hashes: None,
                                 },
                             );
                             errors += 1;
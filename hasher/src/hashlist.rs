@@ -17,11 +17,21 @@ pub struct HashList {
     pub files: Vec<FileHashItem>,
 }
 
+impl HashList {
+    /// Read a hashlist from a file written by `hasher hash`.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, rmp_serde::decode::Error> {
+        let file = std::fs::File::open(path).map_err(rmp_serde::decode::Error::InvalidDataRead)?;
+        rmp_serde::from_read(file)
+    }
+}
+
 /// A FileHashItem is a structure that stores a file's name, length, and hash.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct FileHashItem {
-    /// The relative path of the file.
-    pub path: String,
+    /// The relative path of the file, as bytes with `/` between the names:
+    /// see `common::paths`. Hashlists from before this are still read.
+    #[serde(with = "common::paths::str_or_bytes")]
+    pub path: Vec<u8>,
 
     /// The size of the file in bytes.
     pub size: u64,
@@ -30,22 +40,46 @@ pub struct FileHashItem {
     /// The kind of hash is specified by the hash_algorithm field in the HashList.
     #[serde(with = "serde_bytes")]
     pub hash: Vec<u8>,
+
+    /// The hashes of the chunks of the file, if they were recorded.
+    /// Hashlists from before these are still read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_hashes: Option<ChunkHashes>,
+}
+
+/// The hashes of the chunks of a file, which let clients check every chunk as it arrives.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChunkHashes {
+    /// The size of the chunks.
+    /// Only a server that splits files into chunks of this size can use the hashes.
+    pub chunk_size: u16,
+
+    /// The SHA-256 hashes of the chunks, one after the other: see `common::chunk_hashes`.
+    #[serde(with = "serde_bytes")]
+    pub hashes: Vec<u8>,
+
+    /// The rolling checksums of the chunks, one after the other: see `common::rolling`.
+    /// These let clients find the chunks in an old copy of the file.
+    /// Hashlists from before these have none, and this is empty.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<u8>,
 }
 
 impl FileHashItem {
     /// Create a FileHashItem representing a non-existent file.
     /// It has a size of 0, and a hash of all zeros
     /// (the real hash of an empty file is different).
-    pub fn nonexistent(path: &str) -> Self {
+    pub fn nonexistent(path: &[u8]) -> Self {
         Self {
-            path: path.to_string(),
+            path: path.to_vec(),
             size: 0,
             hash: vec![0; 32],
+            chunk_hashes: None,
         }
     }
 
     /// Create a FileHashItem representing a non-existent file with the empty path.
     pub fn nonexistent_empty_path() -> Self {
-        Self::nonexistent("")
+        Self::nonexistent(b"")
     }
 }
@@ -1,11 +1,10 @@
 use async_recursion::async_recursion;
 /// Module for walking a directory and hashing its contents.
-use sha2::Digest;
 use std::path::{Path, PathBuf};
 
-use tokio::{io::AsyncReadExt, sync::mpsc::Sender, task::JoinHandle};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
 
-use crate::hashlist::{FileHashItem, HashList};
+use crate::hashlist::{ChunkHashes, FileHashItem, HashList};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -29,32 +28,46 @@ pub fn collect_entries() -> (Sender<FileHashItem>, JoinHandle<HashList>) {
 
 /// Get the Sha256 hash of a file.
 pub async fn get_file_hash(path: impl AsRef<Path>) -> [u8; 32] {
-    let mut file = tokio::fs::File::open(path)
+    common::filesystem::hash_file(path)
         .await
-        .expect("Unable to open file");
-    let mut hasher = sha2::Sha256::new();
-    let mut buf = [0; 1024];
+        .expect("Unable to read file")
+}
 
-    loop {
-        let n = file.read(&mut buf).await.expect("Unable to read file");
-        if n == 0 {
-            break;
+/// Get the Sha256 hash of a file, and if a chunk size is given, the hashes of its chunks of that size.
+pub async fn get_file_hashes(
+    path: impl AsRef<Path>,
+    chunk_size: Option<u16>,
+) -> ([u8; 32], Option<ChunkHashes>) {
+    match chunk_size {
+        Some(chunk_size) => {
+            let (hash, hashes, checksums) =
+                common::filesystem::hash_file_and_chunks(path, chunk_size.into())
+                    .await
+                    .expect("Unable to read file");
+            let chunk_hashes = ChunkHashes {
+                chunk_size,
+                hashes,
+                checksums,
+            };
+            (hash, Some(chunk_hashes))
         }
-        hasher.update(&buf[..n]);
+        None => (get_file_hash(path).await, None),
     }
-
-    let mut hash = [0; 32];
-    hash.copy_from_slice(hasher.finalize().as_slice());
-    hash
 }
 
 /// Walk a directory.
 /// For subdirectories, spawn a new thread to walk them.
 /// For files, hash them and send the result to the main thread.
+/// If a chunk size is given, also hash their chunks of that size.
 ///
 /// For the initial invocation, both the `path` and the `base` should be the same.
 #[async_recursion]
-pub async fn walk_directory_and_hash(path: PathBuf, base: PathBuf, sender: Sender<FileHashItem>) {
+pub async fn walk_directory_and_hash(
+    path: PathBuf,
+    base: PathBuf,
+    chunk_size: Option<u16>,
+    sender: Sender<FileHashItem>,
+) {
     let mut pending_inner_tasks = vec![];
     let mut dir_listing = tokio::fs::read_dir(&path)
         .await
@@ -69,20 +82,29 @@ pub async fn walk_directory_and_hash(path: PathBuf, base: PathBuf, sender: Sende
                 debug!("Found directory: {:?}", path);
                 let sender_copy = sender.clone();
                 let base_copy = base.clone();
-                let handle = tokio::spawn(walk_directory_and_hash(path, base_copy, sender_copy));
+                let handle = tokio::spawn(walk_directory_and_hash(
+                    path,
+                    base_copy,
+                    chunk_size,
+                    sender_copy,
+                ));
                 pending_inner_tasks.push(handle);
             } else {
                 debug!("Found file: {:?}", path);
+                let relative = match common::paths::encode(path.strip_prefix(&base).unwrap()) {
+                    Ok(relative) => relative,
+                    Err(e) => {
+                        warn!("Skipping file {:?}: {e}", path);
+                        continue;
+                    }
+                };
                 let file_size = tokio::fs::metadata(&path).await.unwrap().len();
+                let (hash, chunk_hashes) = get_file_hashes(&path, chunk_size).await;
                 let item = FileHashItem {
-                    path: path
-                        .strip_prefix(base.clone())
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string(),
+                    path: relative,
                     size: file_size,
-                    hash: get_file_hash(&path).await.to_vec(),
+                    hash: hash.to_vec(),
+                    chunk_hashes,
                 };
                 sender.send(item).await.unwrap();
             }
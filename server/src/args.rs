@@ -1,4 +1,7 @@
 use clap::Parser;
+use common::{checksum::ChecksumAlgorithm, compression::Compression};
+
+use crate::mtu;
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
@@ -10,9 +13,54 @@ pub(crate) struct Args {
     #[clap(short, long)]
     pub listen_port: Option<u16>,
 
-    /// IP addresses to transmit to, comma-separated (use broadcast addresses)
-    #[clap(short, long, default_value = "255.255.255.255")]
-    pub ip: String,
+    /// IP addresses to transmit to, comma-separated (use broadcast or multicast addresses).
+    /// Link-local IPv6 addresses can name their interface, like `ff02::1%eth0`.
+    /// If unset, this is `255.255.255.255,ff02::1`, which reaches IPv4 clients by broadcast
+    /// and IPv6 clients by link-local multicast;
+    /// or nothing, with `--multicast-group` or `--all-interfaces`.
+    #[clap(short, long)]
+    pub ip: Option<String>,
+
+    /// Also transmit to the broadcast address of every subnet of every network interface,
+    /// and to the IPv6 all-nodes group on every interface.
+    /// This replaces the default `--ip`.
+    #[clap(long)]
+    pub all_interfaces: bool,
+
+    /// Multicast group to transmit to, instead of broadcasting.
+    /// Clients must join the group with the same `--multicast-group`.
+    /// Use a group in 232.0.0.0/8 or ff3x::/32 for clients to receive with source-specific multicast.
+    #[clap(long)]
+    pub multicast_group: Option<String>,
+
+    /// How many routers multicast packets can cross.
+    /// The default keeps them on the local network.
+    #[clap(long, default_value_t = 1)]
+    pub multicast_ttl: u32,
+
+    /// Whether the multicast packets we send are also delivered to this host.
+    /// If unset, the kernel's default is used, which is true.
+    #[clap(long)]
+    pub multicast_loop: Option<bool>,
+
+    /// Size of the kernel's receive buffer for our sockets, in bytes.
+    /// Raise it if packets are dropped when they arrive in bursts.
+    /// The kernel caps it at net.core.rmem_max.
+    #[clap(long)]
+    pub recv_buffer: Option<usize>,
+
+    /// Size of the kernel's send buffer for our sockets, in bytes.
+    /// The kernel caps it at net.core.wmem_max.
+    #[clap(long)]
+    pub send_buffer: Option<usize>,
+
+    /// DSCP class to mark our packets with, from 0 to 63, for example 8 (CS1) for low priority.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..64))]
+    pub dscp: Option<u8>,
+
+    /// TTL (IPv4) or hop limit (IPv6) of the unicast and broadcast packets we send.
+    #[clap(long)]
+    pub ttl: Option<u32>,
 
     /// Name of this server. Will show up on clients. If unset, generated randomly.
     #[clap(short, long)]
@@ -27,4 +75,68 @@ pub(crate) struct Args {
     /// consider creating a hashlist file if you have a lot of files.
     #[clap(long, short_alias = 'f')]
     pub hashlist: Option<String>,
+
+    /// When building the hashlist in memory, also hash every chunk of every file,
+    /// so that clients can check every chunk as it arrives, and take the chunks of changed files from their old copies.
+    /// This keeps 36 bytes in memory for every chunk of every file.
+    /// A hashlist file has chunk hashes if `hasher hash` was given `--chunk-size`.
+    #[clap(long, conflicts_with = "hashlist")]
+    pub chunk_hashes: bool,
+
+    /// Size of the chunks that files are split into, in bytes.
+    /// If unset, the largest size whose packets fit in `--max-datagram-size` is used.
+    #[clap(long)]
+    pub chunk_size: Option<u16>,
+
+    /// Largest datagram to send, in bytes.
+    /// The default is the largest that clients older than this option can receive.
+    /// Once every client is newer, raise it to 1472 to fill a 1500-byte Ethernet frame, or more for jumbo frames.
+    #[clap(long, default_value_t = mtu::LEGACY_DATAGRAM_SIZE)]
+    pub max_datagram_size: usize,
+
+    /// Use the largest datagram that the kernel's routes to the addresses in `--ip` carry without fragmentation,
+    /// from the MTU of their interfaces, instead of `--max-datagram-size`.
+    /// Nothing is sent to measure it, so a smaller MTU further along the path is not noticed.
+    /// Like raising `--max-datagram-size`, this cuts off clients older than that option.
+    #[clap(long)]
+    pub route_mtu: bool,
+
+    /// Number of chunks in each forward error correction block.
+    /// Repair shards are computed over the chunks of a block.
+    #[clap(long, default_value_t = 32)]
+    pub fec_block_size: u16,
+
+    /// Number of repair shards sent per chunk, for example 0.1 for 10% overhead.
+    /// If set to 0, no repair shards will be sent.
+    #[clap(long, default_value_t = 0.1)]
+    pub fec_overhead: f64,
+
+    /// Compression for file chunks: none, zstd or lz4.
+    /// Each chunk is sent compressed only if that makes it smaller.
+    /// Clients older than this option always get uncompressed chunks.
+    #[clap(long, default_value_t = Compression::Zstd)]
+    pub compression: Compression,
+
+    /// Pre-shared passphrase to authenticate packets with.
+    /// If set, packets that are not authenticated with the same key are dropped,
+    /// so every server and client on the network must use the same key.
+    #[clap(long, conflicts_with = "psk_file")]
+    pub psk: Option<String>,
+
+    /// File containing the pre-shared key to authenticate packets with.
+    /// This works like `--psk`, but keeps the key out of the process list.
+    #[clap(long)]
+    pub psk_file: Option<String>,
+
+    /// Encrypt packets with the pre-shared key, so that file names and contents can't be read without it.
+    /// Packets from peers are accepted whether they are encrypted or only authenticated.
+    #[clap(long)]
+    pub encrypt: bool,
+
+    /// Checksum algorithm for the packets we send: sha256, crc32c or xxh3.
+    /// Packets from peers are accepted with any of these.
+    /// Defaults to sha256 for peers older than protocol version 4, which is all that the oldest ones understand,
+    /// and to crc32c for the others.
+    #[clap(long)]
+    pub checksum: Option<ChecksumAlgorithm>,
 }
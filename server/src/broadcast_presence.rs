@@ -11,7 +11,10 @@ pub fn broadcast_presence(
     my_port: u16,
 ) -> tokio::task::JoinHandle<()> {
     let mut interval = tokio::time::interval(Duration::from_secs(1));
-    let message = common::messages::Message::Announce { port: my_port };
+    let message = common::messages::Message::Announce {
+        port: my_port,
+        versions: common::magic::VersionRange::SUPPORTED,
+    };
     tokio::spawn(async move {
         loop {
             interval.tick().await;
@@ -1,7 +1,13 @@
-use common::messages::Message;
+use common::{
+    batch::{BatchSender, MAX_BATCH},
+    magic::{make_magic_packet_into, make_sequenced_packet_into, COMPACT_VERSION, MIN_VERSION, VERSION},
+    messages::Message,
+    networking::SocketOptions,
+    Identity,
+};
 /// Module to deal with broadcasting messages to the network.
 use std::net::SocketAddr;
-use tokio::{select, sync::mpsc, net::UdpSocket};
+use tokio::{select, sync::{mpsc, watch}, net::UdpSocket};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -10,37 +16,151 @@ use crate::rate_limiter::RateLimiter;
 
 pub type MessageSender = mpsc::Sender<Message>;
 
+/// The addresses of one address family, and the socket to send to them from.
+struct Target {
+    socket: UdpSocket,
+    addrs: Vec<SocketAddr>,
+    batch_sender: BatchSender,
+}
+
+impl Target {
+    /// Make a target for every address family that there are addresses of.
+    async fn for_addrs(addrs: &[SocketAddr], options: &SocketOptions) -> Vec<Target> {
+        let (v4_addrs, v6_addrs): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|addr| addr.is_ipv4());
+        let mut targets = vec![];
+        for (bind_addr, addrs) in [("0.0.0.0:0", v4_addrs), ("[::]:0", v6_addrs)] {
+            if addrs.is_empty() {
+                continue;
+            }
+            let socket = UdpSocket::bind(bind_addr).await.unwrap();
+            socket.set_broadcast(true).unwrap();
+            options.apply(&socket).expect("Failed to set socket options");
+            let batch_sender = BatchSender::new(&socket);
+            targets.push(Target {
+                socket,
+                addrs,
+                batch_sender,
+            });
+        }
+        targets
+    }
+}
+
 /// Make a channel that will broadcast the messages it receives to all given addresses,
-/// providing the specified name,
+/// which can be IPv4 or IPv6,
+/// from the specified identity,
 /// and rate-limiting the messages to the given speed.
+/// The sockets that the messages are sent from get the given options.
+///
+/// Every packet in a version with sequence numbers gets the next one, from 0 (see `common::sequence`),
+/// so that clients can tell exactly which ones they lost.
+///
+/// Messages are encoded in the protocol version currently in `version`.
+/// Messages that do not exist in that version are dropped.
+/// `Announce` messages are the exception: they are sent once in every supported version,
+/// so that clients of any version can find us.
 ///
 /// Produces two MessageSenders. The first one is for important messages that should be sent
 /// immediately, and the second one is for normal messages that should be rate-limited.
+/// Normal messages that are queued up together are sent in one batch,
+/// of as many as the rate limiter lets go out together, once it lets the whole batch go.
 pub fn make_broadcaster(
     addrs: Vec<SocketAddr>,
-    name: &str,
+    identity: &Identity,
     mut rate_limiter: RateLimiter,
+    version: watch::Receiver<u16>,
+    options: SocketOptions,
 ) -> (MessageSender, MessageSender) {
-    let (sender, mut receiver) = mpsc::channel(100);
-    let (vip_sender, mut vip_receiver) = mpsc::channel(100);
-    let name = name.to_string();
+    let (sender, mut receiver) = mpsc::channel::<Message>(100);
+    let (vip_sender, mut vip_receiver) = mpsc::channel::<Message>(100);
+    let identity = identity.clone();
     tokio::spawn(async move {
-        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-        socket.set_broadcast(true).unwrap();
+        let mut targets = Target::for_addrs(&addrs, &options).await;
+        // Every packet is built in one of these buffers, so that sending does not allocate
+        let mut packet = Vec::new();
+        let mut batch = Vec::with_capacity(MAX_BATCH);
+        let mut packets: Vec<Vec<u8>> = vec![Vec::new(); MAX_BATCH];
+        let mut sequence: u32 = 0;
         loop {
             select! {
                 Some(message) = vip_receiver.recv() => {
                     // Important messages are sent immediately, without advancing the rate limiter
-                    common::networking::broadcast_message(&socket, &addrs, &name, &message).await.unwrap();
+                    if let Message::Announce { .. } = message {
+                        // Newest first: a client takes the first announcement it can parse,
+                        // and an announcement in an old version cannot list the newer versions.
+                        for version in (MIN_VERSION..=VERSION).rev() {
+                            make_packet(&identity, version, &mut sequence, &message, &mut packet)
+                                .expect("Announcements exist in every version");
+                            for target in &targets {
+                                common::networking::broadcast_packet(&target.socket, &target.addrs, &packet).await.unwrap();
+                            }
+                        }
+                        log::debug!("Message {message:?} on wire as VIP in all versions");
+                        continue;
+                    }
+                    let version = *version.borrow();
+                    if message.min_version() > version {
+                        log::trace!("Dropping {message:?}: not supported in version {version}");
+                        continue;
+                    }
+                    make_packet(&identity, version, &mut sequence, &message, &mut packet)
+                        .expect("Message is supported in this version");
+                    for target in &targets {
+                        common::networking::broadcast_packet(&target.socket, &target.addrs, &packet).await.unwrap();
+                    }
                     log::debug!("Message {message:?} on wire as VIP");
                 },
                 Some(message) = receiver.recv() => {
-                        rate_limiter.on_packet().await;
-                        common::networking::broadcast_message(&socket, &addrs, &name, &message).await.unwrap();
-                        log::debug!("Message {message:?} on wire");
+                        // Take whatever else is already waiting, to send it all at once,
+                        // up to as many as the rate limiter lets go out together
+                        batch.push(message);
+                        let batch_size = rate_limiter.burst_size().min(MAX_BATCH);
+                        while batch.len() < batch_size {
+                            match receiver.try_recv() {
+                                Ok(message) => batch.push(message),
+                                Err(_) => break,
+                            }
+                        }
+                        let version = *version.borrow();
+                        let mut count = 0;
+                        for message in batch.drain(..) {
+                            if message.min_version() > version {
+                                log::trace!("Dropping {message:?}: not supported in version {version}");
+                                continue;
+                            }
+                            make_packet(&identity, version, &mut sequence, &message, &mut packets[count])
+                                .expect("Message is supported in this version");
+                            count += 1;
+                            log::debug!("Message {message:?} on wire");
+                        }
+                        if count == 0 {
+                            continue;
+                        }
+                        rate_limiter.on_packets(count).await;
+                        for target in &mut targets {
+                            target.batch_sender.send(&target.socket, &packets[..count], &target.addrs).await.unwrap();
+                        }
                 },
             }
         }
     });
     (vip_sender, sender)
 }
+
+/// Make the packet of a message, with the next sequence number if the version has them.
+/// The packets of older versions don't use up a number, so that they don't look lost.
+fn make_packet(
+    identity: &Identity,
+    version: u16,
+    sequence: &mut u32,
+    message: &Message,
+    packet: &mut Vec<u8>,
+) -> Option<()> {
+    if version < COMPACT_VERSION {
+        return make_magic_packet_into(identity, version, message, packet);
+    }
+    make_sequenced_packet_into(identity, version, *sequence, message, packet)?;
+    *sequence = sequence.wrapping_add(1);
+    Some(())
+}
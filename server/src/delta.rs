@@ -0,0 +1,113 @@
+/// Leaving the chunks that clients took from old copies of files out of the broadcasts.
+///
+/// A client that takes chunks of a file from an old copy of it tells us which in `CopiedChunks` messages,
+/// and a chunk that every joined client took doesn't need to be broadcast.
+/// A client that didn't tell us about a file needs all of its chunks, like a client that can't tell us,
+/// so the broadcasts of a file only shrink while every joined client is taking chunks of it from an old copy.
+/// A client requests the chunks that it still misses, so a chunk that it loses again still reaches it.
+use std::collections::HashMap;
+
+use common::messages::ChunkRange;
+
+/// The chunks that clients took from old copies, by file index.
+/// The ranges of every file are in order, and don't overlap or touch.
+pub type CopiedChunks = HashMap<u32, Vec<ChunkRange>>;
+
+/// The chunks that every one of the clients took, by file.
+/// No chunks are left out if there are no clients.
+pub fn copied_by_all<'a>(mut clients: impl Iterator<Item = &'a CopiedChunks>) -> CopiedChunks {
+    let Some(mut common) = clients.next().cloned() else {
+        return CopiedChunks::new();
+    };
+    for copied in clients {
+        common.retain(|idx, ranges| {
+            *ranges = match copied.get(idx) {
+                Some(theirs) => intersect(ranges, theirs),
+                None => vec![],
+            };
+            !ranges.is_empty()
+        });
+    }
+    common
+}
+
+/// Whether a chunk is in the ranges, which must be in order and not overlap.
+pub fn contains(ranges: &[ChunkRange], chunk: u64) -> bool {
+    let after = ranges.partition_point(|range| range.first <= chunk);
+    after > 0 && chunk < ranges[after - 1].end()
+}
+
+/// Put the ranges that a client sent in order, merging the ones that overlap or touch.
+pub fn normalize(ranges: &[ChunkRange]) -> Vec<ChunkRange> {
+    let mut sorted: Vec<ChunkRange> = ranges
+        .iter()
+        .copied()
+        .filter(|range| range.count > 0)
+        .collect();
+    sorted.sort_by_key(|range| range.first);
+    let mut merged: Vec<ChunkRange> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.first <= last.end() => {
+                last.count = last.count.max(range.end() - last.first);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// The chunks that are in both sets of ranges, which must be in order and not overlap.
+fn intersect(a: &[ChunkRange], b: &[ChunkRange]) -> Vec<ChunkRange> {
+    let mut both = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let first = a[i].first.max(b[j].first);
+        let end = a[i].end().min(b[j].end());
+        if first < end {
+            both.push(ChunkRange {
+                first,
+                count: end - first,
+            });
+        }
+        if a[i].end() < b[j].end() {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    both
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(runs: &[(u64, u64)]) -> Vec<ChunkRange> {
+        runs.iter()
+            .map(|&(first, count)| ChunkRange { first, count })
+            .collect()
+    }
+
+    #[test]
+    fn test_copied_by_all() {
+        let one = CopiedChunks::from([
+            (0, normalize(&ranges(&[(10, 5), (0, 4), (3, 2), (20, 0)]))),
+            (1, ranges(&[(0, 100)])),
+        ]);
+        assert_eq!(one[&0], ranges(&[(0, 5), (10, 5)]));
+        let two = CopiedChunks::from([(0, ranges(&[(2, 10)]))]);
+
+        let all = copied_by_all([&one, &two].into_iter());
+        // Only the first client took chunks of file 1
+        assert_eq!(all, CopiedChunks::from([(0, ranges(&[(2, 3), (10, 2)]))]));
+        assert!(!contains(&all[&0], 1));
+        assert!(contains(&all[&0], 2));
+        assert!(contains(&all[&0], 4));
+        assert!(!contains(&all[&0], 5));
+        assert!(contains(&all[&0], 11));
+        assert!(!contains(&all[&0], 12));
+
+        assert!(copied_by_all(std::iter::empty()).is_empty());
+    }
+}
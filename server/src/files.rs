@@ -1,42 +1,182 @@
+use bytes::Bytes;
 use common::{
-    messages::{FileChunkData, FileListingFragment, Message},
-    MessageReceiver,
+    capabilities::Capabilities,
+    chunk_hashes::HASH_SIZE,
+    rolling::CHECKSUM_SIZE,
+    compression::Compression,
+    fec::FecParams,
+    messages::{
+        ChunkHashesData, CompressedFileChunkData, FileChunkData, FileChunkRepairData,
+        FileListingFragment, FileListingPartData, Message,
+    },
+    Identity, MessageReceiver,
 };
 use hasher::hashlist;
-use std::{path::PathBuf, str::FromStr};
+use std::path::PathBuf;
+use tokio::sync::watch;
+
+use crate::delta::CopiedChunks;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
 /// Code that deals with files and file transfers.
 
+/// The hashes of the chunks of a file, to send in `ChunkHashes` messages.
+#[derive(Debug, Clone)]
+pub struct ChunkHashes {
+    /// The SHA-256 hashes of the chunks, one after the other.
+    pub hashes: Bytes,
+    /// The rolling checksums of the chunks, one after the other,
+    /// unless the hashlist is from before these.
+    pub checksums: Option<Bytes>,
+}
+
 /// Convert a hashlist into a vector of FileListingFragments,
 /// used for transmitting the file listing.
-pub fn hashlist_into_file_listing(hashlist: hashlist::HashList) -> Vec<FileListingFragment> {
-    let mut file_listing = Vec::with_capacity(hashlist.files.len());
-    let len = hashlist.files.len();
-    for (idx, item) in hashlist.files.into_iter().enumerate() {
-        let path = PathBuf::from_str(&item.path).unwrap();
+/// Every file is split into chunks of the given size.
+///
+/// Also returns the hashes of the chunks of every file, if the hashlist has them for chunks of this size,
+/// with their rolling checksums if it has those.
+///
+/// Files with paths that would leave the directory, or that can't be stored on this platform, are left out.
+pub fn hashlist_into_file_listing(
+    hashlist: hashlist::HashList,
+    chunk_size: u16,
+) -> (Vec<FileListingFragment>, Vec<Option<ChunkHashes>>) {
+    let files: Vec<_> = hashlist
+        .files
+        .into_iter()
+        .filter(|item| match common::paths::decode(&item.path) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Leaving out {:?}: {e}", common::paths::display(&item.path));
+                false
+            }
+        })
+        .collect();
+    let mut file_listing = Vec::with_capacity(files.len());
+    let mut all_chunk_hashes = Vec::with_capacity(files.len());
+    let mut unusable_chunk_hashes = 0;
+    let len = files.len();
+    for (idx, item) in files.into_iter().enumerate() {
         let hash = item.hash.try_into().unwrap();
+        let chunk_count = item.size.div_ceil(chunk_size as u64);
+        let chunk_hashes = item.chunk_hashes.and_then(|chunk_hashes| {
+            if chunk_hashes.chunk_size == chunk_size
+                && chunk_hashes.hashes.len() as u64 == chunk_count * HASH_SIZE as u64
+            {
+                let checksums = (chunk_hashes.checksums.len() as u64
+                    == chunk_count * CHECKSUM_SIZE as u64)
+                    .then(|| Bytes::from(chunk_hashes.checksums));
+                Some(ChunkHashes {
+                    hashes: Bytes::from(chunk_hashes.hashes),
+                    checksums,
+                })
+            } else {
+                unusable_chunk_hashes += 1;
+                None
+            }
+        });
         let file_listing_fragment = FileListingFragment {
             idx: idx as u32,
             total: len as u32,
-            path: path.to_str().unwrap().to_string(),
+            path: item.path,
             hash,
             size: item.size,
-            chunk_size: 512,
+            chunk_size,
+            chunk_hashes: chunk_hashes.is_some(),
         };
         file_listing.push(file_listing_fragment);
+        all_chunk_hashes.push(chunk_hashes);
+    }
+    if unusable_chunk_hashes > 0 {
+        warn!(
+            "The hashlist has chunk hashes of {unusable_chunk_hashes} files that are not for chunks of {chunk_size} bytes: these are not sent"
+        );
+    }
+    (file_listing, all_chunk_hashes)
+}
+
+/// Split the file listing entries that don't fit in datagrams of the given size into parts.
+/// Returns the parts of every entry, which are empty for the entries that fit.
+pub fn split_large_listings(
+    entries: &[FileListingFragment],
+    identity: &Identity,
+    max_datagram_size: usize,
+) -> Vec<Vec<FileListingPartData>> {
+    let part_size = crate::mtu::largest_listing_part_size(identity, max_datagram_size)
+        .unwrap_or_else(|| panic!("Datagrams of {max_datagram_size} bytes are too small for file listings"));
+    let parts: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            if crate::mtu::fits(identity, &Message::FileListing(entry.clone()), max_datagram_size) {
+                vec![]
+            } else {
+                common::listing::split(entry, part_size)
+            }
+        })
+        .collect();
+    let split = parts.iter().filter(|parts| !parts.is_empty()).count();
+    if split > 0 {
+        info!("{split} file listing entries don't fit in a datagram, and are sent in parts");
     }
-    file_listing
+    parts
 }
 
+/// The messages to send a file listing entry in:
+/// its parts, if it has any and every client can put them together, or else the whole entry.
+fn listing_messages(
+    entry: &FileListingFragment,
+    parts: &[FileListingPartData],
+    capabilities: Capabilities,
+) -> Vec<Message> {
+    if parts.is_empty() || !capabilities.contains(Capabilities::LISTING_PARTS) {
+        return vec![Message::FileListing(entry.clone())];
+    }
+    parts.iter().cloned().map(Message::FileListingPart).collect()
+}
+
+/// The `ChunkHashes` message with the hashes and checksums of the chunks of a file from the given chunk on,
+/// as many as fit in a message.
+///
+/// Returns `None` if the chunk is past the end of the file.
+fn chunk_hashes_message(
+    idx: u32,
+    first_chunk: u64,
+    hashes: &ChunkHashes,
+    entry: &FileListingFragment,
+) -> Option<Message> {
+    let first = usize::try_from(first_chunk).ok()?;
+    let count = hashes.hashes.len() / HASH_SIZE;
+    if first >= count {
+        return None;
+    }
+    let last = (first + common::chunk_hashes::hashes_per_message(entry.chunk_size)).min(count);
+    Some(Message::ChunkHashes(ChunkHashesData {
+        idx,
+        first_chunk,
+        hashes: hashes.hashes.slice(first * HASH_SIZE..last * HASH_SIZE),
+        checksums: hashes
+            .checksums
+            .as_ref()
+            .map(|checksums| checksums.slice(first * CHECKSUM_SIZE..last * CHECKSUM_SIZE)),
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_transmissions(
     transmission_listener: MessageReceiver,
     directory_entries: Vec<FileListingFragment>,
+    listing_parts: Vec<Vec<FileListingPartData>>,
+    chunk_hashes: Vec<Option<ChunkHashes>>,
     broadcaster: crate::broadcaster::MessageSender,
     vip_broadcaster: crate::broadcaster::MessageSender,
     base: PathBuf,
+    fec: FecParams,
+    compression: Compression,
+    capabilities: watch::Receiver<Capabilities>,
+    copied: watch::Receiver<CopiedChunks>,
 ) {
     // Transmit all the directory entries over a period of 5 seconds
     // Also listen for file requests and transmit those out of order
@@ -49,53 +189,61 @@ pub async fn run_transmissions(
 
     let single_entry_duration = std::time::Duration::from_secs(5) / directory_entries.len() as u32;
     let directory_entries_out = directory_entries.clone();
+    let listing_parts_out = listing_parts.clone();
+    let capabilities_out = capabilities.clone();
     let broadcaster_out = vip_broadcaster.clone(); // File listings are sent to the VIP broadcaster
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(single_entry_duration);
         let mut current_index = 0;
         loop {
-            let message = tokio::select! {
+            let idx = tokio::select! {
                 _ = interval.tick() => {
-                    let entry = &directory_entries_out[current_index];
-                    let message = common::messages::Message::FileListing( entry.clone() );
+                    let idx = current_index;
                     if current_index == 0 {
                         debug!("Starting to transmit directory entries");
                     }
                     current_index += 1;
                     current_index %= directory_entries_out.len();
-                    message
+                    idx
                 },
                 Some((_, _, message)) = file_listing_request_listener.recv() => {
                     // Got a request for a file listing entry
-                    let message = match message {
+                    match message {
                         common::messages::Message::FileListingRequest{idx} => {
                             // If the idx is out of bounds, just send the last entry
                             debug!("Got request for file listing entry: {}", idx);
-                            let idx = idx.min(directory_entries_out.len() as u32 - 1);
-                            let entry = &directory_entries_out[idx as usize];
-                            common::messages::Message::FileListing( entry.clone() )
+                            idx.min(directory_entries_out.len() as u32 - 1) as usize
                         },
                         _ => unreachable!(),
-                    };
-                    message
+                    }
                 },
             };
-            debug!("Sending message: {:?}", message);
-            broadcaster_out.send(message).await.unwrap();
+            let capabilities = *capabilities_out.borrow();
+            for message in listing_messages(&directory_entries_out[idx], &listing_parts_out[idx], capabilities) {
+                debug!("Sending message: {:?}", message);
+                broadcaster_out.send(message).await.unwrap();
+            }
         }
     });
 
     // Listen for file requests and transmit those out of order
     debug!("Starting file chunk reply thread");
     let directory_entries_out = directory_entries.clone();
+    let chunk_hashes_out = chunk_hashes.clone();
     let broadcaster_out = broadcaster.clone();
 
     let (mut file_chunk_listener, listener) = common::channels::filter_branch_pred(
         listener,
-        |msg| matches!(msg.2, common::messages::Message::FileChunkRequest { .. }),
+        |msg| {
+            matches!(
+                msg.2,
+                Message::FileChunkRequest { .. } | Message::ChunkHashesRequest { .. }
+            )
+        },
         false,
     );
     let base_out = base.clone();
+    let capabilities_out = capabilities.clone();
 
     tokio::spawn(async move {
         let mut mmaps = std::collections::HashMap::new();
@@ -108,12 +256,18 @@ pub async fn run_transmissions(
                     } => {
                         // If the idx is out of bounds, send the last entry
                         if idx > directory_entries_out.len().try_into().unwrap() {
-                            broadcaster_out
-                                .send(Message::FileListing(
-                                    directory_entries_out.last().unwrap().clone(),
-                                ))
-                                .await
-                                .expect("Failed to send file listing entry");
+                            let capabilities = *capabilities_out.borrow();
+                            let messages = listing_messages(
+                                directory_entries_out.last().unwrap(),
+                                listing_parts.last().unwrap(),
+                                capabilities,
+                            );
+                            for message in messages {
+                                broadcaster_out
+                                    .send(message)
+                                    .await
+                                    .expect("Failed to send file listing entry");
+                            }
                             continue;
                         }
                         let entry = &directory_entries_out[idx as usize];
@@ -121,18 +275,33 @@ pub async fn run_transmissions(
                         let chunk_count = (entry.size + chunk_size - 1) / chunk_size;
                         // If the chunk_idx is out of bounds, send the last chunk
                         let chunk_idx = chunk_idx.min(chunk_count - 1);
-                        let path = base_out.join(&entry.path);
+                        let path = base_out.join(entry.local_path().unwrap());
                         let data_piece =
                             common::filesystem::read_chunk(&path, chunk_size, chunk_idx, &mut mmaps)
                                 .await
                                 .expect("Failed to read piece of file");
-                        let message = Message::FileChunk(FileChunkData {
+                        let message = make_chunk_message(
                             idx,
-                            chunk: chunk_idx,
-                            data: data_piece,
-                        });
+                            chunk_idx,
+                            data_piece.into(),
+                            compression,
+                            *capabilities_out.borrow(),
+                        );
                         broadcaster_out.send(message).await.unwrap();
                     }
+                    Message::ChunkHashesRequest { idx, first_chunk } => {
+                        debug!("Got request for chunk hashes {idx}-{first_chunk}");
+                        let message = directory_entries_out
+                            .get(idx as usize)
+                            .zip(chunk_hashes_out.get(idx as usize).and_then(Option::as_ref))
+                            .and_then(|(entry, hashes)| {
+                                chunk_hashes_message(idx, first_chunk, hashes, entry)
+                            });
+                        match message {
+                            Some(message) => broadcaster_out.send(message).await.unwrap(),
+                            None => debug!("No chunk hashes {idx}-{first_chunk} to send"),
+                        }
+                    }
                     _ => unreachable!(),
                 }
             }
@@ -151,22 +320,67 @@ pub async fn run_transmissions(
         // and another set is created for unsolicited chunks.
         // Perhaps we can share the same set of mmaps?
         // Requires locking: maybe too slow?
+        // Chunks of the current FEC block, kept until the repair shards are computed
+        let mut block = Vec::with_capacity(fec.block_size.into());
+        // Whether any chunk of the current FEC block was sent
+        let mut block_sent = false;
         loop {
             // get chunk contents
             let entry = &directory_entries_out[current_file_idx];
             let chunk_size = entry.chunk_size.into();
             let chunk_count = (entry.size + chunk_size - 1) / chunk_size;
-            let path = base_out.join(&entry.path);
-            let data_piece = common::filesystem::read_chunk(&path, chunk_size, current_chunk_idx, &mut mmaps)
+            let path = base_out.join(entry.local_path().unwrap());
+            let data_piece: Bytes = common::filesystem::read_chunk(&path, chunk_size, current_chunk_idx, &mut mmaps)
                 .await
-                .expect("Failed to read piece of file");
-            let message = Message::FileChunk(FileChunkData {
-                idx: current_file_idx as u32,
-                chunk: current_chunk_idx,
-                data: data_piece,
-            });
-            // send chunk contents
-            broadcaster.send(message).await.unwrap();
+                .expect("Failed to read piece of file")
+                .into();
+            if fec.overhead > 0.0 && chunk_count > 0 {
+                block.push(data_piece.clone());
+            }
+            // Leave out the chunks that every client took from an old copy of the file.
+            // They still go into the repair shards, as the clients have them to rebuild the others with.
+            let copied_by_all = copied
+                .borrow()
+                .get(&(current_file_idx as u32))
+                .is_some_and(|ranges| crate::delta::contains(ranges, current_chunk_idx));
+            if !copied_by_all {
+                // Send the hashes of the next chunks ahead of them, if every client can check the chunks
+                if let Some(hashes) = &chunk_hashes[current_file_idx] {
+                    let per_message = common::chunk_hashes::hashes_per_message(entry.chunk_size) as u64;
+                    if current_chunk_idx % per_message == 0
+                        && capabilities.borrow().contains(Capabilities::CHUNK_HASHES)
+                    {
+                        if let Some(message) = chunk_hashes_message(current_file_idx as u32, current_chunk_idx, hashes, entry) {
+                            broadcaster.send(message).await.unwrap();
+                        }
+                    }
+                }
+                let message = make_chunk_message(
+                    current_file_idx as u32,
+                    current_chunk_idx,
+                    data_piece,
+                    compression,
+                    *capabilities.borrow(),
+                );
+                // send chunk contents
+                broadcaster.send(message).await.unwrap();
+                block_sent = true;
+            }
+
+            // at the end of a block, send its repair shards
+            let block_done = block.len() >= fec.block_size.into()
+                || current_chunk_idx + 1 >= chunk_count;
+            if !block.is_empty() && block_done {
+                // Unless none of its chunks were sent, or some client can't rebuild chunks from them
+                if block_sent && capabilities.borrow().contains(Capabilities::FEC) {
+                    let first_chunk = current_chunk_idx + 1 - block.len() as u64;
+                    for message in make_repair_messages(current_file_idx as u32, first_chunk, &block, entry, fec) {
+                        broadcaster.send(message).await.unwrap();
+                    }
+                }
+                block.clear();
+                block_sent = false;
+            }
 
             // increment chunk (and file if necessary)
             current_chunk_idx += 1;
@@ -182,3 +396,64 @@ pub async fn run_transmissions(
     // Any other messages are ignored
     common::channels::drain(listener);
 }
+
+/// Wrap a chunk of a file into a message.
+///
+/// The chunk is compressed if compression makes it smaller,
+/// and if every client that receives the broadcast can decompress it.
+/// Repair shards are always computed over the uncompressed chunks.
+fn make_chunk_message(
+    idx: u32,
+    chunk: u64,
+    data: Bytes,
+    compression: Compression,
+    capabilities: Capabilities,
+) -> Message {
+    if compression != Compression::None
+        && capabilities.contains(Capabilities::for_compression(compression))
+    {
+        let compressed = compression.compress(&data);
+        if compressed.len() < data.len() {
+            return Message::FileChunkCompressed(CompressedFileChunkData {
+                idx,
+                chunk,
+                compression,
+                data: compressed.into(),
+            });
+        }
+    }
+    Message::FileChunk(FileChunkData { idx, chunk, data })
+}
+
+/// Compute the repair shards for a block of chunks of a file,
+/// and wrap them into messages.
+///
+/// `first_chunk` is the index of the first chunk in `block`.
+fn make_repair_messages(
+    idx: u32,
+    first_chunk: u64,
+    block: &[Bytes],
+    entry: &FileListingFragment,
+    fec: FecParams,
+) -> Vec<Message> {
+    let parity_shards = fec.parity_shards(block.len());
+    if parity_shards == 0 {
+        return vec![];
+    }
+    let repair = common::fec::make_repair_shards(block, parity_shards, entry.chunk_size.into())
+        .expect("Failed to compute repair shards");
+    repair
+        .into_iter()
+        .enumerate()
+        .map(|(shard, data)| {
+            Message::FileChunkRepair(FileChunkRepairData {
+                idx,
+                first_chunk,
+                data_shards: block.len() as u16,
+                parity_shards: parity_shards as u16,
+                shard: shard as u16,
+                data: data.into(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use common::{
+    capabilities::Capabilities,
+    magic::{VersionRange, VERSION},
+    messages::{JoinReason, Message},
+    Identity, MessageReceiver, Peer,
+};
+use tokio::{net::UdpSocket, sync::watch, time::Instant};
+
+use crate::delta::CopiedChunks;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// How long a client can go without pinging us before we take it to be gone, as if it had disconnected.
+/// Clients ping every second, so this only happens to clients that crashed or were killed.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client that joined.
+struct Client {
+    /// The protocol version that it talks to us in.
+    version: u16,
+    capabilities: Capabilities,
+    /// When it last joined or pinged us.
+    last_seen: Instant,
+    /// The chunks that it took from old copies of files.
+    copied: CopiedChunks,
+}
+
+/// Accept every client that asks to join, and keep track of the protocol versions
+/// and the capabilities of the clients.
+///
+/// Takes a listener of `JoinQuery`, `Disconnect`, `Ping` and `CopiedChunks` messages,
+/// and the listening socket to send the responses from.
+/// Every client talks to us in the highest version we have in common,
+/// but broadcasts must be understood by every client:
+/// so the lowest version used by any joined client is sent to `broadcast_version`,
+/// and the capabilities that all of them and we have to `broadcast_capabilities`.
+/// The chunks that all of them took from old copies are sent to `broadcast_copied`: see the `delta` module.
+///
+/// A client is forgotten when it disconnects, or when it stops pinging us for `CLIENT_TIMEOUT`,
+/// so that an old client that crashed doesn't hold the broadcasts back.
+pub async fn handle_joins(
+    mut join_query_listener: MessageReceiver,
+    socket: Arc<UdpSocket>,
+    identity: Identity,
+    send_port: u16,
+    broadcast_version: watch::Sender<u16>,
+    broadcast_capabilities: watch::Sender<Capabilities>,
+    broadcast_copied: watch::Sender<CopiedChunks>,
+) {
+    let mut clients: HashMap<Peer, Client> = HashMap::new();
+    let mut expiry_interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let (src, name, message) = tokio::select! {
+            message = join_query_listener.recv() => message.unwrap(),
+            _ = expiry_interval.tick() => {
+                clients.retain(|name, client| {
+                    let alive = client.last_seen.elapsed() < CLIENT_TIMEOUT;
+                    if !alive {
+                        info!("Client {name} stopped pinging us: forgetting it");
+                    }
+                    alive
+                });
+                update_broadcasts(
+                    &clients,
+                    &broadcast_version,
+                    &broadcast_capabilities,
+                    &broadcast_copied,
+                );
+                continue;
+            }
+        };
+        match message {
+            Message::JoinQuery {
+                versions,
+                capabilities,
+            } => {
+                debug!(
+                    "Received join query from {} ({}) supporting versions {:?} and capabilities {:?}",
+                    src, name, versions, capabilities
+                );
+                let version = match VersionRange::SUPPORTED.highest_common(&versions) {
+                    Some(version) => version,
+                    None => {
+                        warn!(
+                            "Ignoring join query from {} ({}): no common protocol version in {:?}",
+                            src, name, versions
+                        );
+                        continue;
+                    }
+                };
+                let message = Message::JoinResponse {
+                    reason: JoinReason::Accepted,
+                    versions: VersionRange::SUPPORTED,
+                    capabilities: Some(Capabilities::SUPPORTED),
+                };
+                let dest = common::networking::with_port(src, send_port);
+                common::networking::send_message(&socket, dest, &identity, version, &message)
+                    .await
+                    .ok();
+                debug!("Sent join response to {} in version {}", dest, version);
+                // Clients from before capabilities have the ones of their version
+                let capabilities = capabilities.unwrap_or_else(|| Capabilities::implied_by(version));
+                clients.insert(
+                    name,
+                    Client {
+                        version,
+                        capabilities,
+                        last_seen: Instant::now(),
+                        copied: CopiedChunks::new(),
+                    },
+                );
+            }
+            Message::Disconnect(reason) => {
+                debug!("Client {} ({}) disconnected: {:?}", src, name, reason);
+                clients.remove(&name);
+            }
+            Message::Ping { .. } => {
+                if let Some(client) = clients.get_mut(&name) {
+                    client.last_seen = Instant::now();
+                }
+            }
+            Message::CopiedChunks { idx, ranges } => {
+                if let Some(client) = clients.get_mut(&name) {
+                    let ranges = crate::delta::normalize(&ranges);
+                    if ranges.is_empty() {
+                        client.copied.remove(&idx);
+                    } else {
+                        client.copied.insert(idx, ranges);
+                    }
+                }
+            }
+            _ => {}
+        }
+        update_broadcasts(
+            &clients,
+            &broadcast_version,
+            &broadcast_capabilities,
+            &broadcast_copied,
+        );
+    }
+}
+
+/// Broadcast in the lowest version of the joined clients, with the capabilities that all of them have,
+/// leaving out the chunks that all of them took from old copies.
+fn update_broadcasts(
+    clients: &HashMap<Peer, Client>,
+    broadcast_version: &watch::Sender<u16>,
+    broadcast_capabilities: &watch::Sender<Capabilities>,
+    broadcast_copied: &watch::Sender<CopiedChunks>,
+) {
+    let version = clients.values().map(|client| client.version).min().unwrap_or(VERSION);
+    if *broadcast_version.borrow() != version {
+        info!("Broadcasting in protocol version {version}");
+        broadcast_version.send(version).ok();
+    }
+    let capabilities = clients
+        .values()
+        .fold(Capabilities::SUPPORTED, |common, client| {
+            common.intersection(client.capabilities)
+        });
+    if *broadcast_capabilities.borrow() != capabilities {
+        info!("Broadcasting with capabilities {capabilities}");
+        broadcast_capabilities.send(capabilities).ok();
+    }
+    let copied = crate::delta::copied_by_all(clients.values().map(|client| &client.copied));
+    if *broadcast_copied.borrow() != copied {
+        debug!(
+            "Leaving chunks of {} files that every client copied out of the broadcasts",
+            copied.len()
+        );
+        broadcast_copied.send(copied).ok();
+    }
+}
@@ -1,7 +1,10 @@
 mod args;
 mod broadcast_presence;
 mod broadcaster;
+mod delta;
 mod files;
+mod join;
+mod mtu;
 mod rate_limiter;
 
 use std::{net::SocketAddr, path::PathBuf};
@@ -28,6 +31,27 @@ async fn main() {
 
     info!("Starting server as {}", my_name);
 
+    let psk = common::auth::Psk::from_args(args.psk.as_deref(), args.psk_file.as_deref())
+        .expect("Failed to read pre-shared key file");
+    assert!(
+        psk.is_some() || !args.encrypt,
+        "Encryption requires a pre-shared key"
+    );
+    if psk.is_some() {
+        if args.encrypt {
+            info!("Encrypting packets with a pre-shared key");
+        } else {
+            info!("Authenticating packets with a pre-shared key");
+        }
+    }
+    let identity = common::Identity {
+        name: my_name.clone(),
+        instance: common::make_instance_id(),
+        psk,
+        encrypt: args.encrypt,
+        checksum: args.checksum,
+    };
+
     let send_port = args.send_port;
 
     let base = PathBuf::from(args.dir);
@@ -37,53 +61,112 @@ async fn main() {
         None => send_port,
     };
 
+    // Without --ip, broadcast, unless we multicast or find the addresses of the interfaces instead
+    let ip = match args.ip {
+        Some(ip) => ip,
+        None if args.multicast_group.is_some() || args.all_interfaces => String::new(),
+        None => "255.255.255.255,ff02::1".to_string(),
+    };
+    let mut addrs: Vec<SocketAddr> = ip
+        .split(',')
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| common::networking::parse_addr(addr, send_port).unwrap())
+        .collect();
+    if let Some(group) = args.multicast_group {
+        common::multicast::MulticastGroup::parse(&group, None).unwrap();
+        addrs.push(common::networking::parse_addr(&group, send_port).unwrap());
+    }
+    if args.all_interfaces {
+        let interface_addrs = common::interfaces::broadcast_addrs(send_port)
+            .expect("Failed to list network interfaces");
+        addrs.extend(interface_addrs);
+    }
     let mut broadcast_addrs: Vec<SocketAddr> = vec![];
-    for addr in args.ip.split(',') {
-        let socket_addr = format!("{addr}:{send_port}");
-        broadcast_addrs.push(socket_addr.parse().unwrap());
+    for socket_addr in addrs {
+        // The default addresses include IPv6, which this host may not have
+        match common::networking::check_route(socket_addr) {
+            Ok(()) => broadcast_addrs.push(socket_addr),
+            Err(e) => warn!("Not sending to {socket_addr}: {e}"),
+        }
     }
+    assert!(
+        !broadcast_addrs.is_empty(),
+        "None of the addresses to transmit to can be sent to"
+    );
+    info!("Sending to {broadcast_addrs:?}");
 
+    // Listen on IPv6 and IPv4 both
     let listen_addrs: Vec<SocketAddr> =
-        vec![SocketAddr::new("0.0.0.0".parse().unwrap(), listen_port)];
+        vec![SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), listen_port)];
+
+    let socket_options = common::networking::SocketOptions {
+        recv_buffer: args.recv_buffer,
+        send_buffer: args.send_buffer,
+        dscp: args.dscp,
+        ttl: args.ttl,
+        multicast_ttl: Some(args.multicast_ttl),
+        multicast_loop: args.multicast_loop,
+    };
 
     // Create a listener
-    let listener = common::networking::make_listener(listen_addrs.clone(), &my_name);
+    let listen_options = common::networking::ListenOptions {
+        socket: socket_options.clone(),
+        ..Default::default()
+    };
+    let (listener, listen_socket) =
+        common::networking::make_listener(listen_addrs.clone(), &identity, &listen_options);
 
     let rate_limiter = rate_limiter::RateLimiter::new(100, 100000);
     let recv_stat_collector = rate_limiter.get_collector();
 
+    // Broadcasts are sent in the lowest protocol version of any joined client,
+    // and only use the capabilities that every joined client has
+    let (broadcast_version_sender, broadcast_version) =
+        tokio::sync::watch::channel(common::magic::VERSION);
+    let (broadcast_capabilities_sender, broadcast_capabilities) =
+        tokio::sync::watch::channel(common::capabilities::Capabilities::SUPPORTED);
+    // Chunks that every joined client took from an old copy are left out of the broadcasts
+    let (broadcast_copied_sender, broadcast_copied) =
+        tokio::sync::watch::channel(delta::CopiedChunks::new());
+
     // Create a broadcaster
-    let (vip_broadcaster, broadcaster) =
-        crate::broadcaster::make_broadcaster(broadcast_addrs.clone(), &my_name, rate_limiter);
+    let (vip_broadcaster, broadcaster) = crate::broadcaster::make_broadcaster(
+        broadcast_addrs.clone(),
+        &identity,
+        rate_limiter,
+        broadcast_version.clone(),
+        socket_options,
+    );
 
     // Create a thread to broadcast our presence
     broadcast_presence::broadcast_presence(vip_broadcaster.clone(), listen_port);
 
-    // Make a listener of JoinQuery messages
-    let (mut join_query_listener, listener) = common::channels::filter_branch_pred(
+    // Make a listener of JoinQuery, Disconnect, Ping and CopiedChunks messages.
+    // The messages go on to the rest too, so that the pings are still answered below.
+    let (join_query_listener, listener) = common::channels::filter_branch_pred(
         listener,
-        |(_, _, message)| matches!(message, common::messages::Message::JoinQuery {}),
-        false,
+        |(_, _, message)| {
+            matches!(
+                message,
+                common::messages::Message::JoinQuery { .. }
+                    | common::messages::Message::Disconnect(_)
+                    | common::messages::Message::Ping { .. }
+                    | common::messages::Message::CopiedChunks { .. }
+            )
+        },
+        true,
     );
 
     // Loop and accept every connection
-
-    let my_name_out = my_name.clone();
-    tokio::spawn(async move {
-        loop {
-            let (src, name, message) = join_query_listener.recv().await.unwrap();
-            if let common::messages::Message::JoinQuery {} = message {
-                debug!("Received join query from {} ({})", src, name);
-                let message =
-                    common::messages::Message::JoinResponse(common::messages::JoinReason::Accepted);
-                let dest = SocketAddr::new(src.ip(), send_port);
-                common::networking::send_message(dest, &my_name_out, &message)
-                    .await
-                    .ok();
-                debug!("Sent join response to {}", dest);
-            }
-        }
-    });
+    tokio::spawn(join::handle_joins(
+        join_query_listener,
+        listen_socket,
+        identity.clone(),
+        send_port,
+        broadcast_version_sender,
+        broadcast_capabilities_sender,
+        broadcast_copied_sender,
+    ));
 
     // Respond to pings with pongs
     let (ping_listener, listener) = common::channels::filter_branch_pred(
@@ -106,14 +189,55 @@ async fn main() {
     // the broadcaster will block the ping reply thread, which will cause clients
     // to disconnect.
 
+    // Choose the chunk size, so that every chunk fits in a datagram
+    let max_datagram_size = if args.route_mtu {
+        let route_size = broadcast_addrs
+            .iter()
+            .map(|addr| common::networking::route_max_datagram_size(*addr))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|sizes| sizes.into_iter().min());
+        match route_size {
+            Some(size) => {
+                info!("Largest datagram that the routes carry without fragmentation is {size} bytes");
+                size
+            }
+            None => {
+                warn!(
+                    "Could not find the MTU, using the maximum datagram size of {} bytes",
+                    args.max_datagram_size
+                );
+                args.max_datagram_size
+            }
+        }
+    } else {
+        args.max_datagram_size
+    };
+    let largest_chunk_size = mtu::largest_chunk_size(&identity, max_datagram_size)
+        .unwrap_or_else(|| panic!("Datagrams of {max_datagram_size} bytes are too small for any chunk"));
+    let chunk_size = match args.chunk_size {
+        Some(chunk_size) => {
+            if chunk_size > largest_chunk_size {
+                warn!(
+                    "Chunks of {chunk_size} bytes may not fit in datagrams of {max_datagram_size} bytes: the largest that fits is {largest_chunk_size}"
+                );
+            }
+            chunk_size
+        }
+        None => largest_chunk_size,
+    };
+    assert!(chunk_size > 0, "Chunk size must be at least 1");
+    info!("Splitting files into chunks of {chunk_size} bytes");
+
     // Construct a list of file listing fragments
     let dir: PathBuf = base.clone();
     let file_listing_fragments;
+    let chunk_hashes;
     if let Some(hashlist) = args.hashlist {
         info!("Loading hashlist from {}", hashlist);
         let hashlist: PathBuf = hashlist.parse().unwrap();
         let hashlist = rmp_serde::from_read(std::fs::File::open(hashlist).unwrap()).unwrap();
-        file_listing_fragments = files::hashlist_into_file_listing(hashlist);
+        (file_listing_fragments, chunk_hashes) =
+            files::hashlist_into_file_listing(hashlist, chunk_size);
         debug!(
             "File listing collected, has {} fragments",
             file_listing_fragments.len()
@@ -122,21 +246,44 @@ async fn main() {
         warn!("Building in-memory hashlist, this may take a while");
         let (sender, handle) = walk::collect_entries();
         let dir2 = dir.clone();
-        walk::walk_directory_and_hash(dir, dir2, sender).await;
+        let chunk_hashes_size = args.chunk_hashes.then_some(chunk_size);
+        walk::walk_directory_and_hash(dir, dir2, chunk_hashes_size, sender).await;
         let hashlist = handle.await.expect("Failed to get hashlist from thread");
-        file_listing_fragments = files::hashlist_into_file_listing(hashlist);
+        (file_listing_fragments, chunk_hashes) =
+            files::hashlist_into_file_listing(hashlist, chunk_size);
         debug!(
             "File listing collected, has {} fragments",
             file_listing_fragments.len()
         );
     }
 
+    let fec = common::fec::FecParams {
+        block_size: args.fec_block_size,
+        overhead: args.fec_overhead,
+    };
+    assert!(fec.block_size >= 1, "FEC block size must be at least 1");
+    assert!(
+        (fec.block_size as usize) < common::fec::MAX_SHARDS,
+        "FEC block size must be less than {}",
+        common::fec::MAX_SHARDS
+    );
+    assert!(fec.overhead >= 0.0, "FEC overhead must not be negative");
+
+    let listing_parts =
+        files::split_large_listings(&file_listing_fragments, &identity, max_datagram_size);
+
     tokio::spawn(run_transmissions(
         listener,
         file_listing_fragments,
+        listing_parts,
+        chunk_hashes,
         broadcaster.clone(),
         vip_broadcaster.clone(),
         base,
+        fec,
+        args.compression,
+        broadcast_capabilities,
+        broadcast_copied,
     ));
 
     // Loop over packets
@@ -0,0 +1,127 @@
+/// Choosing the chunk size, so that file chunks fit in a datagram,
+/// and the size of the parts of file listing entries that don't.
+use bytes::Bytes;
+use common::{
+    compression::Compression,
+    magic::{make_sequenced_packet_into, MIN_VERSION, VERSION},
+    messages::{
+        CompressedFileChunkData, FileChunkData, FileChunkRepairData, FileListingPartData, Message,
+    },
+    Identity,
+};
+
+/// The largest datagram that every client can receive: older clients receive into a buffer of 1024 bytes,
+/// and any more of a datagram is cut off.
+pub const LEGACY_DATAGRAM_SIZE: usize = 1024;
+
+/// The size of the largest packet that a chunk of the given size can be sent in,
+/// in any protocol version.
+///
+/// This is the size with the least compressible data and the largest indices,
+/// so that the encoding is as long as it can be.
+/// Chunks are sent by the broadcaster, so their packets have sequence numbers.
+pub fn worst_case_packet_size(identity: &Identity, chunk_size: u16) -> usize {
+    let data = Bytes::from(vec![u8::MAX; chunk_size.into()]);
+    let messages = [
+        Message::FileChunk(FileChunkData {
+            idx: u32::MAX,
+            chunk: u64::MAX,
+            data: data.clone(),
+        }),
+        // Compressed chunks are only sent when they are smaller than the chunk size
+        Message::FileChunkCompressed(CompressedFileChunkData {
+            idx: u32::MAX,
+            chunk: u64::MAX,
+            compression: Compression::Zstd,
+            data: data.clone(),
+        }),
+        Message::FileChunkRepair(FileChunkRepairData {
+            idx: u32::MAX,
+            first_chunk: u64::MAX,
+            data_shards: u16::MAX,
+            parity_shards: u16::MAX,
+            shard: u16::MAX,
+            data,
+        }),
+    ];
+    largest_packet_size(identity, &messages)
+}
+
+/// The size of the largest packet that any of the messages is sent in, in any protocol version that has it.
+/// The packets are sent by the broadcaster, so they have sequence numbers.
+fn largest_packet_size(identity: &Identity, messages: &[Message]) -> usize {
+    let mut largest = 0;
+    let mut packet = Vec::new();
+    for version in MIN_VERSION..=VERSION {
+        for message in messages {
+            if make_sequenced_packet_into(identity, version, u32::MAX, message, &mut packet).is_some() {
+                largest = largest.max(packet.len());
+            }
+        }
+    }
+    largest
+}
+
+/// Whether the packets of the message fit in datagrams of the given size, in every protocol version.
+pub fn fits(identity: &Identity, message: &Message, max_datagram_size: usize) -> bool {
+    largest_packet_size(identity, std::slice::from_ref(message)) <= max_datagram_size
+}
+
+/// The largest part of a file listing entry whose packets fit in datagrams of the given size, if any does.
+pub fn largest_listing_part_size(identity: &Identity, max_datagram_size: usize) -> Option<usize> {
+    // Measure the overhead with data as long as the datagram, so that its length takes the most bytes
+    let part = Message::FileListingPart(FileListingPartData {
+        idx: u32::MAX,
+        part: u16::MAX,
+        parts: u16::MAX,
+        data: Bytes::from(vec![0; max_datagram_size]),
+    });
+    let overhead = largest_packet_size(identity, &[part]) - max_datagram_size;
+    max_datagram_size.checked_sub(overhead).filter(|size| *size > 0)
+}
+
+/// The largest chunk size whose packets fit in datagrams of the given size, if any does.
+pub fn largest_chunk_size(identity: &Identity, max_datagram_size: usize) -> Option<u16> {
+    if worst_case_packet_size(identity, 1) > max_datagram_size {
+        return None;
+    }
+    // The packet size grows with the chunk size, so find the largest that fits
+    let (mut fits, mut too_large) = (1u32, u16::MAX as u32 + 1);
+    while too_large - fits > 1 {
+        let middle = (fits + too_large) / 2;
+        if worst_case_packet_size(identity, middle as u16) <= max_datagram_size {
+            fits = middle;
+        } else {
+            too_large = middle;
+        }
+    }
+    Some(fits as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_largest_chunk_size_fits() {
+        let identity = Identity::new("some-server-name".to_string());
+        let chunk_size = largest_chunk_size(&identity, LEGACY_DATAGRAM_SIZE).unwrap();
+        assert!(worst_case_packet_size(&identity, chunk_size) <= LEGACY_DATAGRAM_SIZE);
+        assert!(worst_case_packet_size(&identity, chunk_size + 1) > LEGACY_DATAGRAM_SIZE);
+        assert!(largest_chunk_size(&identity, 20).is_none());
+    }
+
+    #[test]
+    fn test_listing_parts_fit() {
+        let identity = Identity::new("some-server-name".to_string());
+        let part_size = largest_listing_part_size(&identity, 1024).unwrap();
+        let part = Message::FileListingPart(FileListingPartData {
+            idx: u32::MAX,
+            part: u16::MAX,
+            parts: u16::MAX,
+            data: Bytes::from(vec![0; part_size]),
+        });
+        assert!(fits(&identity, &part, 1024));
+        assert!(largest_listing_part_size(&identity, 20).is_none());
+    }
+}
@@ -4,19 +4,26 @@ use tokio::sync::Mutex;
 
 use tokio::time::Instant;
 
+use common::{ping_reply::PingStats, Peer};
+
 pub struct RateLimiter {
     current_packets_per_second: tokio::sync::watch::Receiver<usize>,
 
-    last_accounting_period: Instant,
-    packets_this_period: usize,
+    /// When the next packets can be sent, at the current rate.
+    next_send: Instant,
 
-    ping_stat_sender: tokio::sync::mpsc::Sender<(String, u64)>,
+    ping_stat_sender: tokio::sync::mpsc::Sender<PingStats>,
 
-    ping_stats: Arc<Mutex<std::collections::HashMap<String, usize>>>,
+    ping_stats: Arc<Mutex<std::collections::HashMap<Peer, usize>>>,
 }
 
 const PACKETS_DELIVERED_TARGET: f64 = 0.5;
 
+/// How long a burst of packets may last: packets are sent at most this long's worth at once at the current rate,
+/// and the rest are spread out evenly, so that slow links don't drop them.
+/// The timer can't wait for much less than this.
+const PACING_INTERVAL: Duration = Duration::from_millis(1);
+
 impl RateLimiter {
     /// Create a new rate limiter.
     ///
@@ -33,8 +40,7 @@ impl RateLimiter {
         let ret = Self {
             current_packets_per_second: rate_limit_receiver,
 
-            last_accounting_period: Instant::now(),
-            packets_this_period: 0,
+            next_send: Instant::now(),
 
             ping_stat_sender: sender,
 
@@ -52,42 +58,33 @@ impl RateLimiter {
     }
 
     /// Get the ping stat collector.
-    pub fn get_collector(&self) -> tokio::sync::mpsc::Sender<(String, u64)> {
+    pub fn get_collector(&self) -> tokio::sync::mpsc::Sender<PingStats> {
         self.ping_stat_sender.clone()
     }
 
-    /// Account for a packet being sent.
-    ///
-    /// If this packet would exceed the rate limit, this function will delay
-    /// until the end of the current accounting period.
-    pub async fn on_packet(&mut self) {
-        let mut ping_stats = self.ping_stats.lock().await;
-
-        self.packets_this_period += 1;
+    /// The largest number of packets to send at once at the current rate: see `PACING_INTERVAL`.
+    pub fn burst_size(&self) -> usize {
+        let packets_per_second = *self.current_packets_per_second.borrow();
+        ((packets_per_second as f64 * PACING_INTERVAL.as_secs_f64()) as usize).max(1)
+    }
 
-        // Check if the current accounting period has ended
-        let accounting_period = Duration::from_secs(1);
-        if Instant::now() - self.last_accounting_period > accounting_period {
-            self.packets_this_period = 1;
-            self.last_accounting_period = Instant::now();
-            return;
-        }
+    /// Account for packets being sent.
+    ///
+    /// Waits until they can be sent at the current rate, after the packets that were sent before them,
+    /// so that the packets are spread out evenly rather than sent in bursts.
+    pub async fn on_packets(&mut self, count: usize) {
+        // Time that was not used, while there was nothing to send, can't be made up for with a burst
+        let now = Instant::now();
+        self.next_send = self.next_send.max(now.checked_sub(PACING_INTERVAL).unwrap_or(now));
+        tokio::time::sleep_until(self.next_send).await;
+        let packets_per_second = *self.current_packets_per_second.borrow();
+        self.next_send += Duration::from_secs_f64(count as f64 / packets_per_second as f64);
 
-        // If we've sent too many packets, wait until the end of the accounting period
-        if self.packets_this_period > *self.current_packets_per_second.borrow() {
-            let time_left = self.last_accounting_period + accounting_period - Instant::now();
-            log::info!(
-                "Ratelimiting for {time_left:?} after sending {}",
-                self.packets_this_period
-            );
-            tokio::time::sleep(time_left).await;
-            self.packets_this_period = 1;
-            self.last_accounting_period = Instant::now();
-        }
+        let mut ping_stats = self.ping_stats.lock().await;
 
-        // Record that every peer should have received a packet
-        for (_peer, count) in ping_stats.iter_mut() {
-            *count += 1;
+        // Record that every peer should have received the packets
+        for (_peer, received) in ping_stats.iter_mut() {
+            *received += count;
         }
 
         // Delete any peers that haven't sent a ping in a while
@@ -111,38 +108,54 @@ impl RateLimiter {
 /// this is the number we expect to see in their next ping.
 /// If we sent more than that, then some packets must have been dropped,
 /// so we decrease the number of packets per second.
+/// Clients that track our sequence numbers tell us exactly which packets they lost instead.
 ///
 /// Uses an additive increase, multiplicative decrease algorithm.
 ///
-/// Takes a mpsc::Receiver<(String, u64)>: the number of packets seen by a peer.
+/// Takes a mpsc::Receiver<PingStats>: the packets seen by a peer.
 /// Takes 2 usize: the minimum and maximum number of packets per second.
 /// Takes a watch::Sender<usize>: the current rate limit.
 async fn rate_limit_thread(
-    mut ping_stat_recv: tokio::sync::mpsc::Receiver<(String, u64)>,
+    mut ping_stat_recv: tokio::sync::mpsc::Receiver<PingStats>,
     min_packets_per_second: usize,
     max_packets_per_second: usize,
     current_packets_per_second: tokio::sync::watch::Sender<usize>,
-    ping_stats: Arc<Mutex<std::collections::HashMap<String, usize>>>,
+    ping_stats: Arc<Mutex<std::collections::HashMap<Peer, usize>>>,
 ) {
     let mut current_pps = min_packets_per_second;
     loop {
         // Receive ping stats
         tokio::select! {
-            Some((peer, count)) = ping_stat_recv.recv() => {
-                let mut ping_stats = ping_stats.lock().await;
-                // If this is the first time we've seen this peer,
-                // then we don't know how many packets they've seen yet.
-                // So we just set the count to 0.
-                if !ping_stats.contains_key(&peer) {
-                    ping_stats.insert(peer, 0);
-                    continue;
-                }
-
-                // If the peer has already been seen, then we can compare
-                // the number of packets they've seen to the number we've sent.
-                let packets_sent = ping_stats.get(&peer).unwrap();
-                let packets_seen = count;
-                let packets_delivered_fraction = packets_seen as f64 / *packets_sent as f64;
+            Some((peer, count, lost)) = ping_stat_recv.recv() => {
+                let packets_delivered_fraction = match lost {
+                    // The peer knows exactly how many of our packets it lost
+                    Some(gaps) => {
+                        let lost: u64 = gaps.iter().map(|gap| gap.length as u64).sum();
+                        if lost > 0 {
+                            log::debug!("{peer} lost {lost} packets: {gaps:?}");
+                        }
+                        if count + lost == 0 {
+                            continue;
+                        }
+                        count as f64 / (count + lost) as f64
+                    }
+                    None => {
+                        let mut ping_stats = ping_stats.lock().await;
+                        // If this is the first time we've seen this peer,
+                        // then we don't know how many packets they've seen yet.
+                        // So we just set the count to 0.
+                        if !ping_stats.contains_key(&peer) {
+                            ping_stats.insert(peer, 0);
+                            continue;
+                        }
+
+                        // If the peer has already been seen, then we can compare
+                        // the number of packets they've seen to the number we've sent.
+                        let packets_sent = ping_stats.get(&peer).unwrap();
+                        let packets_seen = count;
+                        packets_seen as f64 / *packets_sent as f64
+                    }
+                };
                 if packets_delivered_fraction < PACKETS_DELIVERED_TARGET {
                     // If too many packets were dropped, decrease the rate limit
                     current_pps = (current_pps as f64 * 0.9) as usize;